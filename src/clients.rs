@@ -0,0 +1,235 @@
+use bytes::Bytes;
+use futures::sync::{mpsc, oneshot};
+use resp::RespValue;
+use std::fmt::Write;
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use types::Token;
+use utils::{assume_str, IdHashMap};
+
+/// Connection metadata, written by the server reactor and read by the workers
+pub struct ClientInfo {
+    pub token: Token,
+    pub addr: net::SocketAddr,
+    pub name: Option<Bytes>,
+    pub created: Instant,
+    pub last_interaction: Instant,
+    pub last_cmd: Option<Bytes>,
+    pub queued: usize,
+    pub monitoring: bool,
+    kill_tx: Option<oneshot::Sender<()>>,
+    monitor_tx: mpsc::Sender<RespValue>,
+}
+
+pub enum ClientFilter {
+    Id(Token),
+    Addr(net::SocketAddr),
+}
+
+#[derive(Default)]
+pub struct Clients {
+    inner: Mutex<IdHashMap<Token, ClientInfo>>,
+    // number of clients in monitor mode, avoids locking when there are none
+    monitors: AtomicUsize,
+}
+
+// monitor arguments longer than this are truncated
+const MONITOR_MAX_ARG_LEN: usize = 64;
+
+fn monitor_escape_into(out: &mut String, arg: &[u8]) {
+    out.push('"');
+    for &b in &arg[..arg.len().min(MONITOR_MAX_ARG_LEN)] {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            b' '...b'~' => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", b);
+            }
+        }
+    }
+    out.push('"');
+    if arg.len() > MONITOR_MAX_ARG_LEN {
+        let _ = write!(out, "... ({} more bytes)", arg.len() - MONITOR_MAX_ARG_LEN);
+    }
+}
+
+impl ClientInfo {
+    fn matches(&self, filter: &ClientFilter) -> bool {
+        match *filter {
+            ClientFilter::Id(token) => self.token == token,
+            ClientFilter::Addr(addr) => self.addr == addr,
+        }
+    }
+
+    fn describe_into(&self, out: &mut String) {
+        let _ = writeln!(
+            out,
+            "id={} addr={} name={} age={} idle={} queued={} flags={} cmd={}",
+            self.token,
+            self.addr,
+            self.name.as_ref().map_or("", |n| assume_str(n)),
+            self.created.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.queued,
+            if self.monitoring { "O" } else { "N" },
+            self.last_cmd
+                .as_ref()
+                .map_or("NULL".into(), |c| assume_str(c).to_lowercase()),
+        );
+    }
+}
+
+impl Clients {
+    /// Registers a new connection, the returned future resolves when the client is killed.
+    /// `monitor_tx` receives the MONITOR output if the client switches to monitor mode.
+    pub fn register(
+        &self,
+        token: Token,
+        addr: net::SocketAddr,
+        monitor_tx: mpsc::Sender<RespValue>,
+    ) -> oneshot::Receiver<()> {
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let now = Instant::now();
+        self.inner.lock().unwrap().insert(
+            token,
+            ClientInfo {
+                token: token,
+                addr: addr,
+                name: None,
+                created: now,
+                last_interaction: now,
+                last_cmd: None,
+                queued: 0,
+                monitoring: false,
+                kill_tx: Some(kill_tx),
+                monitor_tx: monitor_tx,
+            },
+        );
+        kill_rx
+    }
+
+    pub fn unregister(&self, token: Token) {
+        if let Some(client) = self.inner.lock().unwrap().remove(&token) {
+            if client.monitoring {
+                self.monitors.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Called by the server as requests are received and dispatched
+    pub fn on_request(&self, token: Token, request: Option<&RespValue>, queued: usize) {
+        if let Some(client) = self.inner.lock().unwrap().get_mut(&token) {
+            if let Some(&RespValue::Array(ref args)) = request {
+                if let Some(&RespValue::Data(ref arg0)) = args.first() {
+                    client.last_cmd = Some(arg0.clone());
+                }
+                client.last_interaction = Instant::now();
+            }
+            client.queued = queued;
+        }
+    }
+
+    pub fn set_name(&self, token: Token, name: Option<Bytes>) -> bool {
+        if let Some(client) = self.inner.lock().unwrap().get_mut(&token) {
+            client.name = name;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get_name(&self, token: Token) -> Option<Bytes> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get(&token)
+            .and_then(|c| c.name.clone())
+    }
+
+    pub fn set_monitor(&self, token: Token) -> bool {
+        if let Some(client) = self.inner.lock().unwrap().get_mut(&token) {
+            if !client.monitoring {
+                client.monitoring = true;
+                self.monitors.fetch_add(1, Ordering::Relaxed);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sends the command to all clients in monitor mode.
+    /// Slow monitors miss messages instead of blocking the caller.
+    pub fn feed_monitors(&self, token: Token, args: &[&Bytes]) {
+        if self.monitors.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let addr = if let Some(client) = inner.get(&token) {
+            client.addr
+        } else {
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = format!(
+            "{}.{:06} [{}]",
+            timestamp.as_secs(),
+            timestamp.subsec_nanos() / 1000,
+            addr
+        );
+        for arg in args {
+            line.push(' ');
+            monitor_escape_into(&mut line, arg);
+        }
+        let line = RespValue::Status(line.into());
+        for client in inner.values_mut().filter(|c| c.monitoring) {
+            if client.monitor_tx.try_send(line.clone()).is_err() {
+                debug!("Dropped monitor line for slow client {}", client.token);
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut clients: Vec<_> = inner.values().collect();
+        clients.sort_by_key(|c| c.token);
+        let mut out = String::with_capacity(clients.len() * 96);
+        for client in clients {
+            client.describe_into(&mut out);
+        }
+        out
+    }
+
+    /// Signals the matching connections to close, returns how many were killed
+    pub fn kill(&self, filter: ClientFilter) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let tokens: Vec<_> = inner
+            .values()
+            .filter(|c| c.matches(&filter))
+            .map(|c| c.token)
+            .collect();
+        for token in &tokens {
+            let mut client = inner.remove(token).unwrap();
+            if client.monitoring {
+                self.monitors.fetch_sub(1, Ordering::Relaxed);
+            }
+            info!("Killing client {} {}", client.token, client.addr);
+            if let Some(kill_tx) = client.kill_tx.take() {
+                let _ = kill_tx.send(());
+            }
+        }
+        tokens.len()
+    }
+}
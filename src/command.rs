@@ -1,15 +1,22 @@
 use bincode;
 use bytes::Bytes;
+use clients::ClientFilter;
 use config;
 use cubes::{self, Cube};
 use database::{Context, Database};
+use dht::{NodeStatus, VNodeNodeStatus};
+use hash::{hash_slot, HASH_SLOTS};
 use metrics::{self, Meter};
 use resp::RespValue;
+use std::cmp::min;
 use std::convert::TryInto;
-use std::net;
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
+use std::{net, process};
 use types::*;
-use utils::{assume_str, replace_default};
+use utils::{assume_str, glob_match, replace_default, GenericError, IdHashMap, IdHashSet};
 use version_vector::*;
+use vnode::VNodeStatus;
 
 #[derive(Debug)]
 pub enum CommandError {
@@ -31,11 +38,19 @@ pub enum CommandError {
     MultiplePartitions,
     MultipleKeyMutations,
     Unavailable,
+    NoSuchClient,
+    InvalidConfig,
+    ClusterError(String),
 }
 
 impl Into<RespValue> for CommandError {
     fn into(self) -> RespValue {
-        RespValue::Error(format!("{:?}", self).into())
+        match self {
+            CommandError::ClusterError(ref msg) => {
+                RespValue::Error(format!("ClusterError {}", msg).into())
+            }
+            _ => RespValue::Error(format!("{:?}", self).into()),
+        }
     }
 }
 
@@ -53,6 +68,26 @@ fn parse_int<T: ::std::str::FromStr + Default>(
     }
 }
 
+// Node ids are shown as 40 hex chars (like Redis Cluster ones), decimal ids are accepted too
+fn node_id_str(node: NodeId) -> String {
+    format!("{:040x}", node)
+}
+
+fn parse_node_id(args: &[&Bytes], i: usize) -> Result<NodeId, CommandError> {
+    let arg = assume_str(&args[i]);
+    if arg.len() == 40 {
+        NodeId::from_str_radix(arg, 16).map_err(|_| CommandError::InvalidIntValue)
+    } else {
+        parse_int(true, args, i)
+    }
+}
+
+fn parse_consistency_arg(arg: &Bytes) -> Result<ConsistencyLevel, CommandError> {
+    arg.as_ref()
+        .try_into()
+        .map_err(|_| CommandError::InvalidConsistencyValue)
+}
+
 fn check_arg_count(count: usize, min: usize, max: usize) -> Result<(), CommandError> {
     if count < min || count > max {
         Err(CommandError::InvalidArgCount)
@@ -61,6 +96,14 @@ fn check_arg_count(count: usize, min: usize, max: usize) -> Result<(), CommandEr
     }
 }
 
+fn cluster_error(e: GenericError) -> CommandError {
+    CommandError::ClusterError(e.to_string())
+}
+
+fn info_field<T: fmt::Display>(out: &mut String, key: &str, value: T) {
+    let _ = write!(out, "{}:{}\r\n", key, value);
+}
+
 fn check_key_len(key_len: usize) -> Result<(), CommandError> {
     if key_len > config::MAX_KEY_LEN {
         Err(CommandError::InvalidKey)
@@ -80,6 +123,20 @@ fn check_value_len(value_len: usize) -> Result<(), CommandError> {
 impl Database {
     pub fn handler_cmd(&self, mut context: Context) {
         let cmd = context.commands.pop().unwrap();
+        if context.started.is_none() {
+            context.started = Some(Instant::now());
+        }
+        if let RespValue::Array(ref args) = cmd {
+            context.args = args.iter()
+                .filter_map(|a| match *a {
+                    RespValue::Data(ref b) => Some(b.clone()),
+                    _ => None,
+                })
+                .collect();
+        }
+        context.consistency = None;
+        context.vnode = None;
+        context.replicas = None;
         if let Err(e) = self.handle_cmd(&mut context, cmd) {
             context.clear();
             self.respond_error(&mut context, e);
@@ -108,6 +165,10 @@ impl Database {
             return Err(CommandError::ProtocolError);
         }
 
+        if !context.is_exec {
+            self.clients.feed_monitors(context.token, &args);
+        }
+
         let arg0 = args[0];
         let args = &args[1..];
 
@@ -160,6 +221,10 @@ impl Database {
                     check_arg_count(args.len(), 0, 0).and_then(|_| Ok(self.respond_ok(context)))
                 }
                 b"CONFIG" | b"config" => self.cmd_config(context, args),
+                b"CLIENT" | b"client" => self.cmd_client(context, args),
+                b"INFO" | b"info" => self.cmd_info(context, args),
+                b"SLOWLOG" | b"slowlog" => self.cmd_slowlog(context, args),
+                b"MONITOR" | b"monitor" => self.cmd_monitor(context, args),
                 _ => {
                     debug!("Unknown command {:?}", cmd);
                     Err(CommandError::UnknownCommand)
@@ -187,14 +252,24 @@ impl Database {
         args: &[&Bytes],
         i: usize,
     ) -> Result<ConsistencyLevel, CommandError> {
-        Ok(if try {
-            args[i]
-                .as_ref()
-                .try_into()
-                .map_err(|_| CommandError::InvalidConsistencyValue)?
+        if try {
+            parse_consistency_arg(args[i])
+        } else {
+            Ok(self.config().consistency_read)
+        }
+    }
+
+    fn parse_write_consistency(
+        &self,
+        try: bool,
+        args: &[&Bytes],
+        i: usize,
+    ) -> Result<ConsistencyLevel, CommandError> {
+        if try {
+            parse_consistency_arg(args[i])
         } else {
-            self.config.consistency_read
-        })
+            Ok(self.config().consistency_write)
+        }
     }
 
     fn cmd_multi(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -209,7 +284,7 @@ impl Database {
             return Err(CommandError::InvalidExec);
         }
         check_arg_count(args.len(), 0, 1)?;
-        let consistency = self.parse_consistency(args.len() > 0, args, 0)?;
+        let consistency = self.parse_write_consistency(args.len() > 0, args, 0)?;
         assert!(!context.is_exec);
         context.is_exec = true;
         let mut cmds = replace_default(&mut context.commands);
@@ -221,8 +296,288 @@ impl Database {
         self.set_flush(context, consistency)
     }
 
-    fn cmd_config(&self, context: &mut Context, _args: &[&Bytes]) -> Result<(), CommandError> {
-        Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+    fn cmd_config(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 3)?;
+        match args[0].as_ref() {
+            b"GET" | b"get" => {
+                check_arg_count(args.len(), 2, 2)?;
+                let mut result = Vec::new();
+                for (key, value) in self.config().values() {
+                    if glob_match(args[1], key.as_bytes()) {
+                        result.push(RespValue::Data(key.as_bytes().into()));
+                        result.push(RespValue::Data(value.into()));
+                    }
+                }
+                Ok(self.respond_resp(context, RespValue::Array(result)))
+            }
+            b"SET" | b"set" => {
+                check_arg_count(args.len(), 3, 3)?;
+                let key = assume_str(args[1]).to_lowercase();
+                let value = String::from_utf8_lossy(args[2]);
+                self.update_config(|config| {
+                    // apply to a copy so a failed validation doesn't leave a partial change
+                    let mut new_config = config.clone();
+                    new_config.set(&key, &value).map(|_| *config = new_config)
+                }).map_err(|e| {
+                    debug!("CONFIG SET {} {} failed: {}", key, value, e);
+                    CommandError::InvalidConfig
+                })?;
+                info!("Config {} set to {}", key, value);
+                if key == "weight" {
+                    let weight = self.config().weight;
+                    self.dht
+                        .update_node_meta(self.dht.node(), |meta| meta.weight = weight)
+                        .map_err(cluster_error)?;
+                }
+                Ok(self.respond_ok(context))
+            }
+            b"REWRITE" | b"rewrite" => {
+                check_arg_count(args.len(), 1, 1)?;
+                // don't hold the config lock while doing IO
+                let config = self.config().clone();
+                config.rewrite().map_err(|e| {
+                    warn!("CONFIG REWRITE failed: {}", e);
+                    CommandError::InvalidConfig
+                })?;
+                Ok(self.respond_ok(context))
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+
+    fn cmd_client(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 3)?;
+        let token = context.token;
+        match args[0].as_ref() {
+            b"LIST" | b"list" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let list = self.clients.describe();
+                Ok(self.respond_resp(context, RespValue::Data(list.into())))
+            }
+            b"ID" | b"id" => {
+                check_arg_count(args.len(), 1, 1)?;
+                Ok(self.respond_int(context, token as _))
+            }
+            b"SETNAME" | b"setname" => {
+                check_arg_count(args.len(), 2, 2)?;
+                if args[1].iter().any(|&b| b <= b' ' || b > b'~') {
+                    return Err(CommandError::InvalidValue);
+                }
+                let name = if args[1].is_empty() {
+                    None
+                } else {
+                    Some(args[1].clone())
+                };
+                self.clients.set_name(token, name);
+                Ok(self.respond_ok(context))
+            }
+            b"GETNAME" | b"getname" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let name = self.clients
+                    .get_name(token)
+                    .map_or(RespValue::Nil, RespValue::Data);
+                Ok(self.respond_resp(context, name))
+            }
+            b"KILL" | b"kill" => if args.len() == 2 {
+                // old style, CLIENT KILL addr:port
+                let addr = assume_str(args[1])
+                    .parse()
+                    .map_err(|_| CommandError::InvalidValue)?;
+                if self.clients.kill(ClientFilter::Addr(addr)) != 0 {
+                    Ok(self.respond_ok(context))
+                } else {
+                    Err(CommandError::NoSuchClient)
+                }
+            } else {
+                check_arg_count(args.len(), 3, 3)?;
+                let filter = match args[1].as_ref() {
+                    b"ID" | b"id" => ClientFilter::Id(parse_int(true, args, 2)?),
+                    b"ADDR" | b"addr" => ClientFilter::Addr(
+                        assume_str(args[2])
+                            .parse()
+                            .map_err(|_| CommandError::InvalidValue)?,
+                    ),
+                    _ => return Err(CommandError::InvalidCommand),
+                };
+                let killed = self.clients.kill(filter);
+                Ok(self.respond_int(context, killed as _))
+            },
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+
+    fn cmd_info(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 0, 1)?;
+        let section = if args.is_empty() {
+            "default".to_owned()
+        } else {
+            assume_str(args[0]).to_lowercase()
+        };
+        let all = match section.as_str() {
+            "default" | "all" | "everything" => true,
+            _ => false,
+        };
+        let mut info = String::with_capacity(2048);
+        for &name in &["server", "clients", "cluster", "sync", "stats", "rocksdb"] {
+            if !all && section != name {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            match name {
+                "server" => self.info_server(&mut info),
+                "clients" => self.info_clients(&mut info),
+                "cluster" => self.info_cluster(&mut info),
+                "sync" => self.info_sync(&mut info),
+                "stats" => self.info_stats(&mut info),
+                "rocksdb" => self.info_rocksdb(&mut info),
+                _ => unreachable!(),
+            }
+        }
+        Ok(self.respond_resp(context, RespValue::Data(info.into())))
+    }
+
+    fn info_server(&self, out: &mut String) {
+        let uptime = self.started.elapsed().as_secs();
+        out.push_str("# Server\r\n");
+        info_field(out, "sucredb_version", env!("CARGO_PKG_VERSION"));
+        info_field(out, "node_id", node_id_str(self.dht.node()));
+        info_field(out, "process_id", process::id());
+        info_field(out, "tcp_port", self.config().listen_addr.port());
+        info_field(out, "listen_addr", self.config().listen_addr);
+        info_field(out, "fabric_addr", self.config().fabric_addr);
+        info_field(out, "data_dir", self.config().data_dir.display());
+        info_field(out, "uptime_in_seconds", uptime);
+        info_field(out, "uptime_in_days", uptime / (24 * 3600));
+    }
+
+    fn info_clients(&self, out: &mut String) {
+        out.push_str("# Clients\r\n");
+        info_field(out, "connected_clients", self.clients.len());
+        info_field(out, "client_connection_max", self.config().client_connection_max);
+    }
+
+    fn info_cluster(&self, out: &mut String) {
+        let (mut ready, mut bootstrap, mut zombie, mut absent) = (0, 0, 0, 0);
+        for status in self.vnodes_status() {
+            match status {
+                VNodeStatus::Ready => ready += 1,
+                VNodeStatus::Bootstrap => bootstrap += 1,
+                VNodeStatus::Zombie => zombie += 1,
+                VNodeStatus::Absent => absent += 1,
+            }
+        }
+        out.push_str("# Cluster\r\n");
+        info_field(out, "cluster_enabled", 1);
+        info_field(out, "cluster_name", &self.config().cluster_name);
+        // the version vector, the epoch is its sum (as in CLUSTER INFO)
+        let version: Vec<_> = self.dht
+            .ring_version()
+            .iter()
+            .map(|(node, v)| format!("{}={}", node_id_str(node), v))
+            .collect();
+        info_field(out, "ring_version", version.join(","));
+        info_field(out, "ring_epoch", self.ring_epoch());
+        info_field(out, "nodes", self.dht.members().len());
+        info_field(out, "partitions", self.dht.partitions());
+        info_field(out, "replication_factor", self.dht.replication_factor());
+        info_field(out, "vnodes_ready", ready);
+        info_field(out, "vnodes_bootstrap", bootstrap);
+        info_field(out, "vnodes_zombie", zombie);
+        info_field(out, "vnodes_absent", absent);
+    }
+
+    fn info_sync(&self, out: &mut String) {
+        out.push_str("# Sync\r\n");
+        info_field(out, "syncs_inflight", self.syncs_inflight());
+        for (name, value) in metrics::gauges() {
+            if name.starts_with("sync_") {
+                info_field(out, name, value);
+            }
+        }
+        info_field(out, "sync_incomming_max", self.config().sync_incomming_max);
+        info_field(out, "sync_outgoing_max", self.config().sync_outgoing_max);
+        info_field(out, "fabric_connections", self.fabric.connections().len());
+    }
+
+    fn info_stats(&self, out: &mut String) {
+        let meters = metrics::meters();
+        let (mut total, mut per_sec) = (0, 0f64);
+        for &(_, ref meter) in meters.iter().filter(|&&(n, _)| n.starts_with("request_")) {
+            total += meter.count;
+            per_sec += meter.rate;
+        }
+        out.push_str("# Stats\r\n");
+        info_field(out, "total_commands_processed", total);
+        info_field(out, "instantaneous_ops_per_sec", per_sec as i64);
+        for &(name, ref meter) in &meters {
+            info_field(out, &format!("{}_total", name), meter.count);
+            info_field(out, &format!("{}_per_sec", name), meter.rate as i64);
+        }
+    }
+
+    fn info_rocksdb(&self, out: &mut String) {
+        out.push_str("# Rocksdb\r\n");
+        for (cf, property, value) in self.storage_manager.stats() {
+            let key = format!(
+                "{}_{}",
+                cf,
+                property.trim_left_matches("rocksdb.").replace('-', "_")
+            );
+            info_field(out, &key, value);
+        }
+    }
+
+    fn cmd_monitor(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 0, 0)?;
+        let token = context.token;
+        self.respond_ok(context);
+        // after responding so the OK goes out before the monitor output
+        self.clients.set_monitor(token);
+        Ok(())
+    }
+
+    fn cmd_slowlog(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 2)?;
+        match args[0].as_ref() {
+            b"GET" | b"get" => {
+                let count: usize = if args.len() > 1 {
+                    parse_int(true, args, 1)?
+                } else {
+                    10
+                };
+                let mut entries = Vec::new();
+                self.slowlog.get(count, |e| {
+                    let duration = e.duration.as_secs() * 1_000_000
+                        + e.duration.subsec_nanos() as u64 / 1_000;
+                    entries.push(RespValue::Array(vec![
+                        RespValue::Int(e.id as _),
+                        RespValue::Int(e.timestamp as _),
+                        RespValue::Int(duration as _),
+                        RespValue::Array(e.args.iter().cloned().map(RespValue::Data).collect()),
+                        RespValue::Int(e.token as _),
+                        e.consistency.map_or(RespValue::Nil, |c| {
+                            RespValue::Data(format!("{:?}", c).as_bytes().into())
+                        }),
+                        e.vnode.map_or(RespValue::Nil, |v| RespValue::Int(v as _)),
+                        e.replicas.map_or(RespValue::Nil, |(r, _)| RespValue::Int(r as _)),
+                    ]));
+                });
+                Ok(self.respond_resp(context, RespValue::Array(entries)))
+            }
+            b"LEN" | b"len" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let len = self.slowlog.len();
+                Ok(self.respond_int(context, len as _))
+            }
+            b"RESET" | b"reset" => {
+                check_arg_count(args.len(), 1, 1)?;
+                self.slowlog.reset();
+                Ok(self.respond_ok(context))
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
     }
 
     fn cmd_hgetall(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -241,7 +596,7 @@ impl Database {
         check_value_len(args[2].len())?;
         let hash_key = args[1].clone();
         let hash_value = args[2].clone();
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
         self.set(
             context,
             args[0],
@@ -262,7 +617,7 @@ impl Database {
         check_key_len(args[0].len())?;
         check_key_len(args[1].len())?;
         let hash_key = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -291,7 +646,7 @@ impl Database {
         check_key_len(args[0].len())?;
         check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -312,7 +667,7 @@ impl Database {
         check_key_len(args[0].len())?;
         check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -365,13 +720,17 @@ impl Database {
         check_value_len(args[1].len())?;
         let value = args[1].clone();
         let vv = self.parse_vv(args.len() > 2, args, 2)?;
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
+        let value_version_max = self.config().value_version_max as usize;
         self.set(
             context,
             args[0],
             Box::new(move |i, v, c: Cube| {
                 let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
                 cube_value.set(i, v, Some(value), &vv);
+                if cube_value.len() > value_version_max {
+                    return Err(CommandError::TooManyVersions);
+                }
                 let resp = if reply_result {
                     None
                 } else {
@@ -394,7 +753,7 @@ impl Database {
         check_arg_count(args.len(), 1, 3)?;
         check_key_len(args[0].len())?;
         let vv = self.parse_vv(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -413,7 +772,7 @@ impl Database {
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
         let value: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -447,7 +806,7 @@ impl Database {
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
         let inc: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -469,33 +828,477 @@ impl Database {
     }
 
     fn cmd_cluster(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
-        check_arg_count(args.len(), 1, 1)?;
+        check_arg_count(args.len(), 1, 3)?;
         match args[0].as_ref() {
-            b"REBALANCE" | b"rebalance" => {
-                self.dht.rebalance().unwrap();
+            b"REBALANCE" | b"rebalance" => match args.get(1).map(|a| a.as_ref()) {
+                None => {
+                    self.dht.rebalance().map_err(cluster_error)?;
+                    Ok(self.respond_ok(context))
+                }
+                Some(b"PLAN") | Some(b"plan") => {
+                    check_arg_count(args.len(), 2, 2)?;
+                    let plan = self.rebalance_plan()?;
+                    Ok(self.respond_resp(context, plan))
+                }
+                Some(b"APPLY") | Some(b"apply") => {
+                    check_arg_count(args.len(), 3, 3)?;
+                    let id = parse_int(true, args, 2)?;
+                    self.dht.apply_rebalance_plan(id).map_err(cluster_error)?;
+                    Ok(self.respond_ok(context))
+                }
+                _ => Err(CommandError::InvalidCommand),
+            },
+            b"LEAVE" | b"leave" => {
+                check_arg_count(args.len(), 1, 2)?;
+                let node = if args.len() > 1 {
+                    parse_node_id(args, 1)?
+                } else {
+                    self.dht.node()
+                };
+                self.dht.decommission_node(node).map_err(cluster_error)?;
+                Ok(self.respond_ok(context))
+            }
+            b"FORGET" | b"forget" => {
+                check_arg_count(args.len(), 2, 2)?;
+                let node = parse_node_id(args, 1)?;
+                self.dht.forget_node(node).map_err(cluster_error)?;
+                Ok(self.respond_ok(context))
+            }
+            b"REPLACE" | b"replace" => {
+                check_arg_count(args.len(), 3, 3)?;
+                let old = parse_node_id(args, 1)?;
+                let new = parse_node_id(args, 2)?;
+                self.dht.swap_node(old, new).map_err(cluster_error)?;
+                Ok(self.respond_ok(context))
+            }
+            b"SET-REPLICATION" | b"set-replication" => {
+                check_arg_count(args.len(), 2, 2)?;
+                let replication_factor = parse_int(true, args, 1)?;
+                self.dht
+                    .set_replication_factor(replication_factor)
+                    .map_err(cluster_error)?;
+                Ok(self.respond_ok(context))
+            }
+            b"SPLIT" | b"split" => {
+                check_arg_count(args.len(), 1, 1)?;
+                self.dht.split_partitions().map_err(cluster_error)?;
+                Ok(self.respond_ok(context))
+            }
+            b"REPLICATION" | b"replication" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let progress = self.replication_progress();
+                Ok(self.respond_resp(context, progress))
+            }
+            b"SET-WEIGHT" | b"set-weight" => {
+                check_arg_count(args.len(), 3, 3)?;
+                let node = parse_node_id(args, 1)?;
+                let weight: u32 = parse_int(true, args, 2)?;
+                if weight == 0 {
+                    return Err(CommandError::InvalidValue);
+                }
+                self.dht
+                    .update_node_meta(node, |meta| meta.weight = weight)
+                    .map_err(cluster_error)?;
+                if node == self.dht.node() {
+                    // so it can be persisted with CONFIG REWRITE
+                    self.update_config(|config| config.weight = weight);
+                }
+                Ok(self.respond_ok(context))
+            }
+            b"MEET" | b"meet" => {
+                check_arg_count(args.len(), 2, 3)?;
+                // accepts both `MEET ip:port` and the redis style `MEET ip port`
+                let addr = if args.len() == 3 {
+                    format!("{}:{}", assume_str(args[1]), assume_str(args[2]))
+                } else {
+                    assume_str(args[1]).to_owned()
+                };
+                let addr: net::SocketAddr =
+                    addr.parse().map_err(|_| CommandError::InvalidValue)?;
+                self.fabric.register_seed(addr);
                 Ok(self.respond_ok(context))
             }
             b"SLOTS" | b"slots" => {
                 let mut slots = Vec::new();
                 for (&(start, end), members) in &self.dht.slots() {
                     let mut slot = vec![RespValue::Int(start as _), RespValue::Int(end as _)];
-                    slot.extend(members.iter().map(|&(node, (_, ext_addr))| {
+                    slot.extend(members.iter().map(|&(node, (_, ref meta))| {
+                        let ext_addr = meta.addr;
                         RespValue::Array(vec![
                             RespValue::Data(ext_addr.ip().to_string().as_bytes().into()),
                             RespValue::Int(ext_addr.port() as _),
-                            RespValue::Data(node.to_string().as_bytes().into()),
+                            RespValue::Data(node_id_str(node).as_bytes().into()),
                         ])
                     }));
                     slots.push(RespValue::Array(slot));
                 }
                 Ok(self.respond_resp(context, RespValue::Array(slots)))
             }
+            b"NODES" | b"nodes" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let nodes = self.cluster_nodes();
+                Ok(self.respond_resp(context, RespValue::Data(nodes.into())))
+            }
+            b"SHARDS" | b"shards" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let shards = self.cluster_shards();
+                Ok(self.respond_resp(context, RespValue::Array(shards)))
+            }
+            b"INFO" | b"info" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let info = self.cluster_info();
+                Ok(self.respond_resp(context, RespValue::Data(info.into())))
+            }
+            b"KEYSLOT" | b"keyslot" => {
+                check_arg_count(args.len(), 2, 2)?;
+                Ok(self.respond_int(context, hash_slot(args[1]) as _))
+            }
+            b"COUNTKEYSINSLOT" | b"countkeysinslot" => {
+                check_arg_count(args.len(), 2, 2)?;
+                let slot: u16 = parse_int(true, args, 1)?;
+                if slot >= HASH_SLOTS {
+                    return Err(CommandError::InvalidIntValue);
+                }
+                let count = self.count_keys_in_slot(slot);
+                Ok(self.respond_int(context, count as _))
+            }
             _ => Err(CommandError::UnknownCommand),
         }
     }
 
+    // The moves of the plan and an estimate of the data to transfer, each partition gained
+    // by a node is a full copy. Sizes are only known for partitions held by this node,
+    // the average is used for the others.
+    fn rebalance_plan(&self) -> Result<RespValue, CommandError> {
+        let (id, moves, loads) = self.dht.plan_rebalance().map_err(cluster_error)?;
+        let sizes: Vec<_> = moves
+            .iter()
+            .map(|m| self.vnode_storage_stats(m.vnode))
+            .collect();
+        let known: Vec<_> = sizes.iter().filter_map(|&s| s).collect();
+        let average = if known.is_empty() {
+            (0, 0)
+        } else {
+            let total = known
+                .iter()
+                .fold((0, 0), |acc, &(k, b)| (acc.0 + k, acc.1 + b));
+            (total.0 / known.len() as u64, total.1 / known.len() as u64)
+        };
+
+        let mut nodes: IdHashMap<NodeId, (usize, usize)> = IdHashMap::default();
+        let (mut transfer_keys, mut transfer_bytes) = (0, 0);
+        let mut resp_moves = Vec::with_capacity(moves.len());
+        for (m, size) in moves.iter().zip(sizes) {
+            let (keys, bytes) = size.unwrap_or(average);
+            transfer_keys += keys * m.gained.len() as u64;
+            transfer_bytes += bytes * m.gained.len() as u64;
+            for &node in &m.gained {
+                nodes.entry(node).or_insert((0, 0)).0 += 1;
+            }
+            for &node in &m.lost {
+                nodes.entry(node).or_insert((0, 0)).1 += 1;
+            }
+            let node_ids = |ids: &[NodeId]| {
+                RespValue::Array(
+                    ids.iter()
+                        .map(|&n| RespValue::Data(node_id_str(n).as_bytes().into()))
+                        .collect(),
+                )
+            };
+            resp_moves.push(RespValue::Array(vec![
+                RespValue::Int(m.vnode as _),
+                node_ids(&m.gained),
+                node_ids(&m.lost),
+                RespValue::Int(keys as _),
+                RespValue::Int(bytes as _),
+            ]));
+        }
+        let weights: IdHashMap<_, _> = self.dht
+            .nodes()
+            .into_iter()
+            .map(|(node, _, (_, meta))| (node, meta.weight))
+            .collect();
+        let resp_nodes = loads
+            .into_iter()
+            .map(|(node, vnodes)| {
+                let (gained, lost) = nodes.get(&node).cloned().unwrap_or((0, 0));
+                RespValue::Array(vec![
+                    RespValue::Data(node_id_str(node).as_bytes().into()),
+                    RespValue::Int(weights.get(&node).cloned().unwrap_or(1) as _),
+                    RespValue::Int(vnodes as _),
+                    RespValue::Int(gained as _),
+                    RespValue::Int(lost as _),
+                ])
+            })
+            .collect();
+
+        Ok(RespValue::Array(vec![
+            RespValue::Data("plan_id".into()),
+            RespValue::Int(id as _),
+            RespValue::Data("moves".into()),
+            RespValue::Array(resp_moves),
+            RespValue::Data("nodes".into()),
+            RespValue::Array(resp_nodes),
+            RespValue::Data("transfer_keys".into()),
+            RespValue::Int(transfer_keys as _),
+            RespValue::Data("transfer_bytes".into()),
+            RespValue::Int(transfer_bytes as _),
+        ]))
+    }
+
+    // Progress towards all vnodes having the desired number of replicas as Owners,
+    // new replicas bootstrap as Pending and extra ones leave as Retiring.
+    fn replication_progress(&self) -> RespValue {
+        let replication_factor = self.dht.replication_factor();
+        let valid_nodes = self.dht
+            .nodes()
+            .iter()
+            .filter(|&&(_, status, _)| status == NodeStatus::Valid)
+            .count();
+        let replicas = min(replication_factor, valid_nodes);
+        let vnodes_owners = self.dht.vnodes_owners();
+        let (mut complete, mut pending, mut retiring) = (0, 0, 0);
+        for owners in &vnodes_owners {
+            let count = |status: VNodeNodeStatus| {
+                owners.iter().filter(|&&(_, s)| s == status).count()
+            };
+            let vn_pending = count(VNodeNodeStatus::Pending);
+            let vn_retiring = count(VNodeNodeStatus::Retiring);
+            if count(VNodeNodeStatus::Owner) == replicas && vn_pending + vn_retiring == 0 {
+                complete += 1;
+            }
+            pending += vn_pending;
+            retiring += vn_retiring;
+        }
+        RespValue::Array(vec![
+            RespValue::Data("replication_factor".into()),
+            RespValue::Int(replication_factor as _),
+            RespValue::Data("replicas".into()),
+            RespValue::Int(replicas as _),
+            RespValue::Data("vnodes".into()),
+            RespValue::Int(vnodes_owners.len() as _),
+            RespValue::Data("vnodes_complete".into()),
+            RespValue::Int(complete as _),
+            RespValue::Data("replicas_pending".into()),
+            RespValue::Int(pending as _),
+            RespValue::Data("replicas_retiring".into()),
+            RespValue::Int(retiring as _),
+        ])
+    }
+
+    fn ring_epoch(&self) -> Version {
+        self.dht.ring_version().iter().map(|(_, v)| v).sum()
+    }
+
+    fn connected_nodes(&self) -> IdHashSet<NodeId> {
+        let mut connected: IdHashSet<_> = self.fabric
+            .connection_counts()
+            .into_iter()
+            .filter(|&(_, count)| count > 0)
+            .map(|(node, _)| node)
+            .collect();
+        connected.insert(self.dht.node());
+        connected
+    }
+
+    // Redis Cluster style node list. Sucredb has no masters/replicas, so all nodes
+    // are reported as masters of the slots they can serve (Owner and Retiring).
+    // Ongoing rebalances are reported for this node, Pending as importing and
+    // Retiring as migrating. The zone is reported as an auxiliary field (with an empty hostname).
+    // Nodes considered dead by the failure detector are flagged with fail.
+    fn cluster_nodes(&self) -> String {
+        let this = self.dht.node();
+        let epoch = self.ring_epoch();
+        let connected = self.connected_nodes();
+        let vnodes_owners = self.dht.vnodes_owners();
+        let mut out = String::with_capacity(1024);
+        for (node, _, (fabric_addr, meta)) in self.dht.nodes() {
+            let zone = if meta.zone.is_empty() {
+                String::new()
+            } else {
+                format!(",,zone={}", meta.zone)
+            };
+            let flags = if node == this {
+                "myself,master"
+            } else if !self.dht.is_node_alive(node) {
+                "master,fail"
+            } else {
+                "master"
+            };
+            let _ = write!(
+                out,
+                "{} {}:{}@{}{} {} - 0 0 {} {}",
+                node_id_str(node),
+                meta.addr.ip(),
+                meta.addr.port(),
+                fabric_addr.port(),
+                zone,
+                flags,
+                epoch,
+                if connected.contains(&node) { "connected" } else { "disconnected" },
+            );
+            let mut ranges: Vec<(u16, u16)> = Vec::new();
+            for (vn_no, owners) in vnodes_owners.iter().enumerate() {
+                if !owners.iter().any(|&(n, s)| n == node && s != VNodeNodeStatus::Pending) {
+                    continue;
+                }
+                let (start, end) = self.dht.vnode_slots(vn_no as VNodeNo);
+                match ranges.last_mut() {
+                    Some(range) if range.1 + 1 == start => range.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+            }
+            for (start, end) in ranges {
+                let _ = write!(out, " {}-{}", start, end);
+            }
+            if node == this {
+                for (vn_no, owners) in vnodes_owners.iter().enumerate() {
+                    let status = owners.iter().find(|&&(n, _)| n == this).map(|&(_, s)| s);
+                    let (direction, peer) = match status {
+                        Some(VNodeNodeStatus::Pending) => (
+                            "<",
+                            owners.iter().find(|&&(_, s)| s != VNodeNodeStatus::Pending),
+                        ),
+                        Some(VNodeNodeStatus::Retiring) => (
+                            ">",
+                            owners.iter().find(|&&(_, s)| s == VNodeNodeStatus::Pending),
+                        ),
+                        _ => continue,
+                    };
+                    if let Some(&(peer, _)) = peer {
+                        let (start, end) = self.dht.vnode_slots(vn_no as VNodeNo);
+                        for slot in start..end + 1 {
+                            let _ = write!(out, " [{}-{}-{}]", slot, direction, node_id_str(peer));
+                        }
+                    }
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // One shard per vnode, the first Owner is reported as master and the rest as replicas.
+    fn cluster_shards(&self) -> Vec<RespValue> {
+        let connected = self.connected_nodes();
+        let nodes: IdHashMap<_, _> = self.dht
+            .nodes()
+            .into_iter()
+            .map(|(node, _, (_, meta))| (node, meta.addr))
+            .collect();
+        let data = |s: &str| RespValue::Data(s.as_bytes().into());
+        let mut shards = Vec::new();
+        for (vn_no, owners) in self.dht.vnodes_owners().into_iter().enumerate() {
+            let (start, end) = self.dht.vnode_slots(vn_no as VNodeNo);
+            let master = owners
+                .iter()
+                .find(|&&(_, s)| s == VNodeNodeStatus::Owner)
+                .or_else(|| owners.iter().find(|&&(_, s)| s == VNodeNodeStatus::Retiring))
+                .map(|&(n, _)| n);
+            let mut shard_nodes = Vec::with_capacity(owners.len());
+            for (node, status) in owners {
+                let addr = if let Some(addr) = nodes.get(&node) {
+                    addr
+                } else {
+                    continue;
+                };
+                let health = if !connected.contains(&node) || !self.dht.is_node_alive(node) {
+                    "fail"
+                } else if status == VNodeNodeStatus::Pending {
+                    "loading"
+                } else {
+                    "online"
+                };
+                let ip = addr.ip().to_string();
+                shard_nodes.push(RespValue::Array(vec![
+                    data("id"),
+                    data(&node_id_str(node)),
+                    data("port"),
+                    RespValue::Int(addr.port() as _),
+                    data("ip"),
+                    data(&ip),
+                    data("endpoint"),
+                    data(&ip),
+                    data("role"),
+                    data(if Some(node) == master { "master" } else { "replica" }),
+                    data("replication-offset"),
+                    RespValue::Int(0),
+                    data("health"),
+                    data(health),
+                ]));
+            }
+            shards.push(RespValue::Array(vec![
+                data("slots"),
+                RespValue::Array(vec![RespValue::Int(start as _), RespValue::Int(end as _)]),
+                data("nodes"),
+                RespValue::Array(shard_nodes),
+            ]));
+        }
+        shards
+    }
+
+    fn cluster_info(&self) -> String {
+        let vnodes_owners = self.dht.vnodes_owners();
+        let (mut slots_ok, mut slots_fail, mut pending, mut retiring) = (0, 0, 0, 0);
+        let mut cluster_size = IdHashSet::default();
+        for (vn_no, owners) in vnodes_owners.iter().enumerate() {
+            let (start, end) = self.dht.vnode_slots(vn_no as VNodeNo);
+            if owners.iter().any(|&(_, s)| s != VNodeNodeStatus::Pending) {
+                slots_ok += end - start + 1;
+            } else {
+                slots_fail += end - start + 1;
+            }
+            for &(node, status) in owners {
+                match status {
+                    VNodeNodeStatus::Pending => pending += 1,
+                    VNodeNodeStatus::Retiring => retiring += 1,
+                    VNodeNodeStatus::Owner => (),
+                }
+                cluster_size.insert(node);
+            }
+        }
+        let epoch = self.ring_epoch();
+        let mut out = String::with_capacity(512);
+        info_field(
+            &mut out,
+            "cluster_state",
+            if slots_fail == 0 { "ok" } else { "fail" },
+        );
+        info_field(&mut out, "cluster_slots_assigned", slots_ok + slots_fail);
+        info_field(&mut out, "cluster_slots_ok", slots_ok);
+        info_field(&mut out, "cluster_slots_pfail", 0);
+        info_field(&mut out, "cluster_slots_fail", slots_fail);
+        info_field(&mut out, "cluster_known_nodes", self.dht.nodes().len());
+        info_field(&mut out, "cluster_size", cluster_size.len());
+        info_field(&mut out, "cluster_current_epoch", epoch);
+        info_field(&mut out, "cluster_my_epoch", epoch);
+        info_field(&mut out, "cluster_name", &self.config().cluster_name);
+        info_field(&mut out, "cluster_partitions", vnodes_owners.len());
+        info_field(
+            &mut out,
+            "cluster_replication_factor",
+            self.dht.replication_factor(),
+        );
+        info_field(&mut out, "cluster_vnodes_pending", pending);
+        info_field(&mut out, "cluster_vnodes_retiring", retiring);
+        out
+    }
+
     pub fn respond(&self, context: &mut Context) {
         debug!("Respond request ({}) {:?}", context.token, context.response);
+        if let Some(started) = context.started.take() {
+            let elapsed = started.elapsed();
+            let histogram = context
+                .args
+                .first()
+                .and_then(|c| metrics::command_latency(c));
+            if let Some(histogram) = histogram {
+                histogram.record(elapsed);
+            }
+            if elapsed >= Duration::from_millis(self.config().slowlog_threshold as _) {
+                self.slowlog.push(context, elapsed);
+            }
+        }
         (&self.response_fn)(replace_default(context));
     }
 
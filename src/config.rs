@@ -1,7 +1,7 @@
 use std::cmp::max;
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
@@ -24,12 +24,30 @@ pub const DEFAULT_PARTITIONS: &str = "64";
 pub const MAX_KEY_LEN: usize = 500;
 pub const MAX_VALUE_LEN: usize = 10 * 1024 * 1024;
 
+// settings that can be changed at runtime with CONFIG SET
+pub const TUNABLE_KEYS: &[&str] = &[
+    "request_timeout",
+    "sync_incomming_max",
+    "sync_outgoing_max",
+    "sync_msg_inflight",
+    "sync_rate_limit",
+    "value_version_max",
+    "client_connection_max",
+    "slowlog_threshold",
+    "consistency_read",
+    "consistency_write",
+    "weight",
+];
+
 #[derive(Debug, Clone)]
 pub struct Config {
+    pub config_file: Option<PathBuf>,
     pub data_dir: PathBuf,
     pub cluster_name: String,
     pub listen_addr: SocketAddr,
     pub fabric_addr: SocketAddr,
+    pub zone: String,
+    pub weight: u32,
     pub cmd_init: Option<InitCommand>,
     pub worker_timer: u32,
     pub worker_count: u16,
@@ -39,12 +57,18 @@ pub struct Config {
     pub sync_timeout: u32,
     pub sync_msg_timeout: u32,
     pub sync_msg_inflight: u32,
+    pub sync_rate_limit: u64,
     pub dht_sync_on_connect: bool,
     pub dht_sync_aae: bool,
     pub fabric_timeout: u32,
     pub request_timeout: u32,
     pub client_connection_max: u32,
     pub value_version_max: u16,
+    pub metrics_addr: Option<SocketAddr>,
+    pub slowlog_threshold: u32,
+    pub slowlog_max_len: u32,
+    pub log_retention: u32,
+    pub log_retention_size: u64,
     pub seed_nodes: Vec<SocketAddr>,
     // TODO: these should be in the cluster config instead
     pub consistency_read: ConsistencyLevel,
@@ -55,10 +79,13 @@ impl Default for Config {
     fn default() -> Self {
         // Remember to update defaults in sucre.yaml!
         Config {
+            config_file: None,
             data_dir: DEFAULT_DATA_DIR.into(),
             cluster_name: DEFAULT_CLUSTER_NAME.into(),
             listen_addr: DEFAULT_LISTEN_ADDR.parse().unwrap(),
             fabric_addr: DEFAULT_FABRIC_ADDR.parse().unwrap(),
+            zone: String::new(),
+            weight: 1,
             cmd_init: None,
             worker_timer: 500,
             worker_count: max(4, num_cpus::get() as u16 * 2),
@@ -67,6 +94,7 @@ impl Default for Config {
             sync_timeout: 10_000,
             sync_msg_timeout: 1000,
             sync_msg_inflight: 10,
+            sync_rate_limit: 0,
             sync_auto: true,
             dht_sync_on_connect: true,
             dht_sync_aae: true,
@@ -74,6 +102,11 @@ impl Default for Config {
             request_timeout: 1000,
             client_connection_max: 100,
             value_version_max: 100,
+            metrics_addr: None,
+            slowlog_threshold: 10,
+            slowlog_max_len: 128,
+            log_retention: 72 * 3600 * 1000,
+            log_retention_size: 1024 * 1024 * 1024,
             seed_nodes: Vec::new(),
             consistency_read: ConsistencyLevel::One,
             consistency_write: ConsistencyLevel::One,
@@ -168,10 +201,14 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
     };
     debug!("Done reading config file: {:?}", config);
 
+    config.config_file = Some(path.into());
+
     cfg!(yaml, config, data_dir, as_str);
     cfg!(yaml, config, cluster_name, as_str);
     cfg!(yaml, config, listen_addr, as_str, SocketAddr::from_str);
     cfg!(yaml, config, fabric_addr, as_str, SocketAddr::from_str);
+    cfg!(yaml, config, zone, as_str);
+    cfg!(yaml, config, weight, as_u64, try_into);
     // pub cmd_init: Option<InitCommand>,
     cfg!(yaml, config, worker_timer, as_str, parse_duration);
     cfg!(yaml, config, worker_count, as_u64, try_into);
@@ -181,10 +218,16 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
     cfg!(yaml, config, sync_timeout, as_str, parse_duration);
     cfg!(yaml, config, sync_msg_timeout, as_str, parse_duration);
     cfg!(yaml, config, sync_msg_inflight, as_u64, try_into);
+    cfg!(yaml, config, sync_rate_limit, as_str, parse_size);
     cfg!(yaml, config, fabric_timeout, as_str, parse_duration);
     cfg!(yaml, config, request_timeout, as_str, parse_duration);
     cfg!(yaml, config, client_connection_max, as_u64, try_into);
     cfg!(yaml, config, value_version_max, as_u64, try_into);
+    cfg!(yaml, config, metrics_addr, as_str, SocketAddr::from_str);
+    cfg!(yaml, config, slowlog_threshold, as_str, parse_duration);
+    cfg!(yaml, config, slowlog_max_len, as_u64, try_into);
+    cfg!(yaml, config, log_retention, as_str, parse_duration);
+    cfg!(yaml, config, log_retention_size, as_str, parse_size);
     cfg!(
         yaml,
         config,
@@ -218,6 +261,154 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
     }
 }
 
+impl Config {
+    /// Current values of all settings, as strings
+    pub fn values(&self) -> Vec<(&'static str, String)> {
+        let opt_addr = |a: Option<SocketAddr>| a.map_or("".into(), |a| a.to_string());
+        vec![
+            (
+                "config_file",
+                self.config_file
+                    .as_ref()
+                    .map_or("".into(), |p| p.display().to_string()),
+            ),
+            ("data_dir", self.data_dir.display().to_string()),
+            ("cluster_name", self.cluster_name.clone()),
+            ("listen_addr", self.listen_addr.to_string()),
+            ("fabric_addr", self.fabric_addr.to_string()),
+            ("zone", self.zone.clone()),
+            ("weight", self.weight.to_string()),
+            ("worker_timer", format!("{}ms", self.worker_timer)),
+            ("worker_count", self.worker_count.to_string()),
+            ("sync_incomming_max", self.sync_incomming_max.to_string()),
+            ("sync_outgoing_max", self.sync_outgoing_max.to_string()),
+            ("sync_auto", self.sync_auto.to_string()),
+            ("sync_timeout", format!("{}ms", self.sync_timeout)),
+            ("sync_msg_timeout", format!("{}ms", self.sync_msg_timeout)),
+            ("sync_msg_inflight", self.sync_msg_inflight.to_string()),
+            ("sync_rate_limit", format!("{}b", self.sync_rate_limit)),
+            ("dht_sync_on_connect", self.dht_sync_on_connect.to_string()),
+            ("dht_sync_aae", self.dht_sync_aae.to_string()),
+            ("fabric_timeout", format!("{}ms", self.fabric_timeout)),
+            ("request_timeout", format!("{}ms", self.request_timeout)),
+            ("client_connection_max", self.client_connection_max.to_string()),
+            ("value_version_max", self.value_version_max.to_string()),
+            ("metrics_addr", opt_addr(self.metrics_addr)),
+            ("slowlog_threshold", format!("{}ms", self.slowlog_threshold)),
+            ("slowlog_max_len", self.slowlog_max_len.to_string()),
+            ("log_retention", format!("{}ms", self.log_retention)),
+            ("log_retention_size", format!("{}b", self.log_retention_size)),
+            (
+                "seed_nodes",
+                self.seed_nodes
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            ("consistency_read", format!("{:?}", self.consistency_read)),
+            ("consistency_write", format!("{:?}", self.consistency_write)),
+        ]
+    }
+
+    /// Changes one of the `TUNABLE_KEYS` settings
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), GenericError> {
+        match key {
+            "request_timeout" => self.request_timeout = parse_duration(value)?.try_into()?,
+            "sync_incomming_max" => self.sync_incomming_max = value.parse::<u64>()?.try_into()?,
+            "sync_outgoing_max" => self.sync_outgoing_max = value.parse::<u64>()?.try_into()?,
+            "sync_msg_inflight" => self.sync_msg_inflight = value.parse::<u64>()?.try_into()?,
+            "sync_rate_limit" => self.sync_rate_limit = parse_size(value)?.try_into()?,
+            "value_version_max" => self.value_version_max = value.parse::<u64>()?.try_into()?,
+            "client_connection_max" => {
+                self.client_connection_max = value.parse::<u64>()?.try_into()?
+            }
+            "slowlog_threshold" => self.slowlog_threshold = parse_duration(value)?.try_into()?,
+            "consistency_read" => {
+                self.consistency_read =
+                    value.parse().map_err(|_| "Invalid consistency level")?
+            }
+            "consistency_write" => {
+                self.consistency_write =
+                    value.parse().map_err(|_| "Invalid consistency level")?
+            }
+            "weight" => self.weight = value.parse::<u64>()?.try_into()?,
+            _ => return Err(format!("Can't set `{}` at runtime", key).into()),
+        }
+        if self.request_timeout == 0 || self.sync_msg_inflight == 0 || self.weight == 0 {
+            return Err("Value must be greater than zero".into());
+        }
+        Ok(())
+    }
+
+    /// Writes the tunable settings back to the config file
+    pub fn rewrite(&self) -> Result<(), GenericError> {
+        let path = self.config_file.as_ref().ok_or("No config file")?;
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        let text = self.rewrite_yaml(&text);
+        // write to a temporary file first so a failure can't corrupt the config file
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        {
+            let mut tmp_file = File::create(&tmp_path)?;
+            tmp_file.write_all(text.as_bytes())?;
+            tmp_file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    // Replaces the `key: value` lines of the tunable settings, preserving everything else.
+    // Commented out settings are only uncommented if the value differs from the default.
+    fn rewrite_yaml(&self, text: &str) -> String {
+        let default_values = Config::default().values();
+        let values: Vec<_> = self.values()
+            .into_iter()
+            .zip(default_values)
+            .filter(|&((k, _), _)| TUNABLE_KEYS.contains(&k))
+            .map(|((k, v), (_, d))| {
+                let yaml_v = if v.parse::<u64>().is_ok() {
+                    v.clone()
+                } else {
+                    format!("{:?}", v)
+                };
+                (k, yaml_v, v != d)
+            })
+            .collect();
+        let mut written = vec![false; values.len()];
+        let mut lines: Vec<String> = Vec::new();
+        for line in text.lines() {
+            let (commented, key) = {
+                let uncommented = line.trim_left_matches('#').trim_left();
+                (
+                    line.starts_with('#'),
+                    uncommented.split(':').next().unwrap_or(""),
+                )
+            };
+            let position = if line.starts_with(' ') {
+                None
+            } else {
+                values.iter().position(|&(k, _, _)| k == key)
+            };
+            match position {
+                Some(i) if !written[i] && (!commented || values[i].2) => {
+                    lines.push(format!("{}: {}", values[i].0, values[i].1));
+                    written[i] = true;
+                }
+                _ => lines.push(line.into()),
+            }
+        }
+        for (i, &(k, ref v, non_default)) in values.iter().enumerate() {
+            if !written[i] && non_default {
+                lines.push(format!("{}: {}", k, v));
+            }
+        }
+        lines.push("".into());
+        lines.join("\n")
+    }
+}
+
 pub fn setup_logging(config_value: &yaml::Value) {
     let raw_config: log4rs::file::RawConfig =
         yaml::from_value(config_value.clone()).expect("failed to parse logging config");
@@ -261,3 +452,49 @@ pub fn setup_default_logging() {
 
     log4rs::init_config(config).expect("failed to init logging");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut config = Config::default();
+        config.set("request_timeout", "2s").unwrap();
+        assert_eq!(config.request_timeout, 2000);
+        config.set("consistency_read", "quorum").unwrap();
+        assert_eq!(config.consistency_read, ConsistencyLevel::Quorum);
+        assert!(config.set("sync_incomming_max", "100000").is_err());
+        assert!(config.set("sync_msg_inflight", "0").is_err());
+        assert!(config.set("weight", "0").is_err());
+        config.set("weight", "3").unwrap();
+        assert_eq!(config.weight, 3);
+        config.set("sync_rate_limit", "10mb").unwrap();
+        assert_eq!(config.sync_rate_limit, 10 * 1024 * 1024);
+        assert!(config.set("data_dir", "/tmp").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_yaml() {
+        let mut config = Config::default();
+        config.set("request_timeout", "2s").unwrap();
+        config.set("sync_incomming_max", "20").unwrap();
+        config.set("consistency_write", "all").unwrap();
+        let text = "# Timeout for client requests\n\
+                    # request_timeout: \"1000ms\"\n\
+                    sync_incomming_max: 15\n\
+                    # sync_outgoing_max: 10\n\
+                    logging:\n  \
+                    consistency_read: 1\n";
+        assert_eq!(
+            config.rewrite_yaml(text),
+            "# Timeout for client requests\n\
+             request_timeout: \"2000ms\"\n\
+             sync_incomming_max: 20\n\
+             # sync_outgoing_max: 10\n\
+             logging:\n  \
+             consistency_read: 1\n\
+             consistency_write: \"All\"\n"
+        );
+    }
+}
@@ -5,6 +5,7 @@ use linear_map::{Entry as LMEntry, LinearMap};
 use resp::RespValue;
 use std::boxed::FnBox;
 use std::time;
+use utils::{IdHashMap, IdHashSet};
 use version_vector::*;
 
 pub type MutatorFn =
@@ -69,6 +70,83 @@ impl Cube {
         }
     }
 
+    /// The dots and the causal context of the cube, sorted. Equal cubes in different
+    /// replicas have the same dots (they identify the values) but not necessarily
+    /// the same serialization, eg. the maps keep the insertion order.
+    pub fn canonical(&self) -> (Vec<(Id, Version)>, Vec<(Id, Version)>) {
+        use self::Cube::*;
+        let mut dots = Vec::new();
+        let vv = match *self {
+            Counter(ref a) => &a.vv,
+            Value(ref a) => &a.vv,
+            Map(ref a) => &a.vv,
+            Set(ref a) => &a.vv,
+            Void(ref vv) => vv,
+        };
+        match *self {
+            Void(_) => (),
+            _ => self.for_each_dot(|i, v| dots.push((i, v))),
+        }
+        let mut context: Vec<_> = vv.iter().collect();
+        dots.sort();
+        context.sort();
+        (dots, context)
+    }
+
+    /// Drops the causal context entries of pruned ids (retired ids seen by all replicas),
+    /// unless the values still have dots from them.
+    /// Pruned cubes must be restored before being merged.
+    pub fn prune(&mut self, pruned: &IdHashMap<Id, Version>) {
+        use self::Cube::*;
+        if pruned.is_empty() {
+            return;
+        }
+        let mut live = IdHashSet::default();
+        match *self {
+            Counter(ref a) => live.extend(a.values.keys().cloned()),
+            Value(ref a) => live.extend(a.values.keys().map(|&(i, _)| i)),
+            Map(ref a) => for v in a.values.values() {
+                live.extend(v.dots.iter().map(|(i, _)| i));
+            },
+            Set(ref a) => for dots in a.values.values() {
+                live.extend(dots.iter().map(|(i, _)| i));
+            },
+            Void(_) => (),
+        }
+        let keep = |i: Id, v: Version| {
+            live.contains(&i) || pruned.get(&i).map_or(true, |&p| v > p)
+        };
+        match *self {
+            Counter(ref mut a) => a.vv.retain(keep),
+            Value(ref mut a) => a.vv.retain(keep),
+            Map(ref mut a) => {
+                a.vv.retain(&keep);
+                a.dots.retain(keep);
+            }
+            Set(ref mut a) => {
+                a.vv.retain(&keep);
+                a.dots.retain(keep);
+            }
+            Void(ref mut vv) => vv.retain(keep),
+        }
+    }
+
+    /// Adds back the causal context entries of pruned ids,
+    /// the vnode storage has seen all their versions.
+    pub fn restore(&mut self, pruned: &IdHashMap<Id, Version>) {
+        use self::Cube::*;
+        let vv = match *self {
+            Counter(ref mut a) => &mut a.vv,
+            Value(ref mut a) => &mut a.vv,
+            Map(ref mut a) => &mut a.vv,
+            Set(ref mut a) => &mut a.vv,
+            Void(ref mut vv) => vv,
+        };
+        for (&i, &v) in pruned {
+            vv.add(i, v);
+        }
+    }
+
     pub fn new(bvv: &BitmappedVersionVector) -> Cube {
         let mut vv = VersionVector::new();
         for (&n, bv) in bvv.iter() {
@@ -1,21 +1,28 @@
 use bytes::Bytes;
+use clients::Clients;
 use command::CommandError;
 use config::Config;
 use cubes::*;
-use dht::{RingDescription, DHT};
+use dht::{Metadata, RingDescription, DHT};
 use fabric::*;
+use gossip::{Gossiper, GossiperMsg};
+use health::Health;
 use metrics::{self, Gauge};
 use rand::{thread_rng, Rng};
 use resp::RespValue;
-use std::sync::{Arc, Mutex, RwLock};
+use slowlog::SlowLog;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use std::{net, time};
-use storage::{Storage, StorageManager};
+use storage::{LogRetention, Storage, StorageManager};
+use throttle::TokenBucket;
 pub use types::*;
 use utils::LoggerExt;
 use utils::{assume_str, is_dir_empty_or_absent, join_u64, replace_default, split_u64};
 use version_vector::Version;
 use vnode::*;
-use vnode_sync::SyncDirection;
+use vnode_sync::{SyncDirection, SyncProgress};
 use workers::*;
 
 // require sync as it can be called from any worker thread
@@ -27,6 +34,7 @@ pub enum WorkerMsg {
     Tick(time::Instant),
     DHTFabric(NodeId, FabricMsg),
     DHTChange,
+    NodeLiveness(NodeId, bool),
     Exit,
 }
 
@@ -80,6 +88,14 @@ pub struct Context {
     pub commands: Vec<RespValue>,
     pub reads: Vec<ContextRead>,
     pub writes: Vec<ContextWrite>,
+    // time the request was dispatched to the workers
+    pub started: Option<time::Instant>,
+    // arguments of the top level command, for metrics and the slowlog
+    pub args: Vec<Bytes>,
+    // details of the request, for the slowlog
+    pub consistency: Option<ConsistencyLevel>,
+    pub vnode: Option<VNodeNo>,
+    pub replicas: Option<(u8, u8)>,
 }
 
 impl Context {
@@ -92,6 +108,11 @@ impl Context {
             commands: Default::default(),
             writes: Default::default(),
             reads: Default::default(),
+            started: None,
+            args: Default::default(),
+            consistency: None,
+            vnode: None,
+            replicas: None,
         }
     }
 
@@ -119,23 +140,57 @@ impl Context {
     }
 }
 
+/// Metadata attached to each node in the cluster
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeMeta {
+    // address for client connections
+    pub addr: net::SocketAddr,
+    pub zone: String,
+    pub weight: u32,
+}
+
+impl Metadata for NodeMeta {
+    fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    // the default zone and weight, the local node ones are set again on restore
+    fn from_addr(addr: net::SocketAddr) -> Option<Self> {
+        Some(NodeMeta {
+            addr: addr,
+            zone: String::new(),
+            weight: 1,
+        })
+    }
+}
+
 // TODO: some things to investigate
-// track deleted keys pending physical deletion (is this a good idea? maybe a compaction filter?)
-// pruning old nodes from node clocks (is it possible?)
 // inner vnode parallelism
-// zombie vnodes need to eagerly sync with other nodes
-// track dead peers with the fabric or gossip and use that info
 
 pub struct Database {
-    pub dht: DHT<net::SocketAddr>,
+    pub dht: DHT<NodeMeta>,
     pub fabric: Arc<Fabric>,
     pub meta_storage: Storage,
     pub storage_manager: StorageManager,
     pub response_fn: DatabaseResponseFn,
-    pub config: Config,
+    config: RwLock<Config>,
+    pub clients: Clients,
+    pub started: time::Instant,
+    pub slowlog: SlowLog,
+    pub health: Health,
+    // rate limit of the outgoing sync and bootstrap traffic
+    pub sync_throttle: TokenBucket,
     stats: Mutex<Stats>,
     vnodes: RwLock<Vec<Mutex<VNode>>>,
+    // generation of the vnode storage prefixes, see `storage_prefix`
+    storage_gen: AtomicUsize,
     workers: Mutex<WorkerManager<WorkerMsg>>,
+    // failure detector, gossips over udp on the fabric address
+    gossiper: Mutex<Option<Gossiper<NodeId>>>,
 }
 
 macro_rules! fabric_send_error {
@@ -163,7 +218,7 @@ macro_rules! fabric_send_error {
 
 macro_rules! vnode {
     ($s:expr, $k:expr, $ok:expr) => {{
-        let vnodes = $s.vnodes.read().unwrap();
+        let vnodes = $s.vnodes();
         let mut locked_vnode = vnodes[$k as usize].lock().unwrap();
         Some(&mut *locked_vnode).map($ok).unwrap()
     }};
@@ -178,8 +233,12 @@ impl Database {
             panic!("Can't init cluster when data directory isn't clean");
         }
 
-        let storage_manager =
-            StorageManager::new(&config.data_dir).expect("Failed to create storage manager");
+        let log_retention = LogRetention {
+            age: time::Duration::from_millis(u64::from(config.log_retention)),
+            size: config.log_retention_size,
+        };
+        let storage_manager = StorageManager::new(&config.data_dir, log_retention)
+            .expect("Failed to create storage manager");
         let meta_storage = storage_manager
             .open(u16::max_value())
             .expect("Can't open storage");
@@ -195,6 +254,12 @@ impl Database {
         let meta_ring = meta_storage
             .get_vec(b"ring")
             .expect("Can't read previous ring from storage");
+        let meta_storage_gen = meta_storage
+            .get_vec(b"storage_gen")
+            .expect("Can't read storage generation from storage");
+        let meta_partitions = meta_storage
+            .get_vec(b"partitions")
+            .expect("Can't read partitions from storage");
 
         let (old_node, node) = if let Some(s_node) = meta_node {
             let prev_node: NodeId = String::from_utf8(s_node).unwrap().parse().unwrap();
@@ -231,12 +296,27 @@ impl Database {
         info!("Metadata loaded! node_id:{} previous:{:?}", node, old_node);
 
         let fabric = Arc::new(Fabric::new(node, config).unwrap());
+        let mut node_meta = NodeMeta {
+            addr: config.listen_addr,
+            zone: config.zone.clone(),
+            weight: config.weight,
+        };
+        if config.cmd_init.is_none() {
+            // the weight in the ring wins, CLUSTER SET-WEIGHT may have changed it
+            let saved_meta = meta_ring.as_ref().and_then(|saved_ring| {
+                DHT::<NodeMeta>::saved_node_meta(saved_ring, old_node.unwrap_or(node))
+            });
+            if let Some(saved_meta) = saved_meta {
+                node_meta.weight = saved_meta.weight;
+            }
+        }
+        let weight = node_meta.weight;
 
         let dht = if let Some(init) = config.cmd_init.as_ref() {
             DHT::init(
                 fabric.clone(),
                 config,
-                config.listen_addr,
+                node_meta,
                 RingDescription::new(init.replication_factor, init.partitions),
                 old_node,
             ).expect("Can't init cluster")
@@ -244,7 +324,7 @@ impl Database {
             DHT::restore(
                 fabric.clone(),
                 config,
-                config.listen_addr,
+                node_meta,
                 &saved_ring,
                 old_node,
             ).expect("Can't restore cluster")
@@ -252,16 +332,26 @@ impl Database {
             DHT::join_cluster(
                 fabric.clone(),
                 config,
-                config.listen_addr,
+                node_meta,
                 &config.seed_nodes,
                 old_node,
             ).expect("Can't join cluster")
         };
 
+        // the vnodes may still have to catch up with splits of the ring
+        let storage_gen: usize = meta_storage_gen
+            .map_or(0, |s_gen| String::from_utf8(s_gen).unwrap().parse().unwrap());
+        let partitions: usize = meta_partitions.map_or(dht.partitions(), |s_partitions| {
+            String::from_utf8(s_partitions).unwrap().parse().unwrap()
+        });
+
         // save init (2 of 2)
         meta_storage
             .set(b"ring", &dht.save_ring())
             .expect("Can't save ring");
+        meta_storage
+            .set(b"partitions", partitions.to_string().as_bytes())
+            .expect("Can't save partitions");
         meta_storage.sync().expect("Can't sync storage");
 
         let workers = WorkerManager::new(node.to_string(), config.worker_count as _);
@@ -273,9 +363,19 @@ impl Database {
             meta_storage: meta_storage,
             response_fn: response_fn,
             vnodes: Default::default(),
+            storage_gen: AtomicUsize::new(storage_gen),
             workers: Mutex::new(workers),
-            config: config.clone(),
+            config: RwLock::new(Config {
+                weight: weight,
+                ..config.clone()
+            }),
+            clients: Default::default(),
+            started: time::Instant::now(),
+            slowlog: SlowLog::new(config.slowlog_max_len as usize),
+            health: Default::default(),
+            sync_throttle: Default::default(),
             stats: Default::default(),
+            gossiper: Default::default(),
         });
 
         db.workers.lock().unwrap().start(|| {
@@ -288,6 +388,9 @@ impl Database {
                         WorkerMsg::Tick(time) => db.handler_tick(time),
                         WorkerMsg::DHTFabric(from, m) => db.dht.handler_fabric_msg(from, m),
                         WorkerMsg::DHTChange => db.handler_dht_change(),
+                        WorkerMsg::NodeLiveness(node, alive) => {
+                            db.handler_node_liveness(node, alive)
+                        }
                         WorkerMsg::Exit => (),
                     }
                 }
@@ -327,17 +430,54 @@ impl Database {
             db.fabric.register_msg_handler(msg_type, Box::new(callback));
         }
 
+        // failure detection, gossip members are identified by their node id
+        let mut sender = db.sender();
+        let mut gossip_nodes = HashMap::new();
+        let callback = move |msg: GossiperMsg<NodeId>| {
+            let liveness = match msg {
+                GossiperMsg::New(addr, node) | GossiperMsg::Alive(addr, node) => {
+                    gossip_nodes.insert(addr, node);
+                    Some((node, true))
+                }
+                GossiperMsg::Dead(addr) => gossip_nodes.get(&addr).map(|&node| (node, false)),
+            };
+            if let Some((node, alive)) = liveness {
+                sender.send(WorkerMsg::NodeLiveness(node, alive));
+            }
+        };
+        match Gossiper::new(config.fabric_addr, node, Box::new(callback)) {
+            Ok(gossiper) => {
+                let seeds: Vec<_> = db.dht
+                    .members()
+                    .into_iter()
+                    .filter(|&(n, _)| n != node)
+                    .map(|(_, addr)| addr)
+                    .chain(config.seed_nodes.iter().cloned())
+                    .collect();
+                gossiper.join(&seeds);
+                *db.gossiper.lock().unwrap() = Some(gossiper);
+            }
+            Err(e) => warn!("Can't start gossiper, failure detection is disabled: {}", e),
+        }
+
         // create vnodes
         {
             // acquire exclusive lock to vnodes to initialize them
             let mut vnodes = db.vnodes.write().unwrap();
             let (ready_vnodes, pending_vnodes) = db.dht.vnodes_for_node(db.dht.node());
+            // each local vnode becomes these ring vnodes once split
+            let per_vnode = (db.dht.partitions() / partitions) as VNodeNo;
+            let in_range = |i: VNodeNo, vnodes: &[VNodeNo]| {
+                vnodes
+                    .iter()
+                    .any(|&v| v >= i * per_vnode && v < (i + 1) * per_vnode)
+            };
             // TODO: this can be done in parallel
-            *vnodes = (0..db.dht.partitions() as VNodeNo)
+            *vnodes = (0..partitions as VNodeNo)
                 .map(|i| {
-                    let vn = if ready_vnodes.contains(&i) {
+                    let vn = if in_range(i, &ready_vnodes) {
                         VNode::new(&db, i, VNodeStatus::Ready)
-                    } else if pending_vnodes.contains(&i) {
+                    } else if in_range(i, &pending_vnodes) {
                         VNode::new(&db, i, VNodeStatus::Bootstrap)
                     } else {
                         VNode::new(&db, i, VNodeStatus::Absent)
@@ -351,7 +491,7 @@ impl Database {
     }
 
     pub fn save(&self, shutdown: bool) {
-        for vn in self.vnodes.read().unwrap().iter() {
+        for vn in self.vnodes().iter() {
             vn.lock().unwrap().save(self, shutdown);
         }
         if shutdown {
@@ -362,12 +502,118 @@ impl Database {
         self.meta_storage.sync().expect("Can't sync storage");
     }
 
+    /// Current configuration, some settings can change at runtime (see `update_config`)
+    pub fn config(&self) -> RwLockReadGuard<Config> {
+        self.config.read().unwrap()
+    }
+
+    pub fn update_config<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Config) -> R,
+    {
+        f(&mut *self.config.write().unwrap())
+    }
+
     // Gets a Sender handle that allows sending work to the database worker pool
     pub fn sender(&self) -> WorkerSender<WorkerMsg> {
         self.workers.lock().unwrap().sender()
     }
 
+    /// Whether the node is expected to serve requests: alive for the failure detector,
+    /// connected to this node and not repeatedly timing out
+    pub fn is_node_healthy(&self, node: NodeId) -> bool {
+        node == self.dht.node()
+            || (self.dht.is_node_alive(node)
+                && self.fabric.is_connected(node)
+                && !self.health.is_down(node))
+    }
+
+    /// Sorts the nodes by preference to serve a request, healthy ones first
+    /// and then by their latency (nodes without replies yet are tried first)
+    pub fn sort_by_health(&self, nodes: &mut Vec<NodeId>) {
+        let mut ranked: Vec<_> = nodes
+            .iter()
+            .map(|&n| ((!self.is_node_healthy(n), self.health.latency(n)), n))
+            .collect();
+        ranked.sort_by_key(|&(rank, _)| rank);
+        *nodes = ranked.into_iter().map(|(_, n)| n).collect();
+    }
+
+    fn vnodes(&self) -> RwLockReadGuard<Vec<Mutex<VNode>>> {
+        self.vnodes.read().unwrap()
+    }
+
+    // Whether the vnode exists, right after the ring partition count increased the vnodes
+    // are only split once the worker handles the change. Vnodes never go away.
+    fn has_vnode(&self, vnode: VNodeNo) -> bool {
+        (vnode as usize) < self.vnodes().len()
+    }
+
+    // Splits the vnodes until they match the ring partition count. The halves only
+    // record the storage they were split from and move their entries in the background,
+    // so the write lock is only held to restructure the vnodes.
+    // Only called from the worker (dht changes and ticks) as it may finish older splits.
+    fn split_vnodes(&self) {
+        let mut split = false;
+        loop {
+            let partitions = {
+                let vnodes = self.vnodes.read().unwrap();
+                if vnodes.len() >= self.dht.partitions() {
+                    break;
+                }
+                // the next generation reuses the storage prefixes of the oldest split sources
+                for vn in vnodes.iter() {
+                    vn.lock().unwrap().finish_split(self, STORAGE_GENERATIONS - 2);
+                }
+                vnodes.len()
+            };
+
+            let mut vnodes = self.vnodes.write().unwrap();
+            if vnodes.len() != partitions {
+                // split concurrently
+                continue;
+            }
+            let gen = (self.storage_gen.load(Ordering::SeqCst) + 1) % STORAGE_GENERATIONS;
+            info!("Splitting {} vnodes", partitions);
+            let halves: Vec<VNode> = replace_default(&mut *vnodes)
+                .into_iter()
+                .flat_map(|vn| {
+                    let (first, second) = vn.into_inner().unwrap().split(self, partitions, gen);
+                    vec![first, second]
+                })
+                .collect();
+
+            let mut batch = self.meta_storage.batch_new(0);
+            for vn in &halves {
+                vn.save_into(&mut batch);
+            }
+            batch.set(b"storage_gen", gen.to_string().as_bytes());
+            batch.set(b"partitions", halves.len().to_string().as_bytes());
+            batch.set(b"ring", &self.dht.save_ring());
+            self.meta_storage
+                .batch_write(batch)
+                .expect("Can't save vnodes after split");
+            self.meta_storage.sync().log_error("Can't sync storage after split");
+
+            self.storage_gen.store(gen, Ordering::SeqCst);
+            *vnodes = halves.into_iter().map(Mutex::new).collect();
+            split = true;
+        }
+        if split {
+            // the ring may have changed while the vnodes didn't follow it
+            self.update_vnodes_status(&self.vnodes.read().unwrap());
+        }
+    }
+
+    /// Storage prefix of the vnode in the current storage generation
+    pub fn storage_prefix(&self, num: VNodeNo) -> u16 {
+        storage_prefix(num, self.storage_gen.load(Ordering::SeqCst))
+    }
+
     fn handler_dht_change(&self) {
+        self.split_vnodes();
+        let vnodes = self.vnodes();
+
         // save dht
         self.meta_storage
             .set(b"ring", &self.dht.save_ring())
@@ -376,7 +622,17 @@ impl Database {
         // register nodes
         self.fabric.set_nodes(self.dht.members().into_iter());
 
-        for (i, vn) in self.vnodes.read().unwrap().iter().enumerate() {
+        // the weight may have been changed by CLUSTER SET-WEIGHT in another node
+        let local_node = self.dht.node();
+        if let Some((_, _, (_, meta))) = self.dht.nodes().into_iter().find(|n| n.0 == local_node) {
+            self.update_config(|config| config.weight = meta.weight);
+        }
+
+        self.update_vnodes_status(&vnodes);
+    }
+
+    fn update_vnodes_status(&self, vnodes: &[Mutex<VNode>]) {
+        for (i, vn) in vnodes.iter().enumerate() {
             let final_status = if self.dht
                 .nodes_for_vnode(i as VNodeNo, true, true)
                 .contains(&self.dht.node())
@@ -389,18 +645,36 @@ impl Database {
         }
     }
 
+    fn handler_node_liveness(&self, node: NodeId, alive: bool) {
+        // fabric first, so requests sent on revival aren't dropped
+        self.fabric.set_node_alive(node, alive);
+        if self.dht.set_node_alive(node, alive) {
+            if alive {
+                info!("Node {} is alive", node);
+            } else {
+                warn!("Node {} is dead", node);
+            }
+        }
+    }
+
     fn handler_tick(&self, time: time::Instant) {
         self.dht.handler_tick(time);
+        // in case a split ring was loaded or a change was missed
+        self.split_vnodes();
 
         let mut incomming_syncs = 0usize;
-        let vnodes = self.vnodes.read().unwrap();
+        let vnodes = self.vnodes();
         for vn in vnodes.iter() {
             let mut vn = vn.lock().unwrap();
             vn.handler_tick(self, time);
             incomming_syncs += vn.syncs_inflight().0;
         }
         // auto start sync in random vnodes
-        if self.config.sync_auto && incomming_syncs < self.config.sync_incomming_max as usize {
+        let (sync_auto, sync_incomming_max) = {
+            let config = self.config();
+            (config.sync_auto, config.sync_incomming_max as usize)
+        };
+        if sync_auto && incomming_syncs < sync_incomming_max {
             for vn in vnodes
                 .iter()
                 .cycle()
@@ -408,7 +682,7 @@ impl Database {
                 .take(vnodes.len())
             {
                 incomming_syncs += vn.lock().unwrap().start_sync_if_ready(self) as usize;
-                if incomming_syncs >= self.config.sync_incomming_max as usize {
+                if incomming_syncs >= sync_incomming_max {
                     break;
                 }
             }
@@ -416,6 +690,13 @@ impl Database {
     }
 
     fn handler_fabric_msg(&self, from: NodeId, msg: FabricMsg) {
+        if let Some(vnode) = msg.vnode() {
+            // the sender already knows of a split not yet received by this node
+            if !self.has_vnode(vnode) {
+                debug!("Ignoring message to unknown vnode {} from {}", vnode, from);
+                return;
+            }
+        }
         match msg {
             FabricMsg::RemoteGet(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_get_remote(self, from, m));
@@ -441,14 +722,15 @@ impl Database {
             FabricMsg::SyncFin(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_fin(self, from, m));
             }
+            FabricMsg::SyncPush(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_push(self, from, m));
+            }
             msg => unreachable!("Can't handle {:?}", msg),
         }
     }
 
-    fn syncs_inflight(&self) -> usize {
-        self.vnodes
-            .read()
-            .unwrap()
+    pub fn syncs_inflight(&self) -> usize {
+        self.vnodes()
             .iter()
             .map(|vn| {
                 let inf = vn.lock().unwrap().syncs_inflight();
@@ -457,9 +739,113 @@ impl Database {
             .sum()
     }
 
+    pub fn syncs_progress(&self) -> Vec<(VNodeNo, SyncProgress)> {
+        let vnodes = self.vnodes();
+        let mut result = Vec::new();
+        for (i, vn) in vnodes.iter().enumerate() {
+            let progress = vn.lock().unwrap().syncs_progress();
+            result.extend(progress.into_iter().map(|p| (i as VNodeNo, p)));
+        }
+        result
+    }
+
+    pub fn vnode_storage_stats(&self, vnode: VNodeNo) -> Option<(u64, u64)> {
+        if !self.has_vnode(vnode) {
+            return None;
+        }
+        vnode!(self, vnode, |vn| vn.storage_stats())
+    }
+
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        let vnode = self.dht.slot_vnode(slot);
+        if !self.has_vnode(vnode) {
+            return 0;
+        }
+        let counter = vnode!(self, vnode, |vn| vn.slot_counter());
+        counter.count_keys_in_slot(slot)
+    }
+
+    pub fn vnodes_status(&self) -> Vec<VNodeStatus> {
+        self.vnodes()
+            .iter()
+            .map(|vn| vn.lock().unwrap().status())
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn _clear_log(&self) {
+        for vn in self.vnodes().iter() {
+            vn.lock().unwrap()._clear_log();
+        }
+    }
+
+    #[cfg(test)]
+    fn _update_log_watermark(&self) -> usize {
+        self.vnodes()
+            .iter()
+            .filter(|vn| vn.lock().unwrap()._update_log_watermark(self))
+            .count()
+    }
+
+    // the number of splitting vnodes and how many of them updated their watermark
+    #[cfg(test)]
+    fn _update_log_watermark_splitting(&self) -> (usize, usize) {
+        let (mut splitting, mut updated) = (0, 0);
+        for vn in self.vnodes().iter() {
+            let mut vn = vn.lock().unwrap();
+            if vn.split_sources() != 0 {
+                splitting += 1;
+                if vn._update_log_watermark(self) {
+                    updated += 1;
+                }
+            }
+        }
+        (splitting, updated)
+    }
+
+    #[cfg(test)]
+    fn _sweep(&self) -> usize {
+        self.vnodes()
+            .iter()
+            .map(|vn| vn.lock().unwrap()._sweep())
+            .sum()
+    }
+
+    #[cfg(test)]
+    fn _zombies_handed_off(&self) -> usize {
+        self.vnodes()
+            .iter()
+            .filter(|vn| vn.lock().unwrap()._handed_off(self))
+            .count()
+    }
+
+    #[cfg(test)]
+    fn _splits_pending(&self) -> usize {
+        self.vnodes()
+            .iter()
+            .filter(|vn| vn.lock().unwrap().split_sources() != 0)
+            .count()
+    }
+
+    #[cfg(test)]
+    fn _bootstrap_checkpoints(&self) -> usize {
+        self.vnodes()
+            .iter()
+            .filter(|vn| vn.lock().unwrap()._has_bootstrap_checkpoint())
+            .count()
+    }
+
+    #[cfg(test)]
+    fn _bootstrap_received(&self) -> u64 {
+        self.vnodes()
+            .iter()
+            .map(|vn| vn.lock().unwrap()._bootstrap_received())
+            .sum()
+    }
+
     #[cfg(test)]
     fn _start_sync(&self, vnode: VNodeNo) -> bool {
-        let vnodes = self.vnodes.read().unwrap();
+        let vnodes = self.vnodes();
         let mut vnode = vnodes.get(vnode as usize).unwrap().lock().unwrap();
         vnode._start_sync(self)
     }
@@ -467,14 +853,14 @@ impl Database {
     pub fn signal_sync_start(&self, direction: SyncDirection) -> bool {
         let mut stats = self.stats.lock().unwrap();
         match direction {
-            SyncDirection::Incomming => if stats.incomming_syncs < self.config.sync_incomming_max {
+            SyncDirection::Incomming => if stats.incomming_syncs < self.config().sync_incomming_max {
                 stats.incomming_syncs += 1;
                 metrics::SYNC_INCOMING.inc();
                 true
             } else {
                 false
             },
-            SyncDirection::Outgoing => if stats.outgoing_syncs < self.config.sync_outgoing_max {
+            SyncDirection::Outgoing => if stats.outgoing_syncs < self.config().sync_outgoing_max {
                 stats.outgoing_syncs += 1;
                 metrics::SYNC_OUTGOING.inc();
                 true
@@ -499,6 +885,16 @@ impl Database {
     }
 
     // CLIENT CRUD
+
+    // requests for vnodes not split yet can be retried once the worker splits them
+    fn check_vnode(&self, vnode: VNodeNo) -> Result<(), CommandError> {
+        if self.has_vnode(vnode) {
+            Ok(())
+        } else {
+            Err(CommandError::Unavailable)
+        }
+    }
+
     pub fn set_flush(
         &self,
         context: &mut Context,
@@ -524,6 +920,7 @@ impl Database {
         }
 
         if let Some(vnode) = multi_vnode {
+            self.check_vnode(vnode)?;
             vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
         } else {
             Ok(self.respond_resp(context, RespValue::Array(Default::default())))
@@ -555,6 +952,7 @@ impl Database {
         } else {
             debug_assert_eq!(context.writes.len(), 1);
             let vnode = self.dht.key_vnode(key);
+            self.check_vnode(vnode)?;
             vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
         }
     }
@@ -568,6 +966,7 @@ impl Database {
     ) -> Result<(), CommandError> {
         debug_assert!(!context.is_multi && !context.is_exec);
         let vnode = self.dht.key_vnode(key);
+        self.check_vnode(vnode)?;
         vnode!(self, vnode, |vn| vn.do_get(
             self,
             context,
@@ -595,6 +994,7 @@ impl Database {
             }
         }
         if let Some(vnode) = multi_vnode {
+            self.check_vnode(vnode)?;
             vnode!(self, vnode, |vn| vn.do_get(
                 self,
                 context,
@@ -611,7 +1011,8 @@ impl Database {
 impl Drop for Database {
     fn drop(&mut self) {
         debug!("Droping database");
-        // force dropping vnodes before other components
+        // stop the failure detector, then force dropping vnodes before other components
+        let _ = self.gossiper.lock().map(|mut g| g.take());
         let _ = self.vnodes.write().map(|mut vns| vns.clear());
     }
 }
@@ -622,11 +1023,15 @@ mod tests {
     use bincode;
     use config;
     use env_logger;
+    use futures::sync::mpsc;
+    use futures::{Future, Stream};
+    use hash::hash_slot;
+    use prometheus;
     use resp::RespValue;
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
     use std::{fs, net, ops};
-    use utils::sleep_ms;
+    use utils::{assume_str, sleep_ms, split_u64};
     use version_vector::VersionVector;
 
     #[allow(non_upper_case_globals)]
@@ -710,6 +1115,14 @@ mod tests {
                 .unwrap()
         }
 
+        // the error message of the response, panics if it isn't an error
+        fn response_error(&self, token: Token) -> String {
+            match self.response_resp(token) {
+                RespValue::Error(e) => assume_str(&e).to_owned(),
+                r => panic!("Expected an error response, got {:?}", r),
+            }
+        }
+
         fn response_values(&self, token: Token) -> (Vec<Vec<u8>>, VersionVector) {
             decode_values(self.response_resp(token))
         }
@@ -722,6 +1135,48 @@ mod tests {
             self.handler_cmd(context)
         }
 
+        // opens an existing node again and waits for fabric to reconnect
+        fn reopen(fabric_addr: &str, data_dir: &str) -> Self {
+            let db = TestDatabase::new(fabric_addr.parse().unwrap(), data_dir, false);
+            sleep_ms(200);
+            db
+        }
+
+        // sets the keys 0..count to their own number
+        fn set_keys(&self, count: u64, consistency: &[u8]) {
+            for i in 0..count {
+                let key = i.to_string();
+                self.do_cmd(i, &[b"SET", key.as_bytes(), key.as_bytes(), b"", consistency]);
+                self.response_resp(i);
+            }
+        }
+
+        // checks the keys set by `set_keys`
+        fn check_keys(&self, count: u64) {
+            for i in 0..count {
+                let key = i.to_string();
+                self.do_cmd(i, &[b"GET", key.as_bytes(), One]);
+                assert_eq!(self.response_values(i).0, [key.as_bytes()]);
+            }
+        }
+
+        // a single message in flight per sync, so they can be interrupted
+        fn slow_syncs(&self) {
+            self.do_cmd(0, &[b"CONFIG", b"SET", b"sync_msg_inflight", b"1"]);
+            assert_eq!(self.response_resp(0), RespValue::Status("OK".into()));
+        }
+
+        // waits until the bootstraps received `count` keys (or all vnodes are ready)
+        fn wait_bootstrap_received(&self, count: u64) {
+            while self._bootstrap_received() < count
+                && self.vnodes_status()
+                    .iter()
+                    .any(|&s| s != VNodeStatus::Ready)
+            {
+                sleep_ms(1);
+            }
+        }
+
         fn dump_logs(&self) -> HashMap<VNodeNo, Vec<((NodeId, Version), Vec<u8>)>> {
             self.vnodes
                 .read()
@@ -846,6 +1301,370 @@ mod tests {
         assert_eq!(db.response_values(1).0.len(), 0);
     }
 
+    #[test]
+    fn test_client() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let (monitor_tx1, _) = mpsc::channel(1);
+        let (monitor_tx2, _) = mpsc::channel(1);
+        let _kill_rx1 = db.clients
+            .register(1, "127.0.0.1:5001".parse().unwrap(), monitor_tx1);
+        let kill_rx2 = db.clients
+            .register(2, "127.0.0.1:5002".parse().unwrap(), monitor_tx2);
+
+        db.do_cmd(1, &[b"CLIENT", b"ID"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+
+        db.do_cmd(1, &[b"CLIENT", b"GETNAME"]);
+        assert_eq!(db.response_resp(1), RespValue::Nil);
+        db.do_cmd(1, &[b"CLIENT", b"SETNAME", b"bad name"]);
+        db.response_error(1);
+        db.do_cmd(1, &[b"CLIENT", b"SETNAME", b"test"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"CLIENT", b"GETNAME"]);
+        assert_eq!(db.response_resp(1), RespValue::Data("test".into()));
+
+        db.do_cmd(1, &[b"CLIENT", b"LIST"]);
+        if let RespValue::Data(list) = db.response_resp(1) {
+            let lines: Vec<_> = assume_str(&list).lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].starts_with("id=1 addr=127.0.0.1:5001 name=test "));
+            assert!(lines[1].starts_with("id=2 addr=127.0.0.1:5002 name= "));
+        } else {
+            panic!("CLIENT LIST didn't return a bulk string");
+        }
+
+        db.do_cmd(1, &[b"CLIENT", b"KILL", b"ID", b"2"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        assert!(kill_rx2.wait().is_ok());
+        assert_eq!(db.clients.len(), 1);
+        db.do_cmd(1, &[b"CLIENT", b"KILL", b"127.0.0.1:5002"]);
+        db.response_error(1);
+    }
+
+    #[test]
+    fn test_cluster_commands() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        db.do_cmd(1, &[b"CLUSTER", b"KEYSLOT", b"somekey"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(11058));
+        db.do_cmd(1, &[b"CLUSTER", b"KEYSLOT", b"foo{hash_tag}"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(2515));
+
+        db.do_cmd(1, &[b"SET", b"somekey", b"1", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"CLUSTER", b"COUNTKEYSINSLOT", b"11058"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd(1, &[b"CLUSTER", b"COUNTKEYSINSLOT", b"11059"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+
+        db.do_cmd(1, &[b"CLUSTER", b"NODES"]);
+        if let RespValue::Data(nodes) = db.response_resp(1) {
+            let lines: Vec<_> = assume_str(&nodes).lines().collect();
+            assert_eq!(lines.len(), 1);
+            assert!(lines[0].starts_with(&format!("{:040x} ", db.dht.node())));
+            assert!(lines[0].contains(" myself,master "));
+            assert!(lines[0].ends_with(" connected 0-16383"));
+        } else {
+            panic!("CLUSTER NODES didn't return a bulk string");
+        }
+
+        db.do_cmd(1, &[b"CLUSTER", b"INFO"]);
+        if let RespValue::Data(info) = db.response_resp(1) {
+            let info = assume_str(&info);
+            assert!(info.contains("cluster_state:ok\r\n"));
+            assert!(info.contains("cluster_slots_ok:16384\r\n"));
+            assert!(info.contains("cluster_known_nodes:1\r\n"));
+        } else {
+            panic!("CLUSTER INFO didn't return a bulk string");
+        }
+
+        db.do_cmd(1, &[b"CLUSTER", b"SHARDS"]);
+        if let RespValue::Array(shards) = db.response_resp(1) {
+            assert_eq!(shards.len(), PARTITIONS);
+        } else {
+            panic!("CLUSTER SHARDS didn't return an array");
+        }
+
+        // the only node can't leave or be forgotten
+        let this = db.dht.node().to_string();
+        let cmds: &[&[&[u8]]] = &[
+            &[b"CLUSTER", b"LEAVE"],
+            &[b"CLUSTER", b"FORGET", this.as_bytes()],
+            &[b"CLUSTER", b"FORGET", b"1234"],
+            &[b"CLUSTER", b"REPLACE", this.as_bytes(), b"1234"],
+            &[b"CLUSTER", b"SET-REPLICATION", b"0"],
+        ];
+        for cmd in cmds {
+            db.do_cmd(1, cmd);
+            let error = db.response_error(1);
+            assert!(error.starts_with("ClusterError "), "{:?} {}", cmd, error);
+        }
+        let ring_version = db.dht.ring_version();
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert!(db.dht.ring_version() != ring_version);
+
+        let plan_id = |resp: RespValue| {
+            if let RespValue::Array(plan) = resp {
+                assert_eq!(plan[3], RespValue::Array(vec![]));
+                if let RespValue::Int(id) = plan[1] {
+                    return id.to_string();
+                }
+            }
+            panic!("Unexpected plan")
+        };
+        // plans are invalidated by ring changes
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE", b"PLAN"]);
+        let id = plan_id(db.response_resp(1));
+        db.dht.rebalance().unwrap();
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE", b"APPLY", id.as_bytes()]);
+        db.response_error(1);
+
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE", b"PLAN"]);
+        let id = plan_id(db.response_resp(1));
+        let ring_version = db.dht.ring_version();
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE", b"APPLY", id.as_bytes()]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert!(db.dht.ring_version() != ring_version);
+        // plans can only be applied once
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE", b"APPLY", id.as_bytes()]);
+        db.response_error(1);
+
+        // node ids are reported in hex, decimal ones are accepted too
+        let node = format!("{:040x}", db.dht.node());
+        db.do_cmd(1, &[b"CLUSTER", b"SET-WEIGHT", node.as_bytes(), b"0"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("InvalidValue".into()));
+        db.do_cmd(1, &[b"CLUSTER", b"SET-WEIGHT", node.as_bytes(), b"2"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(db.config().weight, 2);
+        db.do_cmd(1, &[b"CLUSTER", b"REBALANCE", b"PLAN"]);
+        if let RespValue::Array(plan) = db.response_resp(1) {
+            assert_eq!(
+                plan[5],
+                RespValue::Array(vec![RespValue::Array(vec![
+                    RespValue::Data(node.as_bytes().into()),
+                    RespValue::Int(2),
+                    RespValue::Int(PARTITIONS as _),
+                    RespValue::Int(0),
+                    RespValue::Int(0),
+                ])])
+            );
+        } else {
+            panic!("Unexpected plan");
+        }
+
+        db.do_cmd(1, &[b"CLUSTER", b"SET-REPLICATION", b"2"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(db.dht.replication_factor(), 2);
+        db.do_cmd(1, &[b"CLUSTER", b"REPLICATION"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![
+                RespValue::Data("replication_factor".into()),
+                RespValue::Int(2),
+                RespValue::Data("replicas".into()),
+                RespValue::Int(1),
+                RespValue::Data("vnodes".into()),
+                RespValue::Int(PARTITIONS as _),
+                RespValue::Data("vnodes_complete".into()),
+                RespValue::Int(PARTITIONS as _),
+                RespValue::Data("replicas_pending".into()),
+                RespValue::Int(0),
+                RespValue::Data("replicas_retiring".into()),
+                RespValue::Int(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        db.do_cmd(1, &[b"CONFIG", b"GET", b"sync_*_max"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![
+                RespValue::Data("sync_incomming_max".into()),
+                RespValue::Data("100".into()),
+                RespValue::Data("sync_outgoing_max".into()),
+                RespValue::Data("100".into()),
+            ])
+        );
+
+        db.do_cmd(1, &[b"CONFIG", b"SET", b"request_timeout", b"2s"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(db.config().request_timeout, 2000);
+        db.do_cmd(1, &[b"CONFIG", b"GET", b"request_timeout"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![
+                RespValue::Data("request_timeout".into()),
+                RespValue::Data("2000ms".into()),
+            ])
+        );
+
+        for &(key, value) in &[
+            (&b"request_timeout"[..], &b"soon"[..]),
+            (b"listen_addr", b"127.0.0.1:7000"),
+        ] {
+            db.do_cmd(1, &[b"CONFIG", b"SET", key, value]);
+            db.response_error(1);
+        }
+        assert_eq!(db.config().request_timeout, 2000);
+
+        // concurrent versions of a value over value_version_max are rejected
+        db.do_cmd(1, &[b"CONFIG", b"SET", b"value_version_max", b"2"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        for &value in &[&b"1"[..], b"2"] {
+            db.do_cmd(1, &[b"SET", b"key", value, b""]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+        db.do_cmd(1, &[b"SET", b"key", b"3", b""]);
+        assert_eq!(db.response_resp(1), RespValue::Error("TooManyVersions".into()));
+        db.do_cmd(1, &[b"GET", b"key"]);
+        let (values, vv) = db.response_values(1);
+        assert_eq!(values.len(), 2);
+        db.do_cmd(1, &[b"SET", b"key", b"3", &encode_vv(&vv)]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+    }
+
+    #[test]
+    fn test_weight_restart() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let node = db.dht.node().to_string();
+        db.do_cmd(1, &[b"CLUSTER", b"SET-WEIGHT", node.as_bytes(), b"3"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        // the ring weight is kept over the config one, with or without a clean shutdown
+        for &shutdown in &[true, false] {
+            db.save(shutdown);
+            drop(db);
+            db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+            let local_node = db.dht.node();
+            let (_, _, (_, meta)) = db.dht
+                .nodes()
+                .into_iter()
+                .find(|n| n.0 == local_node)
+                .unwrap();
+            assert_eq!(meta.weight, 3);
+            assert_eq!(db.config().weight, 3);
+        }
+    }
+
+    #[test]
+    fn test_info() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"GET", b"key", One]);
+        db.response_values(1);
+
+        db.do_cmd(1, &[b"INFO"]);
+        if let RespValue::Data(info) = db.response_resp(1) {
+            let info = assume_str(&info);
+            for section in &["Server", "Clients", "Cluster", "Sync", "Stats", "Rocksdb"] {
+                assert!(info.contains(&format!("# {}\r\n", section)), "{}", section);
+            }
+            assert!(info.contains(&format!("node_id:{:040x}\r\n", db.dht.node())));
+            assert!(info.contains(&format!("vnodes_ready:{}\r\n", PARTITIONS)));
+        } else {
+            panic!("INFO didn't return a bulk string");
+        }
+
+        // the ring version is reported as the vector, the epoch is its sum
+        db.do_cmd(1, &[b"INFO", b"cluster"]);
+        if let RespValue::Data(info) = db.response_resp(1) {
+            let info = assume_str(&info);
+            assert!(info.starts_with("# Cluster\r\n"));
+            assert!(!info.contains("# Server"));
+            let version = db.dht.ring_version();
+            let entries: Vec<_> = version
+                .iter()
+                .map(|(node, v)| format!("{:040x}={}", node, v))
+                .collect();
+            assert!(info.contains(&format!("ring_version:{}\r\n", entries.join(","))));
+            let epoch: Version = version.iter().map(|(_, v)| v).sum();
+            assert!(info.contains(&format!("ring_epoch:{}\r\n", epoch)));
+        } else {
+            panic!("INFO cluster didn't return a bulk string");
+        }
+
+        let metrics = prometheus::render(&db);
+        assert!(metrics.contains("# TYPE sucredb_command_duration_seconds histogram\n"));
+        assert!(metrics.contains(&format!("sucredb_vnodes{{status=\"ready\"}} {}\n", PARTITIONS)));
+        assert!(metrics.contains("sucredb_vnodes{status=\"absent\"} 0\n"));
+        // the samples of each metric are grouped under a single TYPE line
+        let mut types: Vec<_> = metrics.lines().filter(|l| l.starts_with("# TYPE ")).collect();
+        let type_lines = types.len();
+        types.sort();
+        types.dedup();
+        assert_eq!(types.len(), type_lines);
+        assert!(metrics.contains("# TYPE sucredb_rocksdb_estimate_num_keys gauge\n"));
+        // the GET above was recorded, buckets are cumulative and end with +Inf
+        let value = |line: &str| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap();
+        let buckets: Vec<_> = metrics
+            .lines()
+            .filter(|l| l.starts_with("sucredb_command_duration_seconds_bucket{command=\"get\","))
+            .collect();
+        assert!(buckets.last().unwrap().contains("le=\"+Inf\""));
+        assert!(buckets.windows(2).all(|w| value(w[0]) <= value(w[1])));
+        let count = metrics
+            .lines()
+            .find(|l| l.starts_with("sucredb_command_duration_seconds_count{command=\"get\"}"))
+            .unwrap();
+        assert!(value(count) >= 1);
+        assert_eq!(value(count), value(buckets.last().unwrap()));
+    }
+
+    #[test]
+    fn test_monitor() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let (monitor_tx1, monitor_rx1) = mpsc::channel(10);
+        let (monitor_tx2, _) = mpsc::channel(10);
+        let _kill_rx1 = db.clients
+            .register(1, "127.0.0.1:5001".parse().unwrap(), monitor_tx1);
+        let _kill_rx2 = db.clients
+            .register(2, "127.0.0.1:5002".parse().unwrap(), monitor_tx2);
+
+        db.do_cmd(1, &[b"MONITOR"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let long_value = vec![b'a'; 100];
+        db.do_cmd(2, &[b"SET", b"key", &long_value]);
+        db.response_resp(2);
+        db.do_cmd(2, &[b"ECHO", b"\"x\"\n"]);
+        db.response_resp(2);
+
+        let lines: Vec<_> = monitor_rx1
+            .take(2)
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|l| {
+                if let RespValue::Status(l) = l {
+                    assume_str(&l).to_owned()
+                } else {
+                    panic!("Unexpected monitor output {:?}", l)
+                }
+            })
+            .collect();
+        assert!(lines[0].ends_with(&format!(
+            " [127.0.0.1:5002] \"SET\" \"key\" \"{}\"... (36 more bytes)",
+            "a".repeat(64)
+        )));
+        assert!(lines[1].ends_with(" [127.0.0.1:5002] \"ECHO\" \"\\\"x\\\"\\n\""));
+    }
+
     #[test]
     fn test_two() {
         let _ = fs::remove_dir_all("t/");
@@ -1010,6 +1829,285 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_bootstrap_resume() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let keys = TEST_JOIN_SIZE * 10;
+        db1.set_keys(keys, One);
+        db1.slow_syncs();
+
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+
+        // kill the sender once the bootstraps are half way
+        db2.wait_bootstrap_received(keys / 2);
+        assert!(db2._bootstrap_checkpoints() > 0);
+        warn!("killing db1 mid bootstrap");
+        drop(db1);
+        db1 = TestDatabase::reopen("127.0.0.1:9000", "t/db1");
+
+        // the interrupted bootstraps time out and resume from their checkpoints,
+        // at most the key in flight of each one is received again
+        db2.wait_syncs();
+        assert_eq!(db2._bootstrap_checkpoints(), 0);
+        assert!(db2._bootstrap_received() <= keys + PARTITIONS as u64);
+        for &db in &[&db1, &db2] {
+            db.check_keys(keys);
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_parallel() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+        let keys = TEST_JOIN_SIZE * 10;
+        db1.set_keys(keys, All);
+        db1.slow_syncs();
+        db2.slow_syncs();
+
+        // node 3 bootstraps shares from both nodes
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db3.dht.rebalance().unwrap();
+        db3.wait_bootstrap_received(keys / 2);
+        assert!(db3._bootstrap_checkpoints() > 0);
+
+        // the shares of node 2 are reassigned to node 1, resuming from their checkpoints
+        warn!("killing db2 mid bootstrap");
+        let db2_node = db2.dht.node();
+        drop(db2);
+        db3.wait_syncs();
+        assert_eq!(db3._bootstrap_checkpoints(), 0);
+        assert!(db3._bootstrap_received() <= keys + PARTITIONS as u64);
+        db1.dht.remove_node(db2_node).unwrap();
+        sleep_ms(100);
+
+        for &db in &[&db1, &db3] {
+            db.check_keys(keys);
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    All,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        db1.do_cmd(0, &[b"CLUSTER", b"SPLIT"]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        sleep_ms(100);
+        // the halves logs are still in the split sources, so nothing looks truncated
+        for &db in &[&db1, &db2] {
+            let (splitting, updated) = db._update_log_watermark_splitting();
+            assert!(splitting > 0);
+            assert_eq!(updated, 0);
+        }
+        for &db in &[&db1, &db2] {
+            assert_eq!(db.vnodes_status().len(), PARTITIONS * 2);
+            // reads go through to the split sources until the entries are moved
+            db.check_keys(TEST_JOIN_SIZE);
+            while db._splits_pending() != 0 {
+                sleep_ms(10);
+            }
+        }
+
+        for &db in &[&db1, &db2] {
+            assert_eq!(db.dht.partitions(), PARTITIONS * 2);
+            assert_eq!(db.vnodes_status().len(), PARTITIONS * 2);
+            for i in 0..TEST_JOIN_SIZE {
+                let key = i.to_string();
+                assert_eq!(db.count_keys_in_slot(hash_slot(key.as_bytes())), 1);
+                db.do_cmd(i, &[b"GET", key.as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [key.as_bytes()]);
+            }
+        }
+
+        // writes are replicated with the new partitioning
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"new", b"", All]);
+            db1.response_values(i);
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_node_liveness() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let node2 = db2.dht.node();
+        assert!(db1.dht.is_node_alive(node2));
+        db1.handler_node_liveness(node2, false);
+        assert!(!db1.dht.is_node_alive(node2));
+
+        db1.do_cmd(0, &[b"CLUSTER", b"NODES"]);
+        if let RespValue::Data(nodes) = db1.response_resp(0) {
+            let prefix = format!("{:040x} ", node2);
+            let line = assume_str(&nodes)
+                .lines()
+                .find(|l| l.starts_with(&prefix))
+                .unwrap();
+            assert!(line.contains(" master,fail "));
+        } else {
+            panic!("CLUSTER NODES didn't return a bulk string");
+        }
+
+        // the dead node is reported as failed in every shard it serves
+        db1.do_cmd(0, &[b"CLUSTER", b"SHARDS"]);
+        let node2_id = RespValue::Data(format!("{:040x}", node2).into());
+        let mut node2_shards = 0;
+        if let RespValue::Array(shards) = db1.response_resp(0) {
+            for shard in shards {
+                let shard_nodes = match shard {
+                    RespValue::Array(mut fields) => fields.pop().unwrap(),
+                    _ => panic!("Unexpected shard"),
+                };
+                if let RespValue::Array(shard_nodes) = shard_nodes {
+                    for fields in shard_nodes {
+                        if let RespValue::Array(fields) = fields {
+                            if fields[1] == node2_id {
+                                assert_eq!(fields[13], RespValue::Data("fail".into()));
+                                node2_shards += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        } else {
+            panic!("CLUSTER SHARDS didn't return an array");
+        }
+        assert!(node2_shards > 0);
+
+        assert!(!db1.is_node_healthy(node2));
+        let mut nodes = vec![node2, db1.dht.node()];
+        db1.sort_by_health(&mut nodes);
+        assert_eq!(nodes, [db1.dht.node(), node2]);
+
+        // the dead replica is skipped, so requiring it fails right away
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", All]);
+        db1.response_error(0);
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", One]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+
+        // but syncs still go through, the failure detector could be wrong
+        db2.force_syncs();
+        db2.do_cmd(0, &[b"GET", b"key", One]);
+        assert_eq!(db2.response_values(0).0, [b"value"]);
+
+        db1.handler_node_liveness(node2, true);
+        assert!(db1.is_node_healthy(node2));
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+    }
+
+    #[test]
+    fn test_node_dead() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // the gossiper notices the node is gone (ping and suspect timeouts)
+        let node2 = db2.dht.node();
+        assert!(db1.dht.is_node_alive(node2));
+        // clean shutdown so it keeps its node id
+        db2.save(true);
+        drop(db2);
+        for _ in 0..200 {
+            if !db1.dht.is_node_alive(node2) {
+                break;
+            }
+            sleep_ms(100);
+        }
+        assert!(!db1.dht.is_node_alive(node2));
+        assert!(!db1.is_node_healthy(node2));
+
+        // and that it's back once restarted
+        db2 = TestDatabase::reopen("127.0.0.1:9001", "t/db2");
+        assert_eq!(db2.dht.node(), node2);
+        for _ in 0..200 {
+            if db1.dht.is_node_alive(node2) {
+                break;
+            }
+            sleep_ms(100);
+        }
+        assert!(db1.dht.is_node_alive(node2));
+    }
+
+    #[test]
+    fn test_single_read_hedge() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+        db1.dht.set_replication_factor(2).unwrap();
+        db1.dht.finish_rebalance().unwrap();
+        sleep_ms(200); // wait for the ring to propagate
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        // a key db1 doesn't replicate, so it reads from a single replica
+        let key = (0..)
+            .map(|i: u64| i.to_string())
+            .find(|k| {
+                let vnode = db1.dht.key_vnode(k.as_bytes());
+                !db1.dht
+                    .nodes_for_vnode(vnode, false, true)
+                    .contains(&db1.dht.node())
+            })
+            .unwrap();
+        let vnode = db1.dht.key_vnode(key.as_bytes());
+        db2.do_cmd(0, &[b"SET", key.as_bytes(), b"value", b"", All]);
+        assert_eq!(db2.response_resp(0), RespValue::Status("OK".into()));
+
+        // db2 is the preferred replica but doesn't reply, so db3 is asked too
+        db1.health.record_reply(db3.dht.node(), time::Duration::from_millis(100));
+        db1.health.record_reply(db2.dht.node(), time::Duration::from_millis(1));
+        let vnodes = db2.vnodes();
+        let locked = vnodes[vnode as usize].lock().unwrap();
+        db1.do_cmd(0, &[b"GET", key.as_bytes(), One]);
+        assert_eq!(db1.response_values(0).0, [b"value"]);
+        drop(locked);
+    }
+
     #[test]
     fn test_sync() {
         let _ = fs::remove_dir_all("t/");
@@ -1117,6 +2215,190 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_zombie_handoff() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let mut db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        // writes only db1 knows about
+        drop(db2);
+        drop(db3);
+        db1.set_keys(TEST_JOIN_SIZE, One);
+        db2 = TestDatabase::reopen("127.0.0.1:9001", "t/db2");
+        db3 = TestDatabase::reopen("127.0.0.1:9002", "t/db3");
+
+        // db1 vnodes go zombie and push their data to the owners
+        db2.dht.remove_node(db1.dht.node()).unwrap();
+        while db1._zombies_handed_off() != PARTITIONS {
+            sleep_ms(10);
+        }
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        for &db in &[&db2, &db3] {
+            db.check_keys(TEST_JOIN_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_prune_retired_ids() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // written by db1 and overwritten by db2, so no value keeps dots from db1
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"1", b"", All]);
+            db1.response_resp(i);
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            let (_, vv) = db2.response_values(i);
+            db2.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"2", &encode_vv(&vv), All]);
+            db2.response_resp(i);
+        }
+        db2.do_cmd(0, &[b"GET", b"0", One]);
+        let (_, old_vv) = db2.response_values(0);
+
+        // the ids used by db1 retire with the dirty restart
+        db1.save(false);
+        drop(db1);
+        db1 = TestDatabase::reopen("127.0.0.1:9000", "t/db1");
+        let physical_id = split_u64(db1.dht.node()).0;
+        assert!(old_vv.iter().any(|(id, _)| split_u64(id).0 == physical_id));
+
+        // db1 learns that db2 saw everything and prunes, then db2 learns from db1
+        db2.force_syncs();
+        db1.force_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                let (values, vv) = db.response_values(i);
+                assert_eq!(values, [b"2"]);
+                assert!(vv.iter().all(|(id, _)| split_u64(id).0 != physical_id));
+            }
+        }
+
+        // contexts from before the pruning still work
+        db2.do_cmd(0, &[b"SET", b"0", b"3", &encode_vv(&old_vv), One]);
+        db2.response_resp(0);
+        db2.do_cmd(0, &[b"GET", b"0", One]);
+        assert_eq!(db2.response_values(0).0, [b"3"]);
+    }
+
+    #[test]
+    fn test_sweep_subsumed() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // a write db2 misses leaves a gap in its clocks
+        drop(db2);
+        db1.do_cmd(1, &[b"SET", b"{k}1", b"1", b"", One]);
+        db1.response_resp(1);
+        db2 = TestDatabase::reopen("127.0.0.1:9001", "t/db2");
+
+        // so the tombstone isn't subsumed when written
+        db1.do_cmd(1, &[b"SET", b"{k}2", b"2", b"", All]);
+        db1.response_resp(1);
+        db1.do_cmd(1, &[b"GET", b"{k}2", One]);
+        let (_, vv) = db1.response_values(1);
+        db1.do_cmd(1, &[b"DEL", b"{k}2", &encode_vv(&vv), All]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        let slot = hash_slot(b"k");
+        assert_eq!(db2.count_keys_in_slot(slot), 1);
+
+        // but it is after the sync fills the gap, so it's no longer counted
+        db2.force_syncs();
+        assert_eq!(db2.count_keys_in_slot(slot), 1);
+        assert_eq!(db2._sweep(), 1);
+        assert_eq!(db2.count_keys_in_slot(slot), 1);
+
+        db2.do_cmd(1, &[b"GET", b"{k}1", One]);
+        assert_eq!(db2.response_values(1).0, [b"1"]);
+        db2.do_cmd(1, &[b"GET", b"{k}2", One]);
+        assert_eq!(db2.response_values(1).0.len(), 0);
+    }
+
+    #[test]
+    fn test_sync_log_truncated() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        warn!("droping db2");
+        drop(db2);
+        db1.set_keys(TEST_JOIN_SIZE, One);
+        // sim the log ttl expiring while db2 is down
+        db1._clear_log();
+        assert!(db1._update_log_watermark() > 0);
+        // nothing else was dropped
+        assert_eq!(db1._update_log_watermark(), 0);
+
+        warn!("bringing back db2");
+        db2 = TestDatabase::reopen("127.0.0.1:9001", "t/db2");
+        db2.force_syncs();
+        db2.wait_syncs();
+        db2.check_keys(TEST_JOIN_SIZE);
+    }
+
+    #[test]
+    fn test_sync_log_truncated_del() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"1", b"", All]);
+            db1.response_resp(i);
+        }
+
+        // deletes db2 misses, db1 doesn't keep any trace of the keys
+        warn!("droping db2");
+        drop(db2);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            let (_, vv) = db1.response_values(i);
+            db1.do_cmd(i, &[b"DEL", i.to_string().as_bytes(), &encode_vv(&vv), One]);
+            assert_eq!(db1.response_resp(i), RespValue::Int(1));
+        }
+        db1._clear_log();
+        assert!(db1._update_log_watermark() > 0);
+
+        // so the hash tree sync has to tell db2 about them
+        warn!("bringing back db2");
+        db2 = TestDatabase::reopen("127.0.0.1:9001", "t/db2");
+        db2.force_syncs();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0.len(), 0);
+        }
+    }
+
     #[test]
     fn test_consistency_level() {
         let _ = fs::remove_dir_all("t/");
@@ -1168,6 +2450,18 @@ mod tests {
             db1.do_cmd(0, &[b"GETSET", b"other", b"", b"", cl]);
             assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
         }
+
+        // reads and writes without a consistency level use their own defaults
+        db1.do_cmd(0, &[b"CONFIG", b"SET", b"consistency_write", b"all"]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"GET", b"key"]);
+        assert_eq!(db1.response_values(0).0, [b"value"]);
+        db1.do_cmd(0, &[b"SET", b"other", b""]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+        db1.do_cmd(0, &[b"CONFIG", b"SET", b"consistency_write", b"one"]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        db1.do_cmd(0, &[b"SET", b"other", b""]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
     }
 
     fn stub_aae_converge(drop: usize) {
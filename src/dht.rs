@@ -1,6 +1,6 @@
-use std::cmp::min;
+use std::cmp::{max, min, Ordering};
 use std::collections::hash_map::Entry as HMEntry;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
@@ -16,7 +16,7 @@ use serde::Serialize;
 use config::Config;
 use database::{NodeId, VNodeNo};
 use fabric::{Fabric, FabricMsg, FabricMsgRef, FabricMsgType};
-use hash::{hash_slot, HASH_SLOTS};
+use hash::{self, hash_slot, HASH_SLOTS};
 use types::PhysicalNodeId;
 use utils::{split_u64, GenericError, IdHashMap, IdHashSet};
 use version_vector::VersionVector;
@@ -28,13 +28,35 @@ pub type DHTChangeFn = Box<Fn() + Send + Sync>;
 pub trait Metadata:
     Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync + fmt::Debug + 'static
 {
-}
+    /// Failure domain (rack, availability zone, etc) of the node.
+    /// Rebalance spreads the replicas of each vnode across as many zones as possible.
+    fn zone(&self) -> &str {
+        ""
+    }
 
-impl<T: Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync + fmt::Debug + 'static>
-    Metadata for T
-{
+    /// Capacity of the node relative to the others.
+    /// Rebalance assigns vnodes to the nodes in proportion to their weight.
+    fn weight(&self) -> u32 {
+        1
+    }
+
+    /// Metadata of a node in a ring saved before zones and weights,
+    /// when the metadata was the node (client) address.
+    /// None if such rings can't be upgraded.
+    fn from_addr(_addr: SocketAddr) -> Option<Self> {
+        None
+    }
 }
 
+// metadata of the rings saved before zones and weights, see `Ring::deserialize_legacy`
+impl Metadata for SocketAddr {}
+
+// serialized rings start with this, older rings start with the vnode count instead
+const RING_FORMAT_V2: u64 = 0x5355_4352_4552_0002;
+
+#[cfg(test)]
+impl Metadata for () {}
+
 // *pseudo* interval used to calculate aae msg rate
 const DHT_AAE_INTERVAL_MS: u64 = 1_000;
 // interval for active anti entropy checks
@@ -59,10 +81,14 @@ struct Inner<T: Metadata> {
     next_req_broadcast: Instant,
     sync_on_connect: bool,
     sync_aae: bool,
+    // latest rebalance plan: id, ring version it was based on and the planned ring
+    rebalance_plan: Option<(u64, VersionVector, Ring<T>)>,
+    // nodes considered dead by the failure detector
+    dead_nodes: IdHashSet<NodeId>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
-enum NodeStatus {
+pub enum NodeStatus {
     // Valid node, as one would expect
     Valid,
     // Like valid but shouldn't be considered to own anything by rebalance
@@ -74,14 +100,13 @@ enum NodeStatus {
 use self::NodeStatus::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
-enum VNodeNodeStatus {
+pub enum VNodeNodeStatus {
     // Takes read/write traffic
     Owner,
     // Takes write traffic while it bootstraps from an Owner
     Pending,
-    // Like Owner, but will go away once there are no more pending
-    // FIXME: once a retiring vnode goes away (vnode goes zombie for a grace period)
-    // other nodes will not sync from it (as sync is a pull)
+    // Like Owner, but will go away once there are no more pending.
+    // Once it goes away the vnode goes zombie and pushes its data to the owners.
     Retiring,
 }
 
@@ -115,6 +140,37 @@ struct Ring<T: Metadata> {
     cluster: String,
 }
 
+// a / b, compared without losing precision
+#[derive(Debug, Clone, Copy)]
+struct Ratio(u64, u64);
+
+impl PartialEq for Ratio {
+    fn eq(&self, other: &Ratio) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ratio {}
+
+impl PartialOrd for Ratio {
+    fn partial_cmp(&self, other: &Ratio) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ratio {
+    fn cmp(&self, other: &Ratio) -> Ordering {
+        (self.0 * other.1).cmp(&(other.0 * self.1))
+    }
+}
+
+/// A vnode that changes owners in a rebalance plan
+pub struct RebalanceMove {
+    pub vnode: VNodeNo,
+    pub gained: Vec<NodeId>,
+    pub lost: Vec<NodeId>,
+}
+
 pub struct RingDescription {
     pub replication_factor: u8,
     pub partitions: u16,
@@ -131,11 +187,44 @@ impl RingDescription {
 
 impl<T: Metadata> Ring<T> {
     fn serialize(ring: &Ring<T>) -> Result<Vec<u8>, GenericError> {
-        bincode::serialize(ring).map_err(|e| format!("Can't serialize Ring: {:?}", e).into())
+        bincode::serialize(&(RING_FORMAT_V2, ring))
+            .map_err(|e| format!("Can't serialize Ring: {:?}", e).into())
     }
 
     fn deserialize(bytes: &[u8]) -> Result<Ring<T>, GenericError> {
-        bincode::deserialize(bytes).map_err(|e| format!("Can't deserialize Ring: {:?}", e).into())
+        if bincode::deserialize::<u64>(bytes).ok() != Some(RING_FORMAT_V2) {
+            return Self::deserialize_legacy(bytes);
+        }
+        bincode::deserialize::<(u64, Ring<T>)>(bytes)
+            .map(|(_, ring)| ring)
+            .map_err(|e| format!("Can't deserialize Ring: {:?}", e).into())
+    }
+
+    // rings saved (or sent) before zones and weights, with addresses as metadata
+    fn deserialize_legacy(bytes: &[u8]) -> Result<Ring<T>, GenericError> {
+        let legacy: Ring<SocketAddr> = bincode::deserialize(bytes)
+            .map_err(|e| format!("Can't deserialize Ring: {:?}", e))?;
+        let mut nodes = IdHashMap::default();
+        for (id, node) in legacy.nodes {
+            let meta = T::from_addr(node.meta)
+                .ok_or("Can't upgrade Ring from an older version, the cluster must be rebuilt")?;
+            nodes.insert(
+                id,
+                Node {
+                    addr: node.addr,
+                    status: node.status,
+                    meta: meta,
+                    version: node.version,
+                },
+            );
+        }
+        Ok(Ring {
+            vnodes: legacy.vnodes,
+            nodes: nodes,
+            replication_factor: legacy.replication_factor,
+            version: legacy.version,
+            cluster: legacy.cluster,
+        })
     }
 
     fn new(cluster: &str, partitions: u16, replication_factor: u8) -> Self {
@@ -152,6 +241,65 @@ impl<T: Metadata> Ring<T> {
         self.nodes.values().filter(|n| n.status == Valid).count()
     }
 
+    fn zone(&self, node: NodeId) -> &str {
+        self.nodes.get(&node).map_or("", |n| n.meta.zone())
+    }
+
+    fn weight(&self, node: NodeId) -> u64 {
+        self.nodes
+            .get(&node)
+            .map_or(1, |n| max(1, n.meta.weight()) as u64)
+    }
+
+    // Number of vnodes (rounded up) each of `nodes` should serve out of `total`,
+    // in proportion to their weight but at most one replica of each vnode.
+    fn weighted_targets(&self, nodes: &[NodeId], total: usize) -> Vec<usize> {
+        let cap = self.vnodes.len() as u64;
+        let mut capped = vec![false; nodes.len()];
+        loop {
+            let weight: u64 = nodes
+                .iter()
+                .zip(&capped)
+                .filter(|&(_, &c)| !c)
+                .map(|(&n, _)| self.weight(n))
+                .sum();
+            let remaining = total as u64 - cap * capped.iter().filter(|&&c| c).count() as u64;
+            let mut changed = false;
+            for (&n, c) in nodes.iter().zip(&mut capped) {
+                if !*c && remaining * self.weight(n) > cap * weight {
+                    *c = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return nodes
+                    .iter()
+                    .zip(&capped)
+                    .map(|(&n, &c)| {
+                        if c || weight == 0 {
+                            cap as usize
+                        } else {
+                            ((remaining * self.weight(n) + weight - 1) / weight) as usize
+                        }
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    fn distinct_zones(&self, nodes: &[NodeId]) -> usize {
+        let mut zones: Vec<_> = nodes.iter().map(|&n| self.zone(n)).collect();
+        zones.sort();
+        zones.dedup();
+        zones.len()
+    }
+
+    // whether another node in `nodes` is in the same zone as `node`
+    fn shares_zone(&self, nodes: &[NodeId], node: NodeId) -> bool {
+        let zone = self.zone(node);
+        nodes.iter().any(|&n| n != node && self.zone(n) == zone)
+    }
+
     fn valid_physical_count(&self, physical: PhysicalNodeId) -> usize {
         self.nodes
             .iter()
@@ -214,6 +362,61 @@ impl<T: Metadata> Ring<T> {
         Ok(())
     }
 
+    fn set_replication_factor(
+        &mut self,
+        this: NodeId,
+        replication_factor: usize,
+    ) -> Result<(), GenericError> {
+        if replication_factor < 1 || replication_factor > 6 {
+            return Err("Replication factor must be between 1 and 6".into());
+        }
+        if replication_factor != self.replication_factor {
+            self.replication_factor = replication_factor;
+            self.version.event(this);
+        }
+        Ok(())
+    }
+
+    // each vnode becomes two consecutive vnodes with the same owners,
+    // so vnode `n` slots are split between vnodes `2n` and `2n + 1`
+    fn split_vnodes(&mut self) {
+        let mut vnodes = Vec::with_capacity(self.vnodes.len() * 2);
+        for vn in self.vnodes.drain(..) {
+            vnodes.push(vn.clone());
+            vnodes.push(vn);
+        }
+        self.vnodes = vnodes;
+    }
+
+    fn split(&mut self, this: NodeId) -> Result<(), GenericError> {
+        if self.vnodes.len() * 2 > HASH_SLOTS as usize {
+            return Err("Partition count can't exceed the number of hash slots".into());
+        }
+        self.split_vnodes();
+        self.version.event(this);
+        Ok(())
+    }
+
+    fn update_node_meta<F: FnOnce(&mut T)>(
+        &mut self,
+        this: NodeId,
+        node: NodeId,
+        update: F,
+    ) -> Result<(), GenericError> {
+        let node = match self.nodes.get_mut(&node) {
+            Some(node) if node.status != Invalid => node,
+            _ => return Err(format!("{} is not in the cluster", node).into()),
+        };
+        let mut meta = node.meta.clone();
+        update(&mut meta);
+        if meta != node.meta {
+            node.meta = meta;
+            node.version.event(this);
+            self.version.event(this);
+        }
+        Ok(())
+    }
+
     fn remove_node(&mut self, this: NodeId, removed: NodeId) -> Result<(), GenericError> {
         self.version.event(this);
         if let Some(node) = self.nodes.get_mut(&removed) {
@@ -305,6 +508,38 @@ impl<T: Metadata> Ring<T> {
         Ok(())
     }
 
+    // Moves the ownership of `old` to `new`, `new` bootstraps from the remaining owners
+    // and `old` leaves once that's done.
+    fn swap_node(&mut self, this: NodeId, old: NodeId, new: NodeId) -> Result<(), GenericError> {
+        if old == new {
+            return Err("Can't swap a node with itself".into());
+        }
+        if !self.nodes.contains_key(&old) {
+            return Err(format!("{} is not in the cluster", old).into());
+        }
+        match self.nodes.get(&new) {
+            Some(node) if node.status == Valid => (),
+            _ => return Err(format!("{} is not a valid node", new).into()),
+        }
+        if self.vnodes.iter().any(|vn| vn.owners.contains_key(&new)) {
+            return Err(format!("{} already owns vnodes", new).into());
+        }
+        self.version.event(this);
+        let owned: Vec<_> = self.vnodes
+            .iter()
+            .enumerate()
+            .filter(|&(_, vn)| vn.owners.get(&old).map_or(false, |&s| s != Retiring))
+            .map(|(i, _)| i)
+            .collect();
+        self.leave_node(this, old)?;
+        for i in owned {
+            let vn = &mut self.vnodes[i];
+            vn.owners.insert(new, Pending);
+            vn.version.event(this);
+        }
+        self.is_valid()
+    }
+
     fn merge(&mut self, mut other: Self) -> Result<bool, GenericError> {
         debug!("Merging rings {:?} {:?}", self.version, other.version);
         // sanity checks
@@ -314,16 +549,24 @@ impl<T: Metadata> Ring<T> {
                 self.cluster, other.cluster
             ).into());
         }
-        if self.vnodes.len() != other.vnodes.len() && !self.vnodes.is_empty() {
-            return Err(format!(
-                "Incompatible partition count {:?} != {:?}",
-                self.vnodes.len(),
-                other.vnodes.len()
-            ).into());
-        }
         if other.vnodes.is_empty() {
             return Err("Other ring isn't valid".into());
         }
+        if !self.vnodes.is_empty() {
+            // one of the rings may have been split since they diverged
+            let (fewer, more) = if self.vnodes.len() < other.vnodes.len() {
+                (self.vnodes.len(), other.vnodes.len())
+            } else {
+                (other.vnodes.len(), self.vnodes.len())
+            };
+            if more % fewer != 0 || !(more / fewer).is_power_of_two() {
+                return Err(format!(
+                    "Incompatible partition count {:?} != {:?}",
+                    self.vnodes.len(),
+                    other.vnodes.len()
+                ).into());
+            }
+        }
 
         if self.version.descends(&other.version) {
             debug!("Accepting this ring {:?} {:?}", self.version, other.version);
@@ -342,7 +585,17 @@ impl<T: Metadata> Ring<T> {
             "Merging diverging ring versions {:?} {:?}",
             self.version, other.version
         );
+        if !self.vnodes.is_empty() {
+            while self.vnodes.len() < other.vnodes.len() {
+                self.split_vnodes();
+            }
+            while other.vnodes.len() < self.vnodes.len() {
+                other.split_vnodes();
+            }
+        }
         self.version.merge(&other.version);
+        // concurrent replication factor changes, the highest wins
+        self.replication_factor = max(self.replication_factor, other.replication_factor);
 
         // merge nodes
         for (n, other_node) in other.nodes.drain() {
@@ -426,118 +679,184 @@ impl<T: Metadata> Ring<T> {
         Ok(true)
     }
 
-    fn rebalance(&mut self, this: NodeId) -> Result<(), GenericError> {
-        self.version.event(this);
-        // special case when #nodes <= replication factor
-        if self.valid_nodes_count() <= self.replication_factor {
-            // special case 1 node case
-            let status = if self.nodes.len() == 1 {
-                Owner
-            } else {
-                Pending
-            };
-            for vn in &mut self.vnodes {
-                for (&node_id, node) in &self.nodes {
-                    if node.status != Valid {
-                        continue;
-                    }
-                    match vn.owners.entry(node_id) {
-                        LMEntry::Vacant(v) => {
-                            v.insert(status);
-                        }
-                        LMEntry::Occupied(mut o) => {
-                            if *o.get() != Retiring {
-                                continue;
-                            }
-                            *o.get_mut() = status;
-                        }
-                    }
-                    vn.version.event(this);
-                }
-            }
-            return Ok(());
+    // Calculates the nodes that should serve each vnode, changing as few replicas as possible:
+    // 1. keep the current replicas that are still valid (dropping any extra
+    //    and those preventing the replicas from spanning enough zones)
+    // 2. assign the missing replicas to the least loaded nodes, from unused zones first
+    // 3. move replicas from the most to the least loaded nodes while that lowers
+    //    the sum of load^2/weight, as long as zones allow it
+    // So nodes end up with a load proportional to their weight (with equal weights
+    // they're at most one vnode apart).
+    // Nodes retiring from a vnode are preferred as they still hold the data.
+    fn rebalance_assignment(&self) -> Result<Vec<Vec<NodeId>>, GenericError> {
+        // at most one replica per physical node
+        fn eligible(replicas: &[NodeId], node: NodeId) -> bool {
+            !replicas.iter().any(|&n| split_u64(n).0 == split_u64(node).0)
         }
 
-        // simple 2-steps eager rebalancing
-        // 1. assing replicas for under replicated vnodes
-        // 2. take from who is doing too much work
+        // replacing `from` by `to` doesn't reduce the number of zones
+        let keeps_zones = |replicas: &[NodeId], from: NodeId, to: NodeId| {
+            self.zone(from) == self.zone(to) || !self.shares_zone(replicas, to)
+                || self.shares_zone(replicas, from)
+        };
 
-        // map of active nodes to vnodes
-        let mut node_map = IdHashMap::default();
-        for (&node_id, node) in &self.nodes {
-            if node.status == Valid {
-                node_map.insert(node_id, IdHashSet::default());
+        let mut valid: Vec<NodeId> = self.nodes
+            .iter()
+            .filter(|&(_, n)| n.status == Valid)
+            .map(|(&i, _)| i)
+            .collect();
+        if valid.is_empty() {
+            return Err("Can't rebalance without valid nodes".into());
+        }
+        // sorted so ties are broken deterministically
+        valid.sort();
+        let replicas = min(valid.len(), self.replication_factor);
+        // number of zones each vnode should span
+        let spread = min(replicas, self.distinct_zones(&valid));
+        let retiring = |vn_no: usize, node: NodeId| {
+            self.vnodes[vn_no].owners.get(&node) == Some(&Retiring)
+        };
+        // change in load^2/weight (times 2) of adding/removing a replica to/from the node
+        let adding = |load: usize, node: NodeId| Ratio(2 * load as u64 + 1, self.weight(node));
+        let removing = |load: usize, node: NodeId| {
+            Ratio((2 * load as u64).saturating_sub(1), self.weight(node))
+        };
+
+        let mut load: IdHashMap<NodeId, usize> = valid.iter().map(|&n| (n, 0)).collect();
+        let mut assignment: Vec<Vec<NodeId>> = self.vnodes
+            .iter()
+            .map(|vn| {
+                vn.owners
+                    .iter()
+                    .filter(|&(n, &s)| s != Retiring && load.contains_key(n))
+                    .map(|(&n, _)| n)
+                    .collect()
+            })
+            .collect();
+        for vn_replicas in &assignment {
+            for n in vn_replicas {
+                *load.get_mut(n).unwrap() += 1;
             }
         }
-        for (vn_no, vn) in self.vnodes.iter_mut().enumerate() {
-            // update all vn versions
-            vn.version.event(this);
-            for (node, status) in &vn.owners {
-                if *status != Retiring {
-                    node_map.get_mut(node).unwrap().insert(vn_no);
-                }
+
+        // 1. drop extra replicas from the most loaded nodes, zone duplicates first
+        for vn_replicas in &mut assignment {
+            while vn_replicas.len() > replicas
+                || vn_replicas.len() - self.distinct_zones(&vn_replicas) > replicas - spread
+            {
+                let i = vn_replicas
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(_, &n)| {
+                        (
+                            self.shares_zone(&vn_replicas, n),
+                            removing(load[&n], n),
+                            n,
+                        )
+                    })
+                    .map(|(i, _)| i)
+                    .unwrap();
+                *load.get_mut(&vn_replicas.swap_remove(i)).unwrap() -= 1;
             }
         }
 
-        // partitions per node
-        let vnpn = ((self.vnodes.len() * self.replication_factor) as f64 / node_map.len() as f64)
-            .ceil() as usize;
-
-        // 1. complete replicas
-        for (vn_no, vn) in self.vnodes.iter_mut().enumerate() {
-            let replicas = vn.owners.values().filter(|&&s| s != Retiring).count();
-            for _ in replicas..self.replication_factor {
-                // try to find a candidate that is doing less work
-                if let Some((&node, vns)) = node_map
-                    .iter_mut()
-                    .filter(|&(n, _)| !vn.owners.contains_key(n))
-                    .min_by_key(|&(_, ref p)| p.len())
-                {
-                    assert!(vns.insert(vn_no));
-                    assert!(vn.owners.insert(node, Pending).is_none());
-                    continue;
-                }
-                // try to find candidate that was retiring from that vnode
-                // only consider nodes in node_map (others could be leaving/invalid)
-                if let Some((_, node)) = vn.owners
+        // 2. complete replicas
+        for (vn_no, vn_replicas) in assignment.iter_mut().enumerate() {
+            while vn_replicas.len() < replicas {
+                let node = valid
                     .iter()
-                    .filter_map(|(&n, &s)| {
-                        if s == Retiring {
-                            node_map.get(&n).map(|p| (p.len(), n))
-                        } else {
-                            None
-                        }
+                    .cloned()
+                    .filter(|&n| eligible(&vn_replicas, n))
+                    .min_by_key(|&n| {
+                        (
+                            self.shares_zone(&vn_replicas, n),
+                            adding(load[&n], n),
+                            !retiring(vn_no, n),
+                            n,
+                        )
                     })
-                    .min()
-                {
-                    assert!(node_map.get_mut(&node).unwrap().insert(vn_no));
-                    assert!(vn.owners.insert(node, Owner).is_some());
-                    continue;
+                    .ok_or_else(|| format!("Can't find replica for vnode {}", vn_no))?;
+                vn_replicas.push(node);
+                *load.get_mut(&node).unwrap() += 1;
+            }
+        }
+
+        // 3. take from who is doing too much work
+        loop {
+            let mut donors = valid.clone();
+            donors.sort_by_key(|&n| (removing(load[&n], n), n));
+            let mut receivers = valid.clone();
+            receivers.sort_by_key(|&n| (adding(load[&n], n), n));
+            let mut found = None;
+            'search: for &from in donors.iter().rev() {
+                for &to in &receivers {
+                    if adding(load[&to], to) >= removing(load[&from], from) {
+                        break;
+                    }
+                    if let Some(vn_no) = (0..assignment.len())
+                        .filter(|&i| {
+                            let vn_replicas = &assignment[i][..];
+                            vn_replicas.contains(&from) && eligible(vn_replicas, to)
+                                && keeps_zones(vn_replicas, from, to)
+                        })
+                        .min_by_key(|&i| (!retiring(i, to), i))
+                    {
+                        found = Some((from, to, vn_no));
+                        break 'search;
+                    }
                 }
-                unreachable!(
-                    "Can't find replica for vnode {} {:?} rf:{}",
-                    vn_no, vn.owners, self.replication_factor
-                );
+            }
+            if let Some((from, to, vn_no)) = found {
+                for n in &mut assignment[vn_no] {
+                    if *n == from {
+                        *n = to;
+                    }
+                }
+                *load.get_mut(&from).unwrap() -= 1;
+                *load.get_mut(&to).unwrap() += 1;
+            } else {
+                break;
             }
         }
 
-        // 2. robin-hood
-        for (vn_no, vn) in self.vnodes.iter_mut().enumerate() {
-            let doing_much: IdHashSet<_> = vn.owners
-                .iter()
-                .filter(|&(n, &s)| s != Retiring && node_map.get(n).unwrap().len() > vnpn)
-                .map(|(n, _)| *n)
-                .collect();
-            let candidates: IdHashSet<_> = node_map
-                .iter()
-                .filter(|&(n, vns)| vns.len() < vnpn && !vn.owners.contains_key(n))
-                .map(|(n, _)| *n)
-                .collect();
-            for (from, to) in doing_much.into_iter().zip(candidates) {
-                assert!(vn.owners.insert(to, Pending).is_none());
-                assert!(vn.owners.insert(from, Retiring).is_some());
-                assert!(node_map.get_mut(&from).unwrap().remove(&vn_no));
-                assert!(node_map.get_mut(&to).unwrap().insert(vn_no));
+        Ok(assignment)
+    }
+
+    fn rebalance(&mut self, this: NodeId) -> Result<(), GenericError> {
+        let assignment = self.rebalance_assignment()?;
+        self.version.event(this);
+        for (vn, vn_replicas) in self.vnodes.iter_mut().zip(assignment) {
+            let previous = vn.owners.clone();
+            // without a node holding the data there's nothing to bootstrap from
+            let status = if vn.owners.values().any(|&s| s != Pending) {
+                Pending
+            } else {
+                Owner
+            };
+            // pending nodes that lost the vnode never got the data, just drop them
+            vn.owners.retain(|n, s| *s != Pending || vn_replicas.contains(n));
+            for (n, s) in vn.owners.iter_mut() {
+                if *s == Owner && !vn_replicas.contains(n) {
+                    *s = Retiring;
+                }
+            }
+            for &n in &vn_replicas {
+                match vn.owners.entry(n) {
+                    LMEntry::Vacant(v) => {
+                        v.insert(status);
+                    }
+                    LMEntry::Occupied(mut o) => {
+                        if *o.get() == Retiring {
+                            *o.get_mut() = Owner;
+                        }
+                    }
+                }
+            }
+            if !vn.owners.values().any(|&s| s == Pending) {
+                vn.owners.retain(|_, s| *s != Retiring);
+            }
+            if vn.owners != previous {
+                vn.version.event(this);
             }
         }
 
@@ -566,11 +885,15 @@ impl<T: Metadata> Ring<T> {
     fn is_valid(&self) -> Result<(), GenericError> {
         let valid_nodes_count = self.valid_nodes_count();
         let desired_replicas = min(valid_nodes_count, self.replication_factor);
-        let vnpn = ((self.vnodes.len() * self.replication_factor) as f64 / valid_nodes_count as f64)
-            .ceil() as usize;
-        let vnpn_rest = self.vnodes.len() * self.replication_factor % valid_nodes_count;
         let mut node_map = IdHashMap::default();
         node_map.reserve(valid_nodes_count);
+        let valid_nodes: Vec<_> = self.nodes
+            .iter()
+            .filter(|&(_, n)| n.status == Valid)
+            .map(|(&i, _)| i)
+            .collect();
+        let zones = self.distinct_zones(&valid_nodes);
+        let spread = min(desired_replicas, zones);
 
         for (vn_no, vn) in self.vnodes.iter().enumerate() {
             let owners: Vec<_> = vn.owners
@@ -594,20 +917,44 @@ impl<T: Metadata> Ring<T> {
                     vn_no, owners, pending
                 ).into());
             }
-            for &node in owners.iter().chain(&pending) {
+            let replicas = [&owners[..], &pending[..]].concat();
+            if self.distinct_zones(&replicas) < spread {
+                return Err(format!(
+                    "vnode {} replicas {:?} span less than {} zones",
+                    vn_no, replicas, spread
+                ).into());
+            }
+            for &node in &replicas {
                 *node_map.entry(node).or_insert(0usize) += 1;
             }
         }
 
-        for (&n, &count) in &node_map {
-            if count > vnpn + vnpn_rest {
-                return Err(format!(
-                    "node {} is a replica for {} vnodes, expected {} max, {:?}",
-                    n,
-                    count,
-                    vnpn + vnpn_rest,
-                    node_map
-                ).into());
+        // nodes should serve vnodes in proportion to their weight, but that's only
+        // expected within each zone, as zones may be too small or too big to get their share
+        let mut zones_nodes: HashMap<&str, Vec<NodeId>> = HashMap::new();
+        for &n in &valid_nodes {
+            zones_nodes
+                .entry(self.zone(n))
+                .or_insert_with(Vec::new)
+                .push(n);
+        }
+        for (zone, nodes) in zones_nodes {
+            let total: usize = nodes
+                .iter()
+                .map(|n| node_map.get(n).cloned().unwrap_or(0))
+                .sum();
+            for (n, target) in nodes.iter().zip(self.weighted_targets(&nodes, total)) {
+                let count = node_map.get(n).cloned().unwrap_or(0);
+                if count > target + 1 {
+                    return Err(format!(
+                        "node {} is a replica for {} vnodes, expected {} max in zone {:?}, {:?}",
+                        n,
+                        count,
+                        target + 1,
+                        zone,
+                        node_map
+                    ).into());
+                }
             }
         }
 
@@ -626,10 +973,6 @@ impl<T: Metadata> DHT<T> {
         let addr = fabric.addr();
         let partitions = ring.partitions;
         let replication_factor = ring.replication_factor;
-        assert!(
-            partitions.is_power_of_two(),
-            "Partition count must be a power of 2"
-        );
         assert!(partitions >= 32, "Partition count must be >= 32");
         assert!(partitions <= 1024, "Partition count must be <= 1024");
         assert!(replication_factor >= 1, "Replication factor must be >= 1");
@@ -726,6 +1069,8 @@ impl<T: Metadata> DHT<T> {
             next_req_broadcast: Instant::now(),
             sync_aae: config.dht_sync_aae,
             sync_on_connect: config.dht_sync_on_connect,
+            rebalance_plan: None,
+            dead_nodes: Default::default(),
         }));
 
         // TODO: move this to Database
@@ -817,7 +1162,10 @@ impl<T: Metadata> DHT<T> {
 
         let mut rng = thread_rng();
         for (&node_id, node) in inner.ring.nodes.iter() {
-            if node_id == inner.node || node.status != NodeStatus::Valid {
+            if node_id == inner.node
+                || node.status != NodeStatus::Valid
+                || inner.dead_nodes.contains(&node_id)
+            {
                 continue;
             }
             if rng.next_f32() < chance {
@@ -851,6 +1199,31 @@ impl<T: Metadata> DHT<T> {
         self.node
     }
 
+    /// Updates the liveness of a node, as reported by the failure detector.
+    /// Returns whether the liveness changed.
+    pub fn set_node_alive(&self, node: NodeId, alive: bool) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        if !alive {
+            return inner.dead_nodes.insert(node);
+        }
+        if !inner.dead_nodes.remove(&node) {
+            return false;
+        }
+        // the node may have missed ring changes while it was unreachable
+        if !inner.ring.vnodes.is_empty() {
+            let serialized_ring: Bytes = Ring::serialize(&inner.ring).unwrap().into();
+            let _ = inner
+                .fabric
+                .send_msg(node, &FabricMsg::DHTSync(serialized_ring));
+        }
+        true
+    }
+
+    /// Whether the node isn't known to be dead
+    pub fn is_node_alive(&self, node: NodeId) -> bool {
+        !self.inner.read().unwrap().dead_nodes.contains(&node)
+    }
+
     pub fn partitions(&self) -> usize {
         // FIXME: should not lock
         self.inner.read().unwrap().ring.vnodes.len()
@@ -860,9 +1233,21 @@ impl<T: Metadata> DHT<T> {
         self.inner.read().unwrap().ring.replication_factor
     }
 
+    pub fn ring_version(&self) -> VersionVector {
+        self.inner.read().unwrap().ring.version.clone()
+    }
+
     pub fn key_vnode(&self, key: &[u8]) -> VNodeNo {
-        // use / instead of % to get continuous hash slots for each vnode
-        (hash_slot(key) / (HASH_SLOTS / self.partitions() as VNodeNo)) as VNodeNo
+        self.slot_vnode(hash_slot(key))
+    }
+
+    pub fn slot_vnode(&self, slot: u16) -> VNodeNo {
+        hash::slot_vnode(slot, self.partitions())
+    }
+
+    /// First and last hash slot of the vnode
+    pub fn vnode_slots(&self, vn_no: VNodeNo) -> (u16, u16) {
+        hash::vnode_slots(vn_no, self.partitions())
     }
 
     pub fn vnodes_for_node(&self, node: NodeId) -> (Vec<VNodeNo>, Vec<VNodeNo>) {
@@ -935,6 +1320,14 @@ impl<T: Metadata> DHT<T> {
         Ring::serialize(&self.inner.read().unwrap().ring).expect("Can't serialize ring")
     }
 
+    /// Metadata of a node in a ring saved with `save_ring`
+    pub fn saved_node_meta(serialized_ring: &[u8], node: NodeId) -> Option<T> {
+        Ring::<T>::deserialize(serialized_ring)
+            .ok()
+            .and_then(|mut ring| ring.nodes.remove(&node))
+            .map(|n| n.meta)
+    }
+
     pub fn members(&self) -> IdHashMap<NodeId, SocketAddr> {
         let inner = self.inner.read().unwrap();
         inner
@@ -946,10 +1339,35 @@ impl<T: Metadata> DHT<T> {
             .collect()
     }
 
+    /// All nodes (except removed ones) with their status
+    pub fn nodes(&self) -> Vec<(NodeId, NodeStatus, (SocketAddr, T))> {
+        let inner = self.inner.read().unwrap();
+        let mut result: Vec<_> = inner
+            .ring
+            .nodes
+            .iter()
+            .filter(|&(_, n)| n.status != Invalid)
+            .map(|(&id, n)| (id, n.status, (n.addr, n.meta.clone())))
+            .collect();
+        result.sort_by_key(|&(id, _, _)| id);
+        result
+    }
+
+    /// Owners of each vnode, including Pending and Retiring ones
+    pub fn vnodes_owners(&self) -> Vec<Vec<(NodeId, VNodeNodeStatus)>> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .ring
+            .vnodes
+            .iter()
+            .map(|vn| vn.owners.iter().map(|(&n, &s)| (n, s)).collect())
+            .collect()
+    }
+
     pub fn slots(&self) -> BTreeMap<(u16, u16), Vec<(NodeId, (SocketAddr, T))>> {
-        let slots_per_partition = HASH_SLOTS / self.partitions() as u16;
         let mut result = BTreeMap::new();
         let inner = self.inner.read().unwrap();
+        let partitions = inner.ring.vnodes.len();
         for (vn_no, vn) in inner.ring.vnodes.iter().enumerate() {
             let mut members = Vec::with_capacity(vn.owners.len());
             for (&node_id, &status) in &vn.owners {
@@ -959,14 +1377,7 @@ impl<T: Metadata> DHT<T> {
                 let node = inner.ring.nodes.get(&node_id).unwrap();
                 members.push((node_id, (node.addr, node.meta.clone())));
             }
-            let vn_no = vn_no as u16;
-            result.insert(
-                (
-                    vn_no * slots_per_partition,
-                    (vn_no + 1) * slots_per_partition - 1,
-                ),
-                members,
-            );
+            result.insert(hash::vnode_slots(vn_no as VNodeNo, partitions), members);
         }
         result
     }
@@ -979,6 +1390,90 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
+    /// Computes a rebalance without applying it, returning the plan id, the vnodes changing
+    /// owners and the number of vnodes each valid node would serve. The plan can be applied
+    /// with `apply_rebalance_plan` as long as the ring doesn't change in between.
+    pub fn plan_rebalance(
+        &self,
+    ) -> Result<(u64, Vec<RebalanceMove>, Vec<(NodeId, usize)>), GenericError> {
+        fn serving(vn: &VNode) -> Vec<NodeId> {
+            vn.owners
+                .iter()
+                .filter(|&(_, &s)| s != Retiring)
+                .map(|(&n, _)| n)
+                .collect()
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        let mut planned = inner.ring.clone();
+        planned.rebalance(self.node)?;
+        let mut moves = Vec::new();
+        for (vn_no, (before, after)) in inner
+            .ring
+            .vnodes
+            .iter()
+            .zip(&planned.vnodes)
+            .enumerate()
+        {
+            let (before, after) = (serving(before), serving(after));
+            let gained: Vec<_> = after
+                .iter()
+                .filter(|n| !before.contains(n))
+                .cloned()
+                .collect();
+            let lost: Vec<_> = before
+                .iter()
+                .filter(|n| !after.contains(n))
+                .cloned()
+                .collect();
+            if !gained.is_empty() || !lost.is_empty() {
+                moves.push(RebalanceMove {
+                    vnode: vn_no as VNodeNo,
+                    gained: gained,
+                    lost: lost,
+                });
+            }
+        }
+        let mut loads: Vec<_> = planned
+            .nodes
+            .iter()
+            .filter(|&(_, n)| n.status == Valid)
+            .map(|(&node, _)| {
+                let vnodes = planned
+                    .vnodes
+                    .iter()
+                    .filter(|vn| serving(vn).contains(&node))
+                    .count();
+                (node, vnodes)
+            })
+            .collect();
+        loads.sort();
+        let id = thread_rng().gen::<u32>() as u64;
+        let version = inner.ring.version.clone();
+        inner.rebalance_plan = Some((id, version, planned));
+        Ok((id, moves, loads))
+    }
+
+    pub fn apply_rebalance_plan(&self, id: u64) -> Result<(), GenericError> {
+        info!("Applying rebalance plan {}", id);
+        let (version, planned) = {
+            let mut inner = self.inner.write().unwrap();
+            match inner.rebalance_plan.take() {
+                Some((plan_id, version, planned)) if plan_id == id => (version, planned),
+                plan => {
+                    inner.rebalance_plan = plan;
+                    return Err(format!("Unknown rebalance plan {}", id).into());
+                }
+            }
+        };
+        self.propose(|ring| {
+            if ring.version != version {
+                return Err("Ring changed since the plan was made".into());
+            }
+            Ok(planned)
+        })
+    }
+
     #[cfg(test)]
     pub fn finish_rebalance(&self) -> Result<(), GenericError> {
         info!("Finish Rebalancing ring");
@@ -1018,6 +1513,38 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
+    /// Changes the replication factor and rebalances, new replicas are added as Pending
+    /// and extra replicas become Retiring.
+    pub fn set_replication_factor(&self, replication_factor: usize) -> Result<(), GenericError> {
+        info!("Setting replication factor to {}", replication_factor);
+        self.propose(|mut ring| {
+            ring.set_replication_factor(self.node, replication_factor)?;
+            ring.rebalance(self.node)?;
+            Ok(ring)
+        })
+    }
+
+    /// Doubles the partition count, each vnode is split in two halves with the same owners
+    pub fn split_partitions(&self) -> Result<(), GenericError> {
+        info!("Splitting partitions");
+        self.propose(|mut ring| {
+            ring.split(self.node)?;
+            Ok(ring)
+        })
+    }
+
+    /// Changes the metadata of a node, it's up to the caller to rebalance
+    pub fn update_node_meta<F: FnOnce(&mut T)>(
+        &self,
+        node: NodeId,
+        update: F,
+    ) -> Result<(), GenericError> {
+        self.propose(|mut ring| {
+            ring.update_node_meta(self.node, node, update)?;
+            Ok(ring)
+        })
+    }
+
     pub fn leave_node(&self, node: NodeId) -> Result<(), GenericError> {
         info!("Leaving node {}", node);
         self.propose(|mut ring| {
@@ -1026,6 +1553,44 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
+    /// Marks the node as leaving and rebalances its vnodes to the other nodes
+    pub fn decommission_node(&self, node: NodeId) -> Result<(), GenericError> {
+        info!("Decommissioning node {}", node);
+        self.propose(|mut ring| {
+            if !ring.nodes.contains_key(&node) {
+                return Err(format!("{} is not in the cluster", node).into());
+            }
+            ring.leave_node(self.node, node)?;
+            ring.rebalance(self.node)?;
+            Ok(ring)
+        })
+    }
+
+    /// Removes the node (eg. a dead node) and rebalances its vnodes to the other nodes
+    pub fn forget_node(&self, node: NodeId) -> Result<(), GenericError> {
+        info!("Forgetting node {}", node);
+        if node == self.node {
+            return Err("Can't forget this node".into());
+        }
+        self.propose(|mut ring| {
+            if !ring.nodes.contains_key(&node) {
+                return Err(format!("{} is not in the cluster", node).into());
+            }
+            ring.remove_node(self.node, node)?;
+            ring.rebalance(self.node)?;
+            Ok(ring)
+        })
+    }
+
+    /// Moves the ownership of `old` to `new`, which must be a member that owns nothing
+    pub fn swap_node(&self, old: NodeId, new: NodeId) -> Result<(), GenericError> {
+        info!("Swapping node {} with {}", old, new);
+        self.propose(|mut ring| {
+            ring.swap_node(self.node, old, new)?;
+            Ok(ring)
+        })
+    }
+
     pub fn promote_pending_node(&self, node: NodeId, vnode: VNodeNo) -> Result<(), GenericError> {
         info!("Promoting pending node {} vnode {}", node, vnode);
         self.propose(|mut ring| {
@@ -1058,6 +1623,24 @@ mod tests {
     use utils::join_u64;
     use utils::sleep_ms;
 
+    // the metadata is the zone name
+    impl Metadata for String {
+        fn zone(&self) -> &str {
+            self
+        }
+
+        fn from_addr(addr: SocketAddr) -> Option<Self> {
+            Some(addr.to_string())
+        }
+    }
+
+    // the metadata is the node weight
+    impl Metadata for u32 {
+        fn weight(&self) -> u32 {
+            *self
+        }
+    }
+
     #[test]
     fn test_ring_dup_join() {
         let mut ring = Ring::new("", 64, 3);
@@ -1095,6 +1678,67 @@ mod tests {
             .unwrap_err();
     }
 
+    #[test]
+    fn test_ring_swap() {
+        let mut ring = Ring::new("", 64, 2);
+        let addr = "127.0.0.1:1999".parse().unwrap();
+        for i in 0..3 {
+            ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        ring.join_node(0, join_u64(3, 0), addr, ()).unwrap();
+
+        let old_vnodes: Vec<_> = ring.vnodes
+            .iter()
+            .map(|vn| vn.owners.contains_key(&join_u64(0, 0)))
+            .collect();
+        ring.clone()
+            .swap_node(0, join_u64(0, 0), join_u64(1, 0))
+            .unwrap_err();
+        ring.clone()
+            .swap_node(0, join_u64(0, 0), join_u64(9, 0))
+            .unwrap_err();
+        ring.swap_node(0, join_u64(0, 0), join_u64(3, 0)).unwrap();
+        for (vn, &owned) in ring.vnodes.iter().zip(&old_vnodes) {
+            if owned {
+                assert_eq!(vn.owners.get(&join_u64(0, 0)), Some(&Retiring));
+                assert_eq!(vn.owners.get(&join_u64(3, 0)), Some(&Pending));
+            } else {
+                assert!(!vn.owners.contains_key(&join_u64(3, 0)));
+            }
+        }
+        ring.finish_rebalance(0).unwrap();
+        assert!(ring.vnodes
+            .iter()
+            .all(|vn| !vn.owners.contains_key(&join_u64(0, 0))));
+    }
+
+    #[test]
+    fn test_ring_legacy_format() {
+        let mut ring = Ring::new("", 64, 2);
+        for i in 0..3 {
+            let addr: SocketAddr = format!("127.0.0.1:{}", 1999 + i).parse().unwrap();
+            ring.join_node(0, join_u64(i, 0), addr, addr).unwrap();
+        }
+        ring.rebalance(0).unwrap();
+        let legacy = bincode::serialize(&ring).unwrap();
+
+        let upgraded = Ring::<String>::deserialize(&legacy).unwrap();
+        assert_eq!(upgraded.version, ring.version);
+        assert_eq!(upgraded.nodes.len(), 3);
+        assert_eq!(upgraded.nodes[&join_u64(1, 0)].meta, "127.0.0.1:2000");
+        for (a, b) in upgraded.vnodes.iter().zip(&ring.vnodes) {
+            assert_eq!(a.owners, b.owners);
+        }
+        assert!(Ring::<u32>::deserialize(&legacy).is_err());
+
+        let bytes = Ring::serialize(&upgraded).unwrap();
+        assert!(Ring::<SocketAddr>::deserialize(&bytes).is_err());
+        let restored = Ring::<String>::deserialize(&bytes).unwrap();
+        assert_eq!(restored.nodes[&join_u64(1, 0)].meta, "127.0.0.1:2000");
+    }
+
     #[test]
     fn test_dht_init() {
         let _ = env_logger::try_init();
@@ -1113,6 +1757,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_dht_slots() {
+        let _ = env_logger::try_init();
+        let config: Config = Default::default();
+        let fabric = Arc::new(Fabric::new(0, &config).unwrap());
+        let dht = DHT::init(fabric, &config, (), RingDescription::new(1, 100), None).unwrap();
+        // 100 partitions don't divide the slots evenly, but all slots are covered
+        let slots = dht.slots();
+        assert_eq!(slots.len(), 100);
+        let mut next = 0;
+        for (vn_no, &(first, last)) in slots.keys().enumerate() {
+            assert_eq!(first, next);
+            assert_eq!(dht.vnode_slots(vn_no as VNodeNo), (first, last));
+            assert_eq!(dht.slot_vnode(first), vn_no as VNodeNo);
+            assert_eq!(dht.slot_vnode(last), vn_no as VNodeNo);
+            next = last + 1;
+        }
+        assert_eq!(next, HASH_SLOTS);
+        assert_eq!(dht.key_vnode(b"somekey"), dht.slot_vnode(hash_slot(b"somekey")));
+    }
+
     #[test]
     fn test_dht_join() {
         let _ = env_logger::try_init();
@@ -1299,4 +1964,251 @@ mod tests {
             ring.finish_rebalance(0).unwrap();
         }
     }
+
+    // replicas that have to be transfered to go from `before` to `after`
+    fn transfers<T: Metadata>(before: &Ring<T>, after: &Ring<T>) -> usize {
+        before
+            .vnodes
+            .iter()
+            .zip(&after.vnodes)
+            .map(|(b, a)| a.owners.keys().filter(|n| !b.owners.contains_key(n)).count())
+            .sum()
+    }
+
+    #[test]
+    fn test_rebalance_moves() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let partitions = 64usize;
+        let rf = 3usize;
+        for nodes in 3..9u32 {
+            let mut ring = Ring::new("", partitions as u16, rf as u8);
+            for i in 0..nodes {
+                ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+            }
+            ring.rebalance(0).unwrap();
+            ring.finish_rebalance(0).unwrap();
+
+            // nothing moves in a balanced ring
+            let mut same = ring.clone();
+            same.rebalance(0).unwrap();
+            assert_eq!(transfers(&ring, &same), 0);
+
+            for n in 1..5u32 {
+                // joining nodes take at most their share
+                let mut joined = ring.clone();
+                for i in 0..n {
+                    joined
+                        .join_node(0, join_u64(100 + i, 0), addr, ())
+                        .unwrap();
+                }
+                joined.rebalance(0).unwrap();
+                joined.finish_rebalance(0).unwrap();
+                let total = (nodes + n) as usize;
+                let share = (partitions * rf + total - 1) / total;
+                let moves = transfers(&ring, &joined);
+                assert!(
+                    moves <= n as usize * share,
+                    "{} nodes joining {} moved {}",
+                    n,
+                    nodes,
+                    moves
+                );
+
+                if n >= nodes {
+                    continue;
+                }
+                // the leaving nodes replicas move (unless there are fewer nodes left than rf),
+                // plus a few to even out the remaining nodes
+                let mut left = ring.clone();
+                for i in 0..n {
+                    left.leave_node(0, join_u64(i, 0)).unwrap();
+                }
+                left.rebalance(0).unwrap();
+                left.finish_rebalance(0).unwrap();
+                let replicas = min(rf, (nodes - n) as usize);
+                let leaving_replicas: usize = ring.vnodes
+                    .iter()
+                    .map(|vn| {
+                        let staying = vn.owners.keys().filter(|&&o| split_u64(o).0 >= n).count();
+                        replicas - staying
+                    })
+                    .sum();
+                let moves = transfers(&ring, &left);
+                assert!(
+                    moves >= leaving_replicas && moves <= leaving_replicas + leaving_replicas / 2,
+                    "{} nodes leaving {} moved {}, expected about {}",
+                    n,
+                    nodes,
+                    moves,
+                    leaving_replicas
+                );
+                assert!(left.vnodes
+                    .iter()
+                    .all(|vn| vn.owners.keys().all(|&o| split_u64(o).0 >= n)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rebalance_zones() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        for _ in 0..200 {
+            let mut ring = Ring::new("", 32, 1 + thread_rng().gen::<u8>() % 4);
+            let zones = 1 + thread_rng().gen::<u32>() % 4;
+            for i in 0..1 + thread_rng().gen::<u32>() % 16 {
+                let zone = format!("zone{}", thread_rng().gen::<u32>() % zones);
+                ring.join_node(0, join_u64(i, 0), addr, zone).unwrap();
+            }
+            ring.rebalance(0).unwrap();
+            ring.finish_rebalance(0).unwrap();
+
+            let nodes: Vec<_> = ring.nodes.keys().cloned().collect();
+            let spread = min(
+                min(nodes.len(), ring.replication_factor),
+                ring.distinct_zones(&nodes),
+            );
+            for vn in &ring.vnodes {
+                let replicas: Vec<_> = vn.owners.keys().cloned().collect();
+                assert_eq!(ring.distinct_zones(&replicas), spread);
+            }
+        }
+
+        let mut ring = Ring::new("", 32, 2);
+        for i in 0..4 {
+            let zone = if i < 2 { "a" } else { "b" };
+            ring.join_node(0, join_u64(i, 0), addr, zone.to_owned())
+                .unwrap();
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        ring.vnodes[0].owners = vec![(join_u64(0, 0), Owner), (join_u64(1, 0), Owner)]
+            .into_iter()
+            .collect();
+        assert!(ring.is_valid().is_err());
+    }
+
+    #[test]
+    fn test_rebalance_weights() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let loads = |ring: &Ring<u32>| -> Vec<usize> {
+            (0..4)
+                .map(|i| {
+                    ring.vnodes
+                        .iter()
+                        .filter(|vn| vn.owners.contains_key(&join_u64(i, 0)))
+                        .count()
+                })
+                .collect()
+        };
+        let mut ring = Ring::new("", 64, 2);
+        for i in 0..4 {
+            let weight = if i == 0 { 3 } else { 1 };
+            ring.join_node(0, join_u64(i, 0), addr, weight).unwrap();
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        // 128 replicas, half of them go to the first node
+        let before = loads(&ring);
+        assert_eq!(before[0], 64);
+        assert!(before[1..].iter().all(|&l| l == 21 || l == 22));
+
+        let prev = ring.clone();
+        ring.update_node_meta(0, join_u64(3, 0), |w| *w = 2).unwrap();
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        // 3/7, 1/7, 1/7 and 2/7 of the replicas, moving only what the last node gained
+        let after = loads(&ring);
+        assert!(after[0] >= 54 && after[0] <= 56);
+        assert!(after[1] >= 18 && after[1] <= 19);
+        assert!(after[2] >= 18 && after[2] <= 19);
+        assert!(after[3] >= 36 && after[3] <= 38);
+        assert_eq!(transfers(&prev, &ring), after[3] - before[3]);
+    }
+
+    #[test]
+    fn test_ring_set_replication_factor() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let mut ring = Ring::new("", 64, 2);
+        for i in 0..4 {
+            ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        let count = |vn: &VNode, status: VNodeNodeStatus| {
+            vn.owners.values().filter(|&&s| s == status).count()
+        };
+
+        ring.clone().set_replication_factor(0, 0).unwrap_err();
+        ring.clone().set_replication_factor(0, 7).unwrap_err();
+
+        // new replicas bootstrap as pending
+        ring.set_replication_factor(0, 3).unwrap();
+        ring.rebalance(0).unwrap();
+        for vn in &ring.vnodes {
+            assert_eq!(count(vn, Owner), 2);
+            assert_eq!(count(vn, Pending), 1);
+        }
+        ring.finish_rebalance(0).unwrap();
+
+        // extra replicas go away right away, as the remaining owners have the data
+        ring.set_replication_factor(0, 1).unwrap();
+        ring.rebalance(0).unwrap();
+        for vn in &ring.vnodes {
+            assert_eq!(vn.owners.len(), 1);
+            assert_eq!(count(vn, Owner), 1);
+        }
+    }
+
+    #[test]
+    fn test_ring_split() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let mut ring = Ring::new("", 32, 2);
+        for i in 0..3 {
+            ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+        }
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+
+        let mut split = ring.clone();
+        split.split(0).unwrap();
+        assert_eq!(split.vnodes.len(), 64);
+        assert!(split.version.descends(&ring.version));
+        for (i, vn) in split.vnodes.iter().enumerate() {
+            assert_eq!(vn.owners, ring.vnodes[i / 2].owners);
+        }
+        split.is_valid().unwrap();
+
+        // a concurrent change to the unsplit ring is merged into the halves
+        let mut other = ring.clone();
+        other.leave_node(1, join_u64(2, 0)).unwrap();
+        other.rebalance(1).unwrap();
+        assert!(split.clone().merge(other.clone()).unwrap());
+        assert!(other.merge(split.clone()).unwrap());
+        split.merge(other.clone()).unwrap();
+        assert_eq!(split.vnodes.len(), 64);
+        assert_eq!(other.vnodes.len(), 64);
+        for (vn, other_vn) in split.vnodes.iter().zip(&other.vnodes) {
+            assert_eq!(vn.owners, other_vn.owners);
+            assert_ne!(vn.owners.get(&join_u64(2, 0)), Some(&Owner));
+        }
+
+        // incompatible rings are rejected without splitting either
+        let mut other = Ring::new("", 48, 2);
+        other.join_node(0, join_u64(0, 0), addr, ()).unwrap();
+        let version = split.version.clone();
+        split.merge(other.clone()).unwrap_err();
+        other.merge(split.clone()).unwrap_err();
+        assert_eq!(split.vnodes.len(), 64);
+        assert_eq!(other.vnodes.len(), 48);
+        assert_eq!(split.version, version);
+
+        let mut ring = Ring::<()>::new("", HASH_SLOTS / 2, 1);
+        ring.split(0).unwrap();
+        ring.split(0).unwrap_err();
+    }
 }
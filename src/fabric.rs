@@ -14,7 +14,7 @@ use rand::{thread_rng, Rng};
 use futures::future::Either;
 use futures::sync::mpsc as fmpsc;
 use futures::sync::oneshot as foneshot;
-use futures::{Future, Sink, Stream};
+use futures::{Async, Future, Poll, Sink, Stream};
 use tokio_core as tokio;
 use tokio_io::codec;
 use tokio_io::{io as tokio_io, AsyncRead};
@@ -22,7 +22,7 @@ use tokio_io::{io as tokio_io, AsyncRead};
 use config::Config;
 use database::NodeId;
 pub use fabric_msg::*;
-use utils::{into_io_error, GenericError, IdHashMap};
+use utils::{into_io_error, GenericError, IdHashMap, IdHashSet};
 
 // u32(le) payload len + bincode payload
 struct FramedBincodeCodec;
@@ -78,6 +78,7 @@ pub type FabricMsgFn = Box<FnMut(NodeId, FabricMsg) + Send>;
 pub type FabricConFn = Box<FnMut(NodeId) + Send>;
 
 type SenderChan = fmpsc::UnboundedSender<Bytes>;
+type ReceiverChan = fmpsc::UnboundedReceiver<Bytes>;
 type InitType = io::Result<(Arc<SharedContext>, foneshot::Sender<()>)>;
 
 const FABRIC_KEEPALIVE_MS: u64 = 1000;
@@ -99,6 +100,19 @@ pub struct Fabric {
     )>,
 }
 
+// each connection has a channel for the bulk messages (syncs and bootstraps)
+// that is only drained when there's nothing else to send,
+// so these never delay the other messages
+struct SenderChans {
+    normal: SenderChan,
+    bulk: SenderChan,
+}
+
+struct PriorityReceiver {
+    normal: Option<ReceiverChan>,
+    bulk: Option<ReceiverChan>,
+}
+
 struct ReaderContext {
     context: Arc<SharedContext>,
     peer: NodeId,
@@ -122,8 +136,10 @@ struct SharedContext {
     con_handlers: Mutex<Vec<FabricConFn>>,
     // TODO: unify nodes_addr and connections maps
     nodes_addr: RwLock<IdHashMap<NodeId, SocketAddr>>,
-    connections: RwLock<IdHashMap<NodeId, Vec<(usize, SenderChan)>>>,
+    connections: RwLock<IdHashMap<NodeId, Vec<(usize, SenderChans)>>>,
     connection_gen: AtomicUsize,
+    // nodes considered dead by the failure detector, messages to them fail fast
+    dead_nodes: RwLock<IdHashSet<NodeId>>,
 }
 
 impl SharedContext {
@@ -135,7 +151,7 @@ impl SharedContext {
         self.nodes_addr.write().unwrap().remove(&peer)
     }
 
-    fn register_connection(&self, peer: NodeId, sender: SenderChan) -> usize {
+    fn register_connection(&self, peer: NodeId, sender: SenderChans) -> usize {
         let connection_id = self.connection_gen.fetch_add(1, Ordering::Relaxed);
         debug!(
             "register_connection peer: {}, id: {:?}",
@@ -175,6 +191,45 @@ impl SharedContext {
     }
 }
 
+impl PriorityReceiver {
+    fn new(normal: ReceiverChan, bulk: ReceiverChan) -> Self {
+        PriorityReceiver {
+            normal: Some(normal),
+            bulk: Some(bulk),
+        }
+    }
+
+    // polls the channel, which is dropped once closed
+    fn poll_chan(chan: &mut Option<ReceiverChan>) -> Poll<Option<Bytes>, ()> {
+        let result = match *chan {
+            Some(ref mut c) => c.poll()?,
+            None => return Ok(Async::Ready(None)),
+        };
+        if let Async::Ready(None) = result {
+            *chan = None;
+        }
+        Ok(result)
+    }
+}
+
+impl Stream for PriorityReceiver {
+    type Item = Bytes;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let normal = Self::poll_chan(&mut self.normal)?;
+        if let Async::Ready(Some(item)) = normal {
+            return Ok(Async::Ready(Some(item)));
+        }
+        let bulk = Self::poll_chan(&mut self.bulk)?;
+        match (normal, bulk) {
+            (_, Async::Ready(Some(item))) => Ok(Async::Ready(Some(item))),
+            (Async::Ready(None), Async::Ready(None)) => Ok(Async::Ready(None)),
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
+
 impl ReaderContext {
     fn new(context: Arc<SharedContext>, peer: NodeId) -> Self {
         ReaderContext {
@@ -200,7 +255,7 @@ impl ReaderContext {
 }
 
 impl WriterContext {
-    fn new(context: Arc<SharedContext>, peer: NodeId, sender: SenderChan) -> Self {
+    fn new(context: Arc<SharedContext>, peer: NodeId, sender: SenderChans) -> Self {
         let connection_id = context.register_connection(peer, sender);
         WriterContext {
             context: context,
@@ -325,6 +380,7 @@ impl Fabric {
         let socket_tx = codec::FramedWrite::new(socket_tx, FramedBincodeCodec);
         let socket_rx = codec::FramedRead::new(socket_rx, FramedBincodeCodec);
         let (chan_tx, chan_rx) = fmpsc::unbounded();
+        let (bulk_chan_tx, bulk_chan_rx) = fmpsc::unbounded();
 
         let ctx_rx = ReaderContext::new(context.clone(), peer);
         let fut_rx = socket_rx.for_each(move |msg| {
@@ -332,9 +388,16 @@ impl Fabric {
             Ok(())
         });
 
-        let ctx_tx = WriterContext::new(context, peer, chan_tx);
+        let chans = SenderChans {
+            normal: chan_tx,
+            bulk: bulk_chan_tx,
+        };
+        let ctx_tx = WriterContext::new(context, peer, chans);
         let fut_tx = socket_tx
-            .send_all(chan_rx.map_err(|_| io::Error::from(io::ErrorKind::Other)))
+            .send_all(
+                PriorityReceiver::new(chan_rx, bulk_chan_rx)
+                    .map_err(|_| io::Error::from(io::ErrorKind::Other)),
+            )
             .then(move |r| {
                 // hold onto ctx_tx until the stream is done
                 drop(ctx_tx);
@@ -358,6 +421,7 @@ impl Fabric {
             con_handlers: Default::default(),
             connections: Default::default(),
             connection_gen: Default::default(),
+            dead_nodes: Default::default(),
         });
 
         let listener = tokio::net::TcpListener::bind(&context.addr, &handle)?;
@@ -429,6 +493,16 @@ impl Fabric {
             .collect()
     }
 
+    pub fn is_connected(&self, node: NodeId) -> bool {
+        let writers = self.context.connections.read().unwrap();
+        writers.get(&node).map_or(false, |c| !c.is_empty())
+    }
+
+    pub fn connection_counts(&self) -> Vec<(NodeId, usize)> {
+        let writers = self.context.connections.read().unwrap();
+        writers.iter().map(|(&n, c)| (n, c.len())).collect()
+    }
+
     pub fn set_nodes<I>(&self, it: I)
     where
         I: Iterator<Item = (NodeId, SocketAddr)>,
@@ -448,6 +522,16 @@ impl Fabric {
         }
     }
 
+    /// Updates the liveness of a node, as reported by the failure detector
+    pub fn set_node_alive(&self, node: NodeId, alive: bool) {
+        let mut dead_nodes = self.context.dead_nodes.write().unwrap();
+        if alive {
+            dead_nodes.remove(&node);
+        } else {
+            dead_nodes.insert(node);
+        }
+    }
+
     fn start_connect(&self, expected_node: Option<NodeId>, addr: SocketAddr) {
         let context = self.context.clone();
         let context_cloned = context.clone();
@@ -467,6 +551,14 @@ impl Fabric {
         if node == self.context.node {
             panic!("Can't send message to self");
         }
+        // only requests fail fast, syncs and ring changes are still sent (if connected)
+        // as the failure detector can be wrong, eg. when udp is blocked between the nodes
+        if let FabricMsgType::Crud = msg.get_type() {
+            if self.context.dead_nodes.read().unwrap().contains(&node) {
+                debug!("DROPING MSG - Node {:?} is dead", node);
+                return Err(FabricError::NoRoute);
+            }
+        }
         if cfg!(test) {
             let droppable = match msg.get_type() {
                 FabricMsgType::Crud => false,
@@ -484,10 +576,15 @@ impl Fabric {
             }
         }
 
+        let bulk = match msg.get_type() {
+            FabricMsgType::Synch => true,
+            _ => false,
+        };
         let serialized_msg = FramedBincodeCodec::serialize(msg);
         let connections = self.context.connections.read().unwrap();
         if let Some(o) = connections.get(&node) {
-            if let Some(&(connection_id, ref chan)) = thread_rng().choose::<(_, _)>(o) {
+            if let Some(&(connection_id, ref chans)) = thread_rng().choose::<(_, _)>(o) {
+                let chan = if bulk { &chans.bulk } else { &chans.normal };
                 if let Err(_) = chan.unbounded_send(serialized_msg) {
                     warn!("Can't send to fabric {}-{} chan", node, connection_id,);
                 } else {
@@ -523,6 +620,23 @@ mod tests {
     use std::thread;
     use std::time::Duration;
 
+    #[test]
+    fn test_priority() {
+        let (normal_tx, normal_rx) = fmpsc::unbounded();
+        let (bulk_tx, bulk_rx) = fmpsc::unbounded();
+        bulk_tx.unbounded_send(Bytes::from_static(b"bulk")).unwrap();
+        normal_tx.unbounded_send(Bytes::from_static(b"normal")).unwrap();
+        drop((normal_tx, bulk_tx));
+        let items: Vec<_> = PriorityReceiver::new(normal_rx, bulk_rx)
+            .wait()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            items,
+            vec![Bytes::from_static(b"normal"), Bytes::from_static(b"bulk")]
+        );
+    }
+
     #[test]
     fn test() {
         let _ = env_logger::try_init();
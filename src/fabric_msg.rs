@@ -1,6 +1,7 @@
 use bytes::Bytes;
 use cubes::Cube;
 use database::*;
+use utils::IdHashMap;
 use version_vector::*;
 
 #[derive(Debug, Copy, Clone)]
@@ -19,6 +20,13 @@ pub enum FabricError {
     NotReady,
     SyncInterrupted,
     StorageError,
+    // sender and receiver don't agree on the partitioning (eg. during a split)
+    WrongPartition,
+    // the sender log doesn't cover the sync anymore
+    LogTruncated,
+    // the sender is alive but not sending (hashing its data for a hash tree sync
+    // or throttled), the receiver keeps waiting
+    SenderWaiting,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,6 +39,7 @@ pub enum FabricMsg {
     SyncSend(MsgSyncSend),
     SyncAck(MsgSyncAck),
     SyncFin(MsgSyncFin),
+    SyncPush(MsgSyncPush),
     DHTAE(VersionVector),
     DHTSync(Bytes),
     Unknown,
@@ -46,6 +55,7 @@ pub enum FabricMsgRef<'a> {
     SyncSend(&'a MsgSyncSend),
     SyncAck(&'a MsgSyncAck),
     SyncFin(&'a MsgSyncFin),
+    SyncPush(&'a MsgSyncPush),
     DHTAE(&'a VersionVector),
     DHTSync(&'a Bytes),
     Unknown,
@@ -61,11 +71,27 @@ impl FabricMsg {
             FabricMsg::SyncStart(..)
             | FabricMsg::SyncSend(..)
             | FabricMsg::SyncAck(..)
-            | FabricMsg::SyncFin(..) => FabricMsgType::Synch,
+            | FabricMsg::SyncFin(..)
+            | FabricMsg::SyncPush(..) => FabricMsgType::Synch,
             FabricMsg::DHTSync(..) | FabricMsg::DHTAE(..) => FabricMsgType::DHT,
             _ => unreachable!(),
         }
     }
+
+    pub fn vnode(&self) -> Option<VNodeNo> {
+        match *self {
+            FabricMsg::RemoteGet(ref m) => Some(m.vnode),
+            FabricMsg::RemoteGetAck(ref m) => Some(m.vnode),
+            FabricMsg::RemoteSet(ref m) => Some(m.vnode),
+            FabricMsg::RemoteSetAck(ref m) => Some(m.vnode),
+            FabricMsg::SyncStart(ref m) => Some(m.vnode),
+            FabricMsg::SyncSend(ref m) => Some(m.vnode),
+            FabricMsg::SyncAck(ref m) => Some(m.vnode),
+            FabricMsg::SyncFin(ref m) => Some(m.vnode),
+            FabricMsg::SyncPush(ref m) => Some(m.vnode),
+            _ => None,
+        }
+    }
 }
 
 impl<'a> FabricMsgRef<'a> {
@@ -78,7 +104,8 @@ impl<'a> FabricMsgRef<'a> {
             FabricMsgRef::SyncStart(..)
             | FabricMsgRef::SyncSend(..)
             | FabricMsgRef::SyncAck(..)
-            | FabricMsgRef::SyncFin(..) => FabricMsgType::Synch,
+            | FabricMsgRef::SyncFin(..)
+            | FabricMsgRef::SyncPush(..) => FabricMsgType::Synch,
             FabricMsgRef::DHTSync(..) | FabricMsgRef::DHTAE(..) => FabricMsgType::DHT,
             _ => unreachable!(),
         }
@@ -117,16 +144,27 @@ pub struct MsgRemoteSetAck {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MsgSyncStart {
     pub vnode: VNodeNo,
+    pub partitions: u16,
     pub cookie: Cookie,
     pub clocks_in_peer: BitmappedVersionVector,
     pub target: Option<NodeId>,
+    // hash tree leaves of the receiver, for syncs that can't rely on the sender log
+    pub tree: Option<Vec<u64>>,
+    // for bootstraps, only the keys after this one are sent (the receiver checkpoint)
+    pub resume_after: Option<Bytes>,
+    // for bootstraps, only the keys in this share are sent (index, number of shares)
+    pub share: Option<(u16, u16)>,
+    // retired ids pruned by the receiver, see `VNodeState::pruned`
+    pub pruned: IdHashMap<Id, Version>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MsgSyncFin {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
-    pub result: Result<BitmappedVersionVector, FabricError>,
+    // the sender clocks and, for hash tree syncs, the leaves that differed
+    // (the receiver drops the values in them the sender saw deleted)
+    pub result: Result<(BitmappedVersionVector, Option<Vec<u16>>), FabricError>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -136,6 +174,8 @@ pub struct MsgSyncSend {
     pub seq: u64,
     pub key: Bytes,
     pub value: Cube,
+    // bootstraps send the clocks snapshot along with the first key
+    pub clocks: Option<BitmappedVersionVector>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -145,6 +185,15 @@ pub struct MsgSyncAck {
     pub seq: u64,
 }
 
+// asks the peer to sync from the sender (syncs are started by the receiving side),
+// used by zombie vnodes to hand off their data
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgSyncPush {
+    pub vnode: VNodeNo,
+    // compare hash trees, as a regular sync didn't cover everything
+    pub tree: bool,
+}
+
 impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
     fn into(self) -> FabricMsgRef<'a> {
         match self {
@@ -156,6 +205,7 @@ impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
             &FabricMsg::SyncSend(ref a) => FabricMsgRef::SyncSend(a),
             &FabricMsg::SyncAck(ref a) => FabricMsgRef::SyncAck(a),
             &FabricMsg::SyncFin(ref a) => FabricMsgRef::SyncFin(a),
+            &FabricMsg::SyncPush(ref a) => FabricMsgRef::SyncPush(a),
             &FabricMsg::DHTSync(ref a) => FabricMsgRef::DHTSync(a),
             &FabricMsg::DHTAE(ref a) => FabricMsgRef::DHTAE(a),
             _ => unreachable!(),
@@ -186,3 +236,4 @@ impl_into!(SyncAck, MsgSyncAck);
 impl_into!(SyncSend, MsgSyncSend);
 impl_into!(SyncFin, MsgSyncFin);
 impl_into!(SyncStart, MsgSyncStart);
+impl_into!(SyncPush, MsgSyncPush);
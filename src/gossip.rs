@@ -135,7 +135,7 @@ impl<T: Metadata> tokio::net::UdpCodec for UdpCodec<T> {
     fn encode(&mut self, addr_msg: Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
         let (addr, msg) = addr_msg;
         trace!("encoding {:?}", msg);
-        match bincode::serialize_into(buf, &msg, bincode::Infinite) {
+        match bincode::serialize_into(buf, &msg) {
             Ok(_) => addr,
             Err(err) => {
                 panic!("encode err: {:?}", err);
@@ -730,7 +730,11 @@ mod tests {
         let _ = env_logger::try_init();
         let g: Vec<_> = (0..n)
             .map(|i| {
-                Gossiper::new(format!("0.0.0.0:{}", 9000 + i).parse().unwrap(), ()).unwrap()
+                Gossiper::new(
+                    format!("0.0.0.0:{}", 9000 + i).parse().unwrap(),
+                    (),
+                    Box::new(|_| ()),
+                ).unwrap()
             })
             .collect();
         let start = Instant::now();
@@ -16,6 +16,19 @@ pub fn hash_slot(mut key: &[u8]) -> u16 {
     crc16::State::<crc16::XMODEM>::calculate(key) % HASH_SLOTS
 }
 
+/// Vnode of a hash slot, each vnode gets a contiguous range of slots and the range
+/// sizes differ by at most one slot. Doubling the partitions splits each range in two.
+pub fn slot_vnode(slot: u16, partitions: usize) -> u16 {
+    (((slot as usize + 1) * partitions - 1) / HASH_SLOTS as usize) as u16
+}
+
+/// First and last hash slot of the vnode, see `slot_vnode`
+pub fn vnode_slots(vnode: u16, partitions: usize) -> (u16, u16) {
+    let first = vnode as usize * HASH_SLOTS as usize / partitions;
+    let next = (vnode as usize + 1) * HASH_SLOTS as usize / partitions;
+    (first as u16, (next - 1) as u16)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -35,4 +48,24 @@ mod tests {
         assert_eq!(hash_slot(b"{abc}{}"), raw_hash(b"abc"));
         assert_eq!(hash_slot(b"{{abc}}"), raw_hash(b"{abc"));
     }
+
+    #[test]
+    fn test_slot_vnode() {
+        for &partitions in &[1usize, 3, 32, 64, 100, 1000, 1024, 5000, 16383, 16384] {
+            let mut next = 0;
+            for vnode in 0..partitions as u16 {
+                let (first, last) = vnode_slots(vnode, partitions);
+                assert_eq!(first, next);
+                assert!(last >= first);
+                assert_eq!(slot_vnode(first, partitions), vnode);
+                assert_eq!(slot_vnode(last, partitions), vnode);
+                next = last + 1;
+                if partitions * 2 <= HASH_SLOTS as usize {
+                    assert_eq!(vnode_slots(vnode * 2, partitions * 2).0, first);
+                    assert_eq!(vnode_slots(vnode * 2 + 1, partitions * 2).1, last);
+                }
+            }
+            assert_eq!(next as usize, HASH_SLOTS as usize);
+        }
+    }
 }
@@ -0,0 +1,99 @@
+use database::NodeId;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use utils::IdHashMap;
+
+// weight of the latest sample in the latency moving average
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+// consecutive request timeouts after which a peer is considered down
+const DOWN_TIMEOUTS: u32 = 3;
+// peers considered down are avoided for this long, then tried again
+const DOWN_BACKOFF_MS: u64 = 2_000;
+
+#[derive(Default)]
+struct PeerHealth {
+    // moving average of the reply latency (microseconds)
+    latency: Option<f64>,
+    consecutive_timeouts: u32,
+    last_timeout: Option<Instant>,
+}
+
+/// Health of the peers as observed by the requests coordinated by this node
+#[derive(Default)]
+pub struct Health {
+    peers: RwLock<IdHashMap<NodeId, PeerHealth>>,
+}
+
+impl Health {
+    /// Records a reply from the node, received `latency` after the request was sent
+    pub fn record_reply(&self, node: NodeId, latency: Duration) {
+        let sample = latency.as_secs() as f64 * 1e6 + f64::from(latency.subsec_nanos()) / 1e3;
+        let mut peers = self.peers.write().unwrap();
+        let peer = peers.entry(node).or_insert_with(Default::default);
+        peer.latency = Some(match peer.latency {
+            Some(latency) => latency + LATENCY_EWMA_ALPHA * (sample - latency),
+            None => sample,
+        });
+        peer.consecutive_timeouts = 0;
+    }
+
+    /// Records a request that timed out waiting for the node
+    pub fn record_timeout(&self, node: NodeId) {
+        let mut peers = self.peers.write().unwrap();
+        let peer = peers.entry(node).or_insert_with(Default::default);
+        peer.consecutive_timeouts += 1;
+        peer.last_timeout = Some(Instant::now());
+    }
+
+    /// Whether the node timed out repeatedly and should be avoided for now
+    pub fn is_down(&self, node: NodeId) -> bool {
+        self.peers.read().unwrap().get(&node).map_or(false, |p| {
+            p.consecutive_timeouts >= DOWN_TIMEOUTS
+                && p.last_timeout
+                    .map_or(false, |t| t.elapsed() < Duration::from_millis(DOWN_BACKOFF_MS))
+        })
+    }
+
+    /// Moving average of the node reply latency, if it ever replied
+    pub fn latency(&self, node: NodeId) -> Option<Duration> {
+        self.peers
+            .read()
+            .unwrap()
+            .get(&node)
+            .and_then(|p| p.latency)
+            .map(|us| {
+                let us = us as u64;
+                Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1_000)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency() {
+        let health = Health::default();
+        assert_eq!(health.latency(1), None);
+        health.record_reply(1, Duration::from_millis(10));
+        assert_eq!(health.latency(1), Some(Duration::from_millis(10)));
+        health.record_reply(1, Duration::from_millis(20));
+        assert_eq!(health.latency(1), Some(Duration::from_millis(12)));
+        assert_eq!(health.latency(2), None);
+    }
+
+    #[test]
+    fn test_down() {
+        let health = Health::default();
+        for _ in 0..DOWN_TIMEOUTS - 1 {
+            health.record_timeout(1);
+        }
+        assert!(!health.is_down(1));
+        health.record_timeout(1);
+        assert!(health.is_down(1));
+        assert!(!health.is_down(2));
+        health.record_reply(1, Duration::from_millis(1));
+        assert!(!health.is_down(1));
+    }
+}
@@ -1,5 +1,5 @@
 use std::cmp::Ordering;
-use std::collections::hash_map::{Entry, RandomState};
+use std::collections::hash_map::{Drain, Entry, RandomState};
 use std::collections::{BinaryHeap, HashMap};
 use std::fmt;
 use std::hash::{BuildHasher, Hash};
@@ -28,6 +28,11 @@ impl<K: Hash + Eq + Copy + fmt::Debug, V, T: Ord + Copy, H: BuildHasher + Defaul
         self.heap.clear();
     }
 
+    pub fn drain(&mut self) -> Drain<K, V> {
+        self.heap.clear();
+        self.map.drain()
+    }
+
     pub fn remove(&mut self, key: &K) -> Option<V> {
         self.map.remove(key)
     }
@@ -42,21 +42,27 @@ extern crate env_logger;
 mod utils;
 mod types;
 mod version_vector;
-// mod gossip;
+mod gossip;
 mod cubes;
 mod dht;
 mod fabric;
 mod fabric_msg;
 mod hash;
+mod health;
 mod inflightmap;
+mod merkle;
 mod storage;
 #[macro_use]
 mod database;
+mod clients;
 mod command;
 mod config;
 mod metrics;
+mod prometheus;
 mod resp;
 mod server;
+mod slowlog;
+mod throttle;
 mod vnode;
 mod vnode_sync;
 mod workers;
@@ -131,8 +137,8 @@ fn configure() -> config::Config {
                         .short("p")
                         .help("Number of partitions")
                         .long_help(
-                            "Number of partitions, the recommended value is \
-                             `expected node count * 10` rounded up to the next power of 2.",
+                            "Number of partitions (32 to 1024), the recommended value is \
+                             `expected node count * 10`.",
                         )
                         .default_value(DEFAULT_PARTITIONS),
                 )
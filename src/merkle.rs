@@ -0,0 +1,124 @@
+use byteorder::{ByteOrder, LittleEndian};
+use utils::GenericError;
+
+/// Number of leaves in the hash trees (a power of 2)
+pub const MERKLE_LEAVES: usize = 1024;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// FNV-1a, unlike the std hasher it's stable across nodes and versions
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Leaf of the key, keys are spread among the leaves by their hash
+pub fn leaf_of(key: &[u8]) -> usize {
+    fnv1a(FNV_OFFSET, key) as usize % MERKLE_LEAVES
+}
+
+/// Hash of a key/value pair, to be added to the leaf of the key.
+/// Leaves are the wrapping sum of their entries hashes, so the order doesn't matter.
+pub fn entry_hash(key: &[u8], value: &[u8]) -> u64 {
+    fnv1a(fnv1a(FNV_OFFSET, key), value)
+}
+
+/// Binary hash tree of the key/values of a vnode.
+/// Replicas exchange the leaves and compare the trees from the root down,
+/// so equal subtrees are skipped.
+#[derive(Debug)]
+pub struct MerkleTree {
+    // levels[0] contains the leaves and the last level the root
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<u64>) -> Result<Self, GenericError> {
+        if leaves.len() != MERKLE_LEAVES {
+            return Err(format!("Expected {} leaves got {}", MERKLE_LEAVES, leaves.len()).into());
+        }
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| {
+                    let mut buffer = [0u8; 16];
+                    LittleEndian::write_u64_into(pair, &mut buffer);
+                    fnv1a(FNV_OFFSET, &buffer)
+                })
+                .collect();
+            levels.push(level);
+        }
+        Ok(MerkleTree { levels: levels })
+    }
+
+    pub fn leaves(&self) -> &[u64] {
+        &self.levels[0]
+    }
+
+    pub fn root(&self) -> u64 {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Leaves that differ between the trees, in ascending order
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        let mut result = Vec::new();
+        // (level, index) pairs pending comparison
+        let mut stack = vec![(self.levels.len() - 1, 0)];
+        while let Some((level, i)) = stack.pop() {
+            if self.levels[level][i] == other.levels[level][i] {
+                continue;
+            }
+            if level == 0 {
+                result.push(i);
+            } else {
+                stack.push((level - 1, i * 2 + 1));
+                stack.push((level - 1, i * 2));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(entries: &[(&str, &str)]) -> MerkleTree {
+        let mut leaves = vec![0u64; MERKLE_LEAVES];
+        for &(k, v) in entries {
+            let leaf = &mut leaves[leaf_of(k.as_bytes())];
+            *leaf = leaf.wrapping_add(entry_hash(k.as_bytes(), v.as_bytes()));
+        }
+        MerkleTree::new(leaves).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = tree(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let b = tree(&[("c", "3"), ("b", "2"), ("a", "1")]);
+        assert_eq!(a.root(), b.root());
+        assert!(a.diff(&b).is_empty());
+
+        let c = tree(&[("a", "1"), ("b", "x"), ("d", "4")]);
+        assert_ne!(a.root(), c.root());
+        let mut expected = vec![leaf_of(b"b"), leaf_of(b"c"), leaf_of(b"d")];
+        expected.sort();
+        expected.dedup();
+        assert_eq!(a.diff(&c), expected);
+        assert_eq!(c.diff(&a), expected);
+    }
+
+    #[test]
+    fn test_bad_leaves() {
+        assert!(MerkleTree::new(vec![0; MERKLE_LEAVES - 1]).is_err());
+        let tree = MerkleTree::new(vec![0; MERKLE_LEAVES]).unwrap();
+        assert_eq!(tree.leaves().len(), MERKLE_LEAVES);
+    }
+}
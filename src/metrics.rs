@@ -1,8 +1,9 @@
 pub use rust_metrics::metrics::{Counter, Gauge, Meter, Metric};
 use rust_metrics::metrics::{StdGauge, StdMeter};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-// TODO: Expose these metrics
 lazy_static! {
     pub static ref CLIENT_CONNECTION: Arc<StdGauge> = { StdGauge::new() };
     pub static ref REQUEST_GET: Arc<Meter> = { StdMeter::new() };
@@ -11,6 +12,164 @@ lazy_static! {
     pub static ref SYNC_SEND: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_RECV: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_RESEND: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref SYNC_TREE: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref SYNC_THROTTLED: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref SWEEP_KEYS: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref SWEEP_BYTES: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref HANDOFF_STUCK: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_OUTGOING: Arc<StdGauge> = { StdGauge::new() };
     pub static ref SYNC_INCOMING: Arc<StdGauge> = { StdGauge::new() };
 }
+
+/// Snapshot of a meter (count, one minute rate)
+pub struct MeterValue {
+    pub count: i64,
+    pub rate: f64,
+}
+
+pub fn meters() -> Vec<(&'static str, MeterValue)> {
+    let meters: [(&'static str, &Meter); 11] = [
+        ("request_get", &**REQUEST_GET),
+        ("request_set", &**REQUEST_SET),
+        ("request_del", &**REQUEST_DEL),
+        ("sync_send", &**SYNC_SEND),
+        ("sync_recv", &**SYNC_RECV),
+        ("sync_resend", &**SYNC_RESEND),
+        ("sync_tree", &**SYNC_TREE),
+        ("sync_throttled", &**SYNC_THROTTLED),
+        ("sweep_keys", &**SWEEP_KEYS),
+        ("sweep_bytes", &**SWEEP_BYTES),
+        ("handoff_stuck", &**HANDOFF_STUCK),
+    ];
+    meters
+        .iter()
+        .map(|&(name, meter)| {
+            let snapshot = meter.snapshot();
+            (
+                name,
+                MeterValue {
+                    count: snapshot.count,
+                    rate: snapshot.rates[0],
+                },
+            )
+        })
+        .collect()
+}
+
+pub fn gauges() -> Vec<(&'static str, i64)> {
+    let gauges: [(&'static str, &StdGauge); 3] = [
+        ("client_connection", &**CLIENT_CONNECTION),
+        ("sync_outgoing", &**SYNC_OUTGOING),
+        ("sync_incoming", &**SYNC_INCOMING),
+    ];
+    gauges
+        .iter()
+        .map(|&(name, gauge)| (name, gauge.snapshot().value))
+        .collect()
+}
+
+// upper bounds (in microseconds) of the latency histogram buckets
+const LATENCY_BUCKETS_US: [u64; 13] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000,
+];
+
+// commands with a latency histogram
+const COMMANDS: &[&str] = &[
+    "get", "mget", "set", "getset", "del", "cget", "cset", "incrby", "hgetall", "hset", "hdel",
+    "smembers", "sadd", "srem", "type", "multi", "exec", "echo", "cluster", "config", "client",
+    "info", "slowlog", "monitor",
+];
+
+lazy_static! {
+    static ref COMMAND_LATENCY: Vec<(&'static str, Histogram)> =
+        { COMMANDS.iter().map(|&c| (c, Histogram::new())).collect() };
+}
+
+/// Latency histogram with fixed buckets, safe to update concurrently
+pub struct Histogram {
+    buckets: Vec<AtomicUsize>,
+    sum_us: AtomicUsize,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: (0..LATENCY_BUCKETS_US.len() + 1)
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            sum_us: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64;
+        let i = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&b| us <= b)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us as usize, Ordering::Relaxed);
+    }
+
+    /// Cumulative counts for each bucket upper bound (in seconds, None is +Inf)
+    /// along with the sum of all recorded values (in seconds)
+    pub fn snapshot(&self) -> (Vec<(Option<f64>, usize)>, f64) {
+        let mut cumulative = 0;
+        let buckets = self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                cumulative += b.load(Ordering::Relaxed);
+                let bound = LATENCY_BUCKETS_US.get(i).map(|&us| us as f64 / 1e6);
+                (bound, cumulative)
+            })
+            .collect();
+        (buckets, self.sum_us.load(Ordering::Relaxed) as f64 / 1e6)
+    }
+}
+
+pub fn command_latency(name: &[u8]) -> Option<&'static Histogram> {
+    COMMAND_LATENCY
+        .iter()
+        .find(|&&(c, _)| c.as_bytes().eq_ignore_ascii_case(name))
+        .map(|&(_, ref h)| h)
+}
+
+pub fn command_latencies() -> &'static [(&'static str, Histogram)] {
+    &COMMAND_LATENCY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_us(us: u64) -> Duration {
+        Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1_000)
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new();
+        for &us in &[0, 100, 101, 1_000_000, 1_000_001, 5_000_000] {
+            histogram.record(from_us(us));
+        }
+        let (buckets, sum) = histogram.snapshot();
+        let last = LATENCY_BUCKETS_US.len();
+        assert_eq!(buckets.len(), last + 1);
+        // bounds are inclusive and the counts cumulative
+        assert_eq!(buckets[0], (Some(0.0001), 2));
+        assert_eq!(buckets[1], (Some(0.00025), 3));
+        assert_eq!(buckets[last - 1], (Some(1.0), 4));
+        // values over the last bound only count in +Inf
+        assert_eq!(buckets[last], (None, 6));
+        assert!((sum - 7.000202).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_command_latency() {
+        assert!(command_latency(b"GET").is_some());
+        assert!(command_latency(b"hgetall").is_some());
+        assert!(command_latency(b"unknown").is_none());
+    }
+}
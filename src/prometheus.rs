@@ -0,0 +1,159 @@
+use database::Database;
+use metrics;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+use utils::{GenericError, LoggerExt};
+use vnode::VNodeStatus;
+
+const HTTP_TIMEOUT_MS: u64 = 5_000;
+
+/// Starts a (blocking) http server that renders the metrics in the Prometheus text format.
+/// Requests are served one at a time, that's good enough for scrappers.
+pub fn start(addr: SocketAddr, database: &Arc<Database>) -> Result<(), GenericError> {
+    let listener = TcpListener::bind(addr)?;
+    let database = Arc::downgrade(database);
+    info!("Serving metrics on {}", addr);
+    thread::Builder::new()
+        .name("Metrics".into())
+        .spawn(move || serve(listener, database))?;
+    Ok(())
+}
+
+fn serve(listener: TcpListener, database: Weak<Database>) {
+    for stream in listener.incoming() {
+        let db = if let Some(db) = database.upgrade() {
+            db
+        } else {
+            break;
+        };
+        match stream {
+            Ok(stream) => handle(stream, &db).log_warn("Error serving metrics request"),
+            Err(e) => warn!("Error accepting metrics connection: {:?}", e),
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream, db: &Database) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(HTTP_TIMEOUT_MS)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(HTTP_TIMEOUT_MS)))?;
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(&stream);
+        reader.read_line(&mut request_line)?;
+        // consume the headers, the body (if any) is ignored
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) | (Some("GET"), Some("/")) => ("200 OK", render(db)),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+fn metric_type(out: &mut String, name: &str, kind: &str) {
+    let _ = writeln!(out, "# TYPE sucredb_{} {}", name, kind);
+}
+
+/// Renders the metrics in the Prometheus text format
+pub fn render(db: &Database) -> String {
+    let mut out = String::with_capacity(16 * 1024);
+
+    for (name, meter) in metrics::meters() {
+        metric_type(&mut out, &format!("{}_total", name), "counter");
+        let _ = writeln!(out, "sucredb_{}_total {}", name, meter.count);
+    }
+
+    for (name, value) in metrics::gauges() {
+        metric_type(&mut out, name, "gauge");
+        let _ = writeln!(out, "sucredb_{} {}", name, value);
+    }
+
+    metric_type(&mut out, "command_duration_seconds", "histogram");
+    for &(command, ref histogram) in metrics::command_latencies() {
+        let (buckets, sum) = histogram.snapshot();
+        let mut count = 0;
+        for (bound, cumulative) in buckets {
+            let le = bound.map_or("+Inf".into(), |b| b.to_string());
+            let _ = writeln!(
+                out,
+                "sucredb_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                command, le, cumulative
+            );
+            count = cumulative;
+        }
+        let _ = writeln!(
+            out,
+            "sucredb_command_duration_seconds_sum{{command=\"{}\"}} {}",
+            command, sum
+        );
+        let _ = writeln!(
+            out,
+            "sucredb_command_duration_seconds_count{{command=\"{}\"}} {}",
+            command, count
+        );
+    }
+
+    metric_type(&mut out, "vnodes", "gauge");
+    let statuses = db.vnodes_status();
+    for &(status, label) in &[
+        (VNodeStatus::Ready, "ready"),
+        (VNodeStatus::Bootstrap, "bootstrap"),
+        (VNodeStatus::Zombie, "zombie"),
+        (VNodeStatus::Absent, "absent"),
+    ] {
+        let count = statuses.iter().filter(|&&s| s == status).count();
+        let _ = writeln!(out, "sucredb_vnodes{{status=\"{}\"}} {}", label, count);
+    }
+
+    metric_type(&mut out, "sync_keys", "gauge");
+    for (vnode, progress) in db.syncs_progress() {
+        let _ = writeln!(
+            out,
+            "sucredb_sync_keys{{vnode=\"{}\",kind=\"{}\",peer=\"{}\"}} {}",
+            vnode, progress.kind, progress.peer, progress.count
+        );
+    }
+
+    metric_type(&mut out, "fabric_connections", "gauge");
+    for (peer, count) in db.fabric.connection_counts() {
+        let _ = writeln!(
+            out,
+            "sucredb_fabric_connections{{peer=\"{}\"}} {}",
+            peer, count
+        );
+    }
+
+    let mut last_property = "";
+    for (cf, property, value) in db.storage_manager.stats() {
+        let name = format!(
+            "rocksdb_{}",
+            property.trim_left_matches("rocksdb.").replace('-', "_")
+        );
+        if property != last_property {
+            metric_type(&mut out, &name, "gauge");
+            last_property = property;
+        }
+        let _ = writeln!(out, "sucredb_{}{{cf=\"{}\"}} {}", name, cf, value);
+    }
+
+    out
+}
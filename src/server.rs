@@ -1,8 +1,10 @@
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::io;
+use std::net;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use bytes::{BufMut, BytesMut};
 use database::{Context as DbContext, Database, Token, WorkerMsg};
@@ -14,9 +16,12 @@ use workers::WorkerSender;
 
 use config::Config;
 use metrics::{self, Gauge};
+use prometheus;
 use resp::{self, RespValue};
 use utils::IdHashMap;
 
+const MONITOR_BUFFER: usize = 1000;
+
 struct RespCodec;
 
 impl codec::Decoder for RespCodec {
@@ -86,7 +91,12 @@ impl Context {
     fn dispatch(&mut self, req: RespValue) {
         if let Some(mut db_context) = self.db_context.take() {
             debug!("Dispatched request ({}) {:?}", self.token, req);
+            self.context
+                .database
+                .clients
+                .on_request(self.token, Some(&req), self.requests.len());
             db_context.commands.push(req);
+            db_context.started = Some(Instant::now());
             self.context
                 .db_sender
                 .borrow_mut()
@@ -94,6 +104,10 @@ impl Context {
         } else {
             debug!("Enqueued request ({}) {:?}", self.token, req);
             self.requests.push_back(req);
+            self.context
+                .database
+                .clients
+                .on_request(self.token, None, self.requests.len());
         }
     }
 
@@ -104,7 +118,12 @@ impl Context {
         );
         if let Some(req) = self.requests.pop_front() {
             debug!("Dispatched request ({}) {:?}", self.token, req);
+            self.context
+                .database
+                .clients
+                .on_request(self.token, Some(&req), self.requests.len());
             db_context.commands.push(req);
+            db_context.started = Some(Instant::now());
             self.context
                 .db_sender
                 .borrow_mut()
@@ -118,6 +137,7 @@ impl Context {
 impl Drop for Context {
     fn drop(&mut self) {
         self.context.token_chans.lock().unwrap().remove(&self.token);
+        self.context.database.clients.unregister(self.token);
         metrics::CLIENT_CONNECTION.dec();
     }
 }
@@ -131,8 +151,13 @@ impl Server {
         context: Rc<SharedContext>,
         token: Token,
         socket: tokio::net::TcpStream,
+        addr: net::SocketAddr,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         socket.set_nodelay(true).expect("Failed to set nodelay");
+        // output of the MONITOR command, bounded so slow clients can't pile up memory
+        let (monitor_tx, monitor_rx) = fmpsc::channel(MONITOR_BUFFER);
+        // resolves if the client is killed through the CLIENT KILL command
+        let kill_rx = context.database.clients.register(token, addr, monitor_tx);
         let (sock_rx, sock_tx) = socket.split();
         let sock_tx = codec::FramedWrite::new(sock_tx, RespCodec);
         let sock_rx = codec::FramedRead::new(sock_rx, RespCodec);
@@ -153,11 +178,22 @@ impl Server {
                         ctx_tx.borrow_mut().dispatch_next(context);
                         response
                     })
+                    .select(monitor_rx)
                     .map_err(|_| io::Error::from(io::ErrorKind::Other)),
             )
             .map(|_| ());
 
-        Box::new(fut_rx.select(fut_tx).map(|_| ()).map_err(|(e, _)| e))
+        let fut_kill = kill_rx.then(|_| Ok::<_, io::Error>(()));
+
+        Box::new(
+            fut_rx
+                .select(fut_tx)
+                .map(|_| ())
+                .map_err(|(e, _)| e)
+                .select(fut_kill)
+                .map(|_| ())
+                .map_err(|(e, _)| e),
+        )
     }
 
     pub fn run(self) {
@@ -179,6 +215,10 @@ impl Server {
 
         let database = Database::new(&self.config, response_fn);
 
+        if let Some(metrics_addr) = self.config.metrics_addr {
+            prometheus::start(metrics_addr, &database).expect("Error starting metrics endpoint");
+        }
+
         let context = Rc::new(SharedContext {
             db_sender: RefCell::new(database.sender()),
             database: database,
@@ -191,7 +231,7 @@ impl Server {
             tokio::net::TcpListener::bind(&self.config.listen_addr, &core.handle()).unwrap();
         let listener_fut = listener.incoming().for_each(|(socket, addr)| {
             if context.token_chans.lock().unwrap().len()
-                >= context.database.config.client_connection_max as usize
+                >= context.database.config().client_connection_max as usize
             {
                 info!(
                     "Refusing connection from {:?}, connection limit reached",
@@ -202,7 +242,7 @@ impl Server {
             info!("Token {} accepting connection from {:?}", next_token, addr);
             let conn_ctx = context.clone();
             handle.spawn(
-                Self::connection(conn_ctx, next_token, socket).then(move |r| {
+                Self::connection(conn_ctx, next_token, socket, addr).then(move |r| {
                     info!("Token {} disconnected {:?}", next_token, r);
                     Ok(())
                 }),
@@ -0,0 +1,152 @@
+use bytes::Bytes;
+use database::Context;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use types::*;
+
+// like redis, very long commands and arguments are truncated
+const SLOWLOG_MAX_ARGS: usize = 32;
+const SLOWLOG_MAX_ARG_LEN: usize = 128;
+
+pub struct SlowLogEntry {
+    pub id: u64,
+    // unix timestamp (seconds)
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub token: Token,
+    pub args: Vec<Bytes>,
+    pub consistency: Option<ConsistencyLevel>,
+    pub vnode: Option<VNodeNo>,
+    // (succesfull, total) replica replies
+    pub replicas: Option<(u8, u8)>,
+}
+
+struct Inner {
+    next_id: u64,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+/// Ring buffer of the most recent slow requests
+pub struct SlowLog {
+    max_len: usize,
+    inner: Mutex<Inner>,
+}
+
+fn truncate_args(args: &[Bytes]) -> Vec<Bytes> {
+    let mut result: Vec<Bytes> = args.iter()
+        .take(SLOWLOG_MAX_ARGS)
+        .map(|a| {
+            if a.len() > SLOWLOG_MAX_ARG_LEN {
+                let mut truncated = Vec::with_capacity(SLOWLOG_MAX_ARG_LEN + 32);
+                truncated.extend_from_slice(&a[..SLOWLOG_MAX_ARG_LEN]);
+                truncated.extend_from_slice(
+                    format!("... ({} more bytes)", a.len() - SLOWLOG_MAX_ARG_LEN).as_bytes(),
+                );
+                truncated.into()
+            } else {
+                a.clone()
+            }
+        })
+        .collect();
+    if args.len() > SLOWLOG_MAX_ARGS {
+        result.pop();
+        result.push(
+            format!("... ({} more arguments)", args.len() - SLOWLOG_MAX_ARGS + 1)
+                .as_bytes()
+                .into(),
+        );
+    }
+    result
+}
+
+impl SlowLog {
+    pub fn new(max_len: usize) -> Self {
+        SlowLog {
+            max_len: max_len,
+            inner: Mutex::new(Inner {
+                next_id: 0,
+                entries: VecDeque::with_capacity(max_len),
+            }),
+        }
+    }
+
+    pub fn push(&self, context: &Context, duration: Duration) {
+        if self.max_len == 0 {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let args = truncate_args(&context.args);
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.entries.len() >= self.max_len {
+            inner.entries.pop_back();
+        }
+        inner.entries.push_front(SlowLogEntry {
+            id: id,
+            timestamp: timestamp,
+            duration: duration,
+            token: context.token,
+            args: args,
+            consistency: context.consistency,
+            vnode: context.vnode,
+            replicas: context.replicas,
+        });
+    }
+
+    /// Calls `f` with up to `count` entries, most recent first
+    pub fn get<F: FnMut(&SlowLogEntry)>(&self, count: usize, mut f: F) {
+        for entry in self.inner.lock().unwrap().entries.iter().take(count) {
+            f(entry);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slowlog() {
+        let slowlog = SlowLog::new(2);
+        let mut context = Context::new(1);
+        for i in 0..3 {
+            context.args = vec![format!("cmd{}", i).as_bytes().into()];
+            slowlog.push(&context, Duration::from_millis(i));
+        }
+        assert_eq!(slowlog.len(), 2);
+        let mut ids = Vec::new();
+        slowlog.get(10, |e| ids.push((e.id, e.args[0].clone())));
+        assert_eq!(
+            ids,
+            vec![(2, Bytes::from(&b"cmd2"[..])), (1, Bytes::from(&b"cmd1"[..]))]
+        );
+        slowlog.reset();
+        assert_eq!(slowlog.len(), 0);
+    }
+
+    #[test]
+    fn test_truncate_args() {
+        let long: Bytes = vec![b'a'; SLOWLOG_MAX_ARG_LEN + 10].into();
+        let args = truncate_args(&[long]);
+        assert!(args[0].ends_with(b"... (10 more bytes)"));
+        let many: Vec<Bytes> = (0..SLOWLOG_MAX_ARGS + 5)
+            .map(|i| i.to_string().as_bytes().into())
+            .collect();
+        let args = truncate_args(&many);
+        assert_eq!(args.len(), SLOWLOG_MAX_ARGS);
+        assert_eq!(&args[SLOWLOG_MAX_ARGS - 1][..], b"... (6 more arguments)");
+    }
+}
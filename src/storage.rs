@@ -3,6 +3,7 @@ use rocksdb::{self, Writable};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{mem, str};
 use utils::*;
 
@@ -18,6 +19,34 @@ impl rocksdb::SliceTransform for U16BeSuffixTransform {
     }
 }
 
+// integer properties reported by StorageManager::stats
+const STATS_PROPERTIES: &[&str] = &[
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.num-running-compactions",
+    "rocksdb.num-running-flushes",
+];
+
+/// Retention of the write log, entries are dropped once older than `age`
+/// or once the log grows past `size` bytes, whatever happens first.
+#[derive(Debug, Clone, Copy)]
+pub struct LogRetention {
+    pub age: Duration,
+    pub size: u64,
+}
+
+impl Default for LogRetention {
+    fn default() -> Self {
+        LogRetention {
+            age: Duration::from_secs(3600 * 72),
+            size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
 pub struct StorageManager {
     path: PathBuf,
     db: Arc<rocksdb::DB>,
@@ -54,6 +83,7 @@ fn build_log_prefix<'a>(buffer: &'a mut [u8], num: u16, prefix: u64) -> &'a [u8]
 // TODO: support TTL
 // TODO: specific comparator for log cf
 // TODO: merge operator could be a big win
+#[derive(Clone)]
 pub struct Storage {
     db: Arc<rocksdb::DB>,
     cf: &'static rocksdb::CFHandle,
@@ -82,7 +112,10 @@ pub struct LogStorageIterator(GenericIterator);
 unsafe impl Send for GenericIterator {}
 
 impl StorageManager {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<StorageManager, GenericError> {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        log_retention: LogRetention,
+    ) -> Result<StorageManager, GenericError> {
         let mut opts = rocksdb::DBOptions::new();
         opts.create_if_missing(true);
         opts.set_max_background_jobs(4);
@@ -112,7 +145,8 @@ impl StorageManager {
         let mut log_cf_opts = rocksdb::ColumnFamilyOptions::new();
         log_cf_opts.compression(rocksdb::DBCompressionType::No);
         let mut fifo_opts = rocksdb::FifoCompactionOptions::new();
-        fifo_opts.set_ttl(3600 * 72); // 72 hours
+        fifo_opts.set_ttl(log_retention.age.as_secs());
+        fifo_opts.set_max_table_files_size(log_retention.size);
         log_cf_opts.set_fifo_compaction_options(fifo_opts);
         log_cf_opts.set_compaction_style(rocksdb::DBCompactionStyle::Fifo);
         log_cf_opts.set_write_buffer_size(32 * 1024 * 1024);
@@ -157,6 +191,21 @@ impl StorageManager {
             num: db_num,
         })
     }
+
+    /// Rocksdb integer properties as (column family, property, value)
+    pub fn stats(&self) -> Vec<(&'static str, &'static str, u64)> {
+        let mut result = Vec::new();
+        // grouped by property, as the metrics of each one are rendered together
+        for &property in STATS_PROPERTIES {
+            for &cf_name in &["default", "log"] {
+                let cf = self.db.cf_handle(cf_name).unwrap();
+                if let Some(value) = self.db.get_property_int_cf(cf, property) {
+                    result.push((cf_name, property, value));
+                }
+            }
+        }
+        result
+    }
 }
 
 impl Drop for StorageManager {
@@ -170,13 +219,18 @@ impl Drop for StorageManager {
 
 impl Storage {
     pub fn iterator(&self) -> StorageIterator {
-        let mut key_prefix = [0u8; 2];
-        build_key(&mut key_prefix, self.num, b"");
+        self.iterator_from(b"")
+    }
+
+    /// Iterator starting at `key` (inclusive), keys are iterated in byte order
+    pub fn iterator_from(&self, key: &[u8]) -> StorageIterator {
+        let mut buffer = [0u8; 512];
+        let start = build_key(&mut buffer, self.num, key);
         let mut ro = rocksdb::ReadOptions::new();
         ro.set_total_order_seek(false);
         ro.set_prefix_same_as_start(true);
         let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
+        iterator.seek(rocksdb::SeekKey::Key(start));
         StorageIterator(GenericIterator {
             db: self.db.clone(),
             iterator: iterator,
@@ -184,9 +238,45 @@ impl Storage {
         })
     }
 
+    /// Estimated number of keys and bytes, without reading the data.
+    /// Rocksdb only estimates the size of the range in the sst files,
+    /// the keys in there are estimated with the average entry size of the column family.
+    pub fn approximate_stats(&self) -> (u64, u64) {
+        let (mut start_buffer, mut end_buffer) = ([0u8; 2], [0xFFu8; 3]);
+        let start = build_key(&mut start_buffer, self.num, b"");
+        let end = match self.num.checked_add(1) {
+            Some(next) => build_key(&mut end_buffer, next, b""),
+            None => &end_buffer[..],
+        };
+        let range = rocksdb::Range::new(start, end);
+        let (mem_keys, mem_bytes) = self.db.get_approximate_memtable_stats_cf(self.cf, &range);
+        let sst_bytes = self.db.get_approximate_sizes_cf(self.cf, &[range])[0];
+        let property = |name: &str| self.db.get_property_int_cf(self.cf, name).unwrap_or(0);
+        let (cf_keys, cf_bytes) = (
+            property("rocksdb.estimate-num-keys"),
+            property("rocksdb.total-sst-files-size"),
+        );
+        let sst_keys = if cf_bytes == 0 {
+            0
+        } else {
+            (sst_bytes as f64 * cf_keys as f64 / cf_bytes as f64) as u64
+        };
+        (mem_keys + sst_keys, mem_bytes + sst_bytes)
+    }
+
+    /// Key prefix of the storage, see `StorageManager::open`
+    pub fn num(&self) -> u16 {
+        self.num
+    }
+
     pub fn log_iterator_all(&self) -> LogStorageIterator {
-        let mut key_prefix = [0u8; 2];
-        build_key(&mut key_prefix, self.num, b"");
+        self.log_iterator_from((0, 0))
+    }
+
+    /// Iterator over the log of all ids, starting at `start` (inclusive)
+    pub fn log_iterator_from(&self, start: (u64, u64)) -> LogStorageIterator {
+        let mut key_prefix = [0u8; 2 + 8 + 8];
+        build_log_key(&mut key_prefix, self.num, start);
         let mut end_prefix = [0u8; 2];
         build_key(&mut end_prefix, self.num + 1, b"");
         let mut ro = rocksdb::ReadOptions::new();
@@ -334,6 +424,21 @@ impl<'a> StorageBatch<'a> {
         let buffer = build_key(&mut buffer, self.storage.num, key);
         self.wb.delete_cf(self.storage.cf, buffer).unwrap()
     }
+
+    pub fn log_del(&mut self, key: (u64, u64)) {
+        trace!("log_del {:?}", key);
+        let mut buffer = [0u8; 2 + 8 + 8];
+        let buffer = build_log_key(&mut buffer, self.storage.num, key);
+        self.wb.delete_cf(self.storage.log_cf, buffer).unwrap()
+    }
+
+    /// Deletes the key from another storage, atomically with the rest of the batch
+    pub fn del_from(&mut self, storage: &Storage, key: &[u8]) {
+        trace!("del_from {} {:?}", storage.num, str::from_utf8(key));
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, storage.num, key);
+        self.wb.delete_cf(storage.cf, buffer).unwrap()
+    }
 }
 
 impl GenericIterator {
@@ -417,7 +522,7 @@ mod tests {
     #[test]
     fn test_simple() {
         let _ = fs::remove_dir_all("t/test_simple");
-        let sm = StorageManager::new("t/test_simple").unwrap();
+        let sm = StorageManager::new("t/test_simple", Default::default()).unwrap();
         let storage = sm.open(1).unwrap();
         assert_eq!(storage.get_vec(b"sample").unwrap(), None);
         storage.set(b"sample", b"sample_value").unwrap();
@@ -432,7 +537,7 @@ mod tests {
     #[test]
     fn test_simple_log() {
         let _ = fs::remove_dir_all("t/test_simple_log");
-        let sm = StorageManager::new("t/test_simple_log").unwrap();
+        let sm = StorageManager::new("t/test_simple_log", Default::default()).unwrap();
         let storage = sm.open(1).unwrap();
         assert_eq!(storage.get_vec(b"sample").unwrap(), None);
         let mut b = storage.batch_new(0);
@@ -449,7 +554,7 @@ mod tests {
     #[test]
     fn test_iter() {
         let _ = fs::remove_dir_all("t/test_iter");
-        let sm = StorageManager::new("t/test_iter").unwrap();
+        let sm = StorageManager::new("t/test_iter", Default::default()).unwrap();
         for &i in &[0, 1, 2] {
             let storage = sm.open(i).unwrap();
             storage.set(b"1", i.to_string().as_bytes()).unwrap();
@@ -463,10 +568,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_iter_from() {
+        let _ = fs::remove_dir_all("t/test_iter_from");
+        let sm = StorageManager::new("t/test_iter_from", Default::default()).unwrap();
+        for &i in &[0, 1] {
+            let storage = sm.open(i).unwrap();
+            for &key in &[b"a", b"b", b"c"] {
+                storage.set(key, i.to_string().as_bytes()).unwrap();
+            }
+        }
+        let storage = sm.open(0).unwrap();
+        let keys = |start: &[u8]| -> Vec<Vec<u8>> {
+            storage.iterator_from(start).iter().map(|(k, _)| k.into()).collect()
+        };
+        assert_eq!(keys(b"b"), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(keys(b"bb"), vec![b"c".to_vec()]);
+        assert_eq!(keys(b"d"), Vec::<Vec<u8>>::new());
+    }
+
     #[test]
     fn test_iter_log() {
         let _ = fs::remove_dir_all("t/test_iter_log");
-        let sm = StorageManager::new("t/test_iter_log").unwrap();
+        let sm = StorageManager::new("t/test_iter_log", Default::default()).unwrap();
         for &i in &[0u64, 1, 2] {
             let storage = sm.open(i as u16).unwrap();
             let mut b = storage.batch_new(0);
@@ -495,10 +619,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_iter_log_from() {
+        let _ = fs::remove_dir_all("t/test_iter_log_from");
+        let sm = StorageManager::new("t/test_iter_log_from", Default::default()).unwrap();
+        for &i in &[1u16, 2] {
+            let storage = sm.open(i).unwrap();
+            let mut b = storage.batch_new(0);
+            for &log_key in &[(1, 1), (1, 2), (2, 1)] {
+                b.log_set(log_key, b"");
+            }
+            storage.batch_write(b).unwrap();
+        }
+        let storage = sm.open(1).unwrap();
+        let log_keys = |start| -> Vec<(u64, u64)> {
+            storage.log_iterator_from(start).iter().map(|(k, _)| k).collect()
+        };
+        assert_eq!(log_keys((0, 0)), vec![(1, 1), (1, 2), (2, 1)]);
+        assert_eq!(log_keys((1, 2)), vec![(1, 2), (2, 1)]);
+        assert_eq!(log_keys((2, 2)), vec![]);
+    }
+
+    #[test]
+    fn test_del_from() {
+        let _ = fs::remove_dir_all("t/test_del_from");
+        let sm = StorageManager::new("t/test_del_from", Default::default()).unwrap();
+        let (storage1, storage2) = (sm.open(1).unwrap(), sm.open(2).unwrap());
+        storage2.set(b"key", b"old").unwrap();
+        let mut b = storage1.batch_new(0);
+        b.set(b"key", b"new");
+        b.del_from(&storage2, b"key");
+        storage1.batch_write(b).unwrap();
+        assert_eq!(storage1.get_vec(b"key").unwrap().unwrap(), b"new");
+        assert_eq!(storage2.get_vec(b"key").unwrap(), None);
+    }
+
     #[test]
     fn test_clear() {
         let _ = fs::remove_dir_all("t/test_clear");
-        let sm = StorageManager::new("t/test_clear").unwrap();
+        let sm = StorageManager::new("t/test_clear", Default::default()).unwrap();
         for &i in &[0u64, 1, 2] {
             let storage = sm.open(i as u16).unwrap();
             let mut b = storage.batch_new(0);
@@ -519,7 +678,7 @@ mod tests {
     #[test]
     fn test_open_all() {
         let _ = fs::remove_dir_all("t/test_open_all");
-        let sm = StorageManager::new("t/test_open_all").unwrap();
+        let sm = StorageManager::new("t/test_open_all", Default::default()).unwrap();
         sm.open(1).unwrap();
         sm.open(2).unwrap();
         sm.open(3).unwrap();
@@ -0,0 +1,96 @@
+use std::f64;
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket limiting a rate (eg. bytes per second), holding up to a second worth of tokens.
+/// The rate is given on each call so it can change at runtime, 0 means unlimited.
+/// Consuming can put the bucket in debt, so big items still go through
+/// but delay the following ones.
+pub struct TokenBucket {
+    bucket: Mutex<Bucket>,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        TokenBucket {
+            bucket: Mutex::new(Bucket {
+                // starts full, whatever the rate
+                tokens: f64::INFINITY,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+}
+
+impl TokenBucket {
+    /// Whether there are tokens available
+    pub fn is_ready(&self, rate: u64) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        bucket.tokens > 0.0
+    }
+
+    /// Takes `amount` tokens, even if there aren't enough of them
+    pub fn consume(&self, rate: u64, amount: u64) {
+        if rate == 0 {
+            return;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        bucket.tokens -= amount as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // pretends some time went by, instead of sleeping
+    fn advance(bucket: &TokenBucket, ms: u64) {
+        bucket.bucket.lock().unwrap().last_refill -= Duration::from_millis(ms);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let bucket = TokenBucket::default();
+        bucket.consume(0, u64::max_value());
+        assert!(bucket.is_ready(0));
+    }
+
+    #[test]
+    fn test_rate() {
+        let bucket = TokenBucket::default();
+        assert!(bucket.is_ready(1_000_000));
+        bucket.consume(1_000_000, 1_500_000);
+        assert!(!bucket.is_ready(1_000_000));
+        advance(&bucket, 400);
+        assert!(!bucket.is_ready(1_000_000));
+        advance(&bucket, 200);
+        assert!(bucket.is_ready(1_000_000));
+        // a higher rate refills faster
+        bucket.consume(1_000_000, 2_000_000);
+        advance(&bucket, 100);
+        assert!(!bucket.is_ready(1_000_000));
+        advance(&bucket, 100);
+        assert!(bucket.is_ready(100_000_000));
+    }
+}
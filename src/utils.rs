@@ -76,6 +76,20 @@ pub fn is_dir_empty_or_absent<P: AsRef<path::Path>>(path: P) -> io::Result<bool>
     }
 }
 
+/// Redis style glob matching, supporting `*` and `?`
+pub fn glob_match(pattern: &[u8], subject: &[u8]) -> bool {
+    match (pattern.first(), subject.first()) {
+        (None, None) => true,
+        (Some(&b'*'), _) => {
+            glob_match(&pattern[1..], subject)
+                || (!subject.is_empty() && glob_match(pattern, &subject[1..]))
+        }
+        (Some(&b'?'), Some(_)) => glob_match(&pattern[1..], &subject[1..]),
+        (Some(p), Some(s)) if p == s => glob_match(&pattern[1..], &subject[1..]),
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 pub fn sleep_ms(ms: u64) {
     ::std::thread::sleep(::std::time::Duration::from_millis(ms));
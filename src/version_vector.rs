@@ -125,12 +125,17 @@ impl BitmappedVersion {
         self.base >= version
     }
 
+    /// Highest version seen
+    pub fn max(&self) -> Version {
+        cmp::max(self.bitmap.max().unwrap_or(0), self.base)
+    }
+
     /// self - other
     pub fn delta(&self, other: &Self) -> BitmappedVersionDelta {
         if self.base < other.base {
             return Default::default();
         }
-        let last_version = cmp::max(self.bitmap.max().unwrap_or(0), self.base);
+        let last_version = self.max();
         BitmappedVersionDelta {
             from: other.clone(),
             to: self.clone(),
@@ -351,6 +356,10 @@ impl BitmappedVersionVector {
         self.0.iter()
     }
 
+    pub fn remove(&mut self, id: Id) -> Option<BitmappedVersion> {
+        self.0.remove(&id)
+    }
+
     pub fn clone_if<F: FnMut(Id) -> bool>(&self, mut cond: F) -> Self {
         let mut result = Self::default();
         for (&id, bv) in self.0.iter() {
@@ -361,6 +370,28 @@ impl BitmappedVersionVector {
         result
     }
 
+    /// Whether all versions in other are also in self
+    pub fn covers(&self, other: &Self) -> bool {
+        other.0.iter().all(|(&id, bv)| {
+            (bv.base == 0 || self.contains_all(id, bv.base))
+                && bv.bitmap.iter().all(|v| self.contains(id, v))
+        })
+    }
+
+    /// Versions known by both, conservatively only the contiguous ones (the bases)
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut result = Self::default();
+        for (&id, bv) in self.0.iter() {
+            if let Some(other_bv) = other.get(id) {
+                let base = cmp::min(bv.base(), other_bv.base());
+                if base != 0 {
+                    result.add_bv(id, &BitmappedVersion::new(base, 0));
+                }
+            }
+        }
+        result
+    }
+
     pub fn delta(&self, other: &Self) -> BitmappedVersionVectorDelta {
         let min_versions: Vec<_> = self.0
             .iter()
@@ -626,6 +657,10 @@ impl VersionVector {
         self.0.iter().map(|(&i, &v)| (i, v))
     }
 
+    pub fn retain<F: FnMut(Id, Version) -> bool>(&mut self, mut cond: F) {
+        self.0.retain(|&i, &mut v| cond(i, v));
+    }
+
     pub fn contained(&self, bvv: &BitmappedVersionVector) -> bool {
         self.0.iter().all(|(&i, &v)| bvv.contains_all(i, v))
     }
@@ -647,6 +682,10 @@ impl DotSet {
         result.0.insert(dot);
         result
     }
+
+    pub fn iter<'a>(&'a self) -> impl 'a + Iterator<Item = (Id, Version)> {
+        self.0.iter().cloned()
+    }
 }
 
 impl CausalValue for DotSet {
@@ -906,6 +945,40 @@ mod test_bvv {
         assert_eq!(a.get(2).unwrap(), &BitmappedVersion::new(2, 4));
     }
 
+    #[test]
+    fn covers() {
+        let mut a = BitmappedVersionVector::new();
+        a.0.insert(1, BitmappedVersion::new(5, 0b10));
+        a.0.insert(2, BitmappedVersion::new(0, 0));
+        let mut b = BitmappedVersionVector::new();
+        b.0.insert(1, BitmappedVersion::new(3, 0));
+        assert!(a.covers(&b));
+        assert!(!b.covers(&a));
+        b.0.insert(1, BitmappedVersion::new(7, 0));
+        assert!(b.covers(&a));
+        a.0.insert(3, BitmappedVersion::new(1, 0));
+        assert!(!b.covers(&a));
+        assert!(a.covers(&BitmappedVersionVector::new()));
+    }
+
+    #[test]
+    fn intersection() {
+        let mut a = BitmappedVersionVector::new();
+        a.0.insert(1, BitmappedVersion::new(5, 0b10));
+        a.0.insert(2, BitmappedVersion::new(2, 0));
+        a.0.insert(3, BitmappedVersion::new(1, 0));
+        let mut b = BitmappedVersionVector::new();
+        b.0.insert(1, BitmappedVersion::new(7, 0));
+        b.0.insert(2, BitmappedVersion::new(0, 0b1));
+        b.0.insert(4, BitmappedVersion::new(1, 0));
+        let c = a.intersection(&b);
+        assert_eq!(c.get(1).unwrap(), &BitmappedVersion::new(5, 0));
+        assert!(c.get(2).is_none());
+        assert!(c.get(3).is_none());
+        assert!(c.get(4).is_none());
+        assert_eq!(b.intersection(&a), c);
+    }
+
     #[test]
     fn event() {
         let mut a = BitmappedVersionVector::new();
@@ -4,10 +4,13 @@ use command::CommandError;
 use cubes::*;
 use database::*;
 use fabric::*;
-use hash::hash_slot;
+use hash::{hash_slot, slot_vnode};
 use inflightmap::InFlightMap;
+use metrics::{self, Meter};
 use rand::{thread_rng, Rng};
+use std::borrow::Cow;
 use std::collections::hash_map::Entry as HMEntry;
+use std::iter;
 use std::time::{Duration, Instant};
 use storage::*;
 use utils::{join_u64, split_u64};
@@ -16,6 +19,30 @@ use version_vector::*;
 use vnode_sync::*;
 
 const ZOMBIE_TIMEOUT_MS: u64 = 60 * 1_000;
+const ZOMBIE_PUSH_INTERVAL_MS: u64 = 5 * 1_000;
+// zombies stop waiting for owners that are down after this, if another owner has the data
+const ZOMBIE_HANDOFF_MAX_MS: u64 = 60 * 60 * 1_000;
+const LOG_WATERMARK_INTERVAL_MS: u64 = 60 * 1_000;
+// pause between full passes deleting the keys subsumed by the clocks
+const SWEEP_INTERVAL_MS: u64 = 60 * 1_000;
+// keys checked per tick while sweeping
+const SWEEP_BATCH_SIZE: usize = 1_000;
+// entries checked per tick while moving the data of a split
+const SPLIT_BATCH_SIZE: usize = 1_000;
+/// Storage generations the vnode storage prefixes rotate through, see `storage_prefix`
+pub const STORAGE_GENERATIONS: usize = 3;
+
+/// Storage prefix of the vnode `num` in the storage generation `gen`. Each split moves
+/// to the next generation, so the halves never share a prefix with a vnode of the previous
+/// partitioning that may still hold their entries.
+pub fn storage_prefix(num: u16, gen: usize) -> u16 {
+    num | (gen as u16) << 14
+}
+
+// meta storage key of the split sources of a vnode
+fn split_key(num: u16) -> String {
+    format!("{}_split", num)
+}
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum VNodeStatus {
@@ -26,7 +53,7 @@ pub enum VNodeStatus {
     // streaming data from another node, can only accept replicated writes in this state
     Bootstrap,
     // another node took over this vnode, this will stay in zombie until it times out
-    // and syncs are completed, etc.
+    // and the owners confirm having all its data, etc.
     Zombie,
     // no actual data is present
     Absent,
@@ -36,6 +63,38 @@ pub struct VNode {
     state: VNodeState,
     syncs: IdHashMap<Cookie, Synchronization>,
     requests: InFlightMap<Cookie, ReqState, Instant, IdHasherBuilder>,
+    last_log_check: Instant,
+    handoff: Handoff,
+    // where the ongoing sweep continues from
+    sweep_cursor: Option<Bytes>,
+    last_sweep: Instant,
+    // where moving the entries of the oldest split source continues from
+    split_cursor: Option<SplitCursor>,
+}
+
+// storage of a vnode this one was split from
+struct SplitSource {
+    storage: Storage,
+    // whether the entries are still valid, otherwise they are just dropped
+    keep: bool,
+}
+
+#[derive(Debug)]
+enum SplitCursor {
+    Entries(Bytes),
+    Log((u64, u64)),
+}
+
+// progress of a zombie handing off its data to the owners
+#[derive(Default)]
+struct Handoff {
+    // owners with clocks covering the local ones
+    done: IdHashSet<NodeId>,
+    // owners that need a hash tree sync, as a regular one didn't cover everything
+    tree: IdHashSet<NodeId>,
+    last_push: Option<Instant>,
+    // whether it was logged as taking longer than the zombie timeout
+    stuck: bool,
 }
 
 pub struct VNodeState {
@@ -45,24 +104,85 @@ pub struct VNodeState {
     last_status_change: Instant,
     pub clocks: BitmappedVersionVector,
     pub storage: Storage,
+    // the log has no entries below these versions (for ids coordinated by this node)
+    pub log_watermark: IdHashMap<NodeId, Version>,
+    // retired ids (from before a dirty restart) that all replicas have seen up to these versions,
+    // dropped from the clocks and the stored cubes
+    pub pruned: IdHashMap<Id, Version>,
+    // replicas known to have seen all versions of the retired ids of this node
+    retired_seen: IdHashMap<Id, IdHashSet<NodeId>>,
+    pub bootstrap_checkpoint: BootstrapCheckpoint,
+    // keys received by bootstraps since loaded (resends included)
+    pub bootstrap_received: u64,
     // state for syncs
     pub pending_bootstrap: bool,
     pub sync_nodes: IdHashSet<NodeId>,
+    // storages of the vnodes this one was split from (most recent first) that still have
+    // some of its entries, see `VNode::split`
+    split_sources: Vec<SplitSource>,
+    // partition count of the last split, to tell which entries belong to this vnode
+    split_partitions: usize,
+}
+
+// saved separately from the state, under `split_key`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedSplit {
+    // storage prefixes and whether their entries are kept
+    sources: Vec<(u16, bool)>,
+    partitions: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SavedVNodeState {
     id: NodeId,
     clocks: BitmappedVersionVector,
+    log_watermark: IdHashMap<NodeId, Version>,
+    pruned: IdHashMap<Id, Version>,
+    bootstrap_checkpoint: BootstrapCheckpoint,
     clean_shutdown: bool,
 }
 
+/// Counts the keys of a slot, see `VNode::slot_counter`
+pub struct SlotCounter {
+    storage: Storage,
+    clocks: BitmappedVersionVector,
+    pruned: IdHashMap<Id, Version>,
+}
+
+impl SlotCounter {
+    /// Number of keys in the slot, not counting the ones subsumed by the clocks
+    /// (which are as good as deleted)
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        let mut iterator = self.storage.iterator();
+        let count = iterator
+            .iter()
+            .filter(|&(k, v)| hash_slot(k) == slot && !self.is_subsumed(v))
+            .count();
+        count
+    }
+
+    fn is_subsumed(&self, value: &[u8]) -> bool {
+        match bincode::deserialize::<Cube>(value) {
+            Ok(mut cube) => {
+                cube.prune(&self.pruned);
+                cube.is_subsumed(&self.clocks)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
 struct ReqState {
     replies: u8,
     succesfull: u8,
     required: u8,
     total: u8,
     context: Context,
+    sent: Instant,
+    // remote nodes that were sent the request and didn't reply yet
+    pending: Vec<NodeId>,
+    // for single replica reads, the request and the replicas not asked yet
+    fallback: Option<(MsgRemoteGet, Vec<NodeId>)>,
 }
 
 #[cfg(test)]
@@ -115,6 +235,9 @@ impl ReqState {
             replies: 0,
             succesfull: 0,
             context,
+            sent: Instant::now(),
+            pending: Vec::new(),
+            fallback: None,
         }
     }
 
@@ -125,35 +248,116 @@ impl ReqState {
     fn satisfied(&self) -> bool {
         self.succesfull >= self.required
     }
+
+    // returns the context annotated with the replica replies
+    fn into_context(self) -> Context {
+        let mut context = self.context;
+        context.replicas = Some((self.succesfull, self.total));
+        context
+    }
 }
 
 impl VNode {
     pub fn new(db: &Database, num: u16, status: VNodeStatus) -> VNode {
         let state = VNodeState::load(num, db, status);
         state.save(db, false);
+        Self::with_state(db, state)
+    }
 
+    fn with_state(db: &Database, state: VNodeState) -> VNode {
         let mut vnode = VNode {
             state: state,
             requests: InFlightMap::new(),
             syncs: Default::default(),
+            last_log_check: Instant::now(),
+            handoff: Default::default(),
+            sweep_cursor: None,
+            last_sweep: Instant::now(),
+            split_cursor: None,
         };
 
         match vnode.status() {
-            VNodeStatus::Ready | VNodeStatus::Absent => (),
+            VNodeStatus::Ready | VNodeStatus::Absent | VNodeStatus::Zombie => (),
             VNodeStatus::Bootstrap => {
                 // mark pending if it doesn't start
                 vnode.start_bootstrap(db);
             }
-            status => panic!("{:?} isn't a valid state after load", status),
         }
 
         vnode
     }
 
+    /// Splits the vnode into the vnodes `2 * num` and `2 * num + 1` of the doubled
+    /// partition count, with the storages of the generation `gen`. Both halves inherit
+    /// the clocks, those are still correct as each half simply stops seeing the keys of
+    /// the other. No data is moved here, the halves read through to the storage of this
+    /// vnode and move their entries in the background (see `split_tick`).
+    /// Data being bootstrapped is discarded and the halves bootstrap again.
+    /// The halves aren't saved, that's up to the caller.
+    pub fn split(mut self, db: &Database, partitions: usize, gen: usize) -> (VNode, VNode) {
+        info!("Splitting vnode {}", self.state.num);
+        // coordinators and peers will retry with the new partitioning
+        self.abort_inflight(db);
+
+        let state = &self.state;
+        let keep = state.status == VNodeStatus::Ready || state.status == VNodeStatus::Zombie;
+        let mut halves = [state.num * 2, state.num * 2 + 1].iter().map(|&num| {
+            let storage = db.storage_manager
+                .open(storage_prefix(num, gen))
+                .expect("Can't open storage");
+            let mut half = VNodeState::empty(num, storage, db, state.status);
+            if keep {
+                half.last_status_change = state.last_status_change;
+                half.clocks = state.clocks.clone();
+                half.log_watermark = state.log_watermark.clone();
+                half.pruned = state.pruned.clone();
+            }
+            half.split_sources = iter::once((&state.storage, keep))
+                .chain(state.split_sources.iter().map(|s| (&s.storage, s.keep)))
+                .map(|(storage, k)| SplitSource {
+                    storage: storage.clone(),
+                    keep: keep && k,
+                })
+                .collect();
+            half.split_partitions = partitions * 2;
+            VNode::with_state(db, half)
+        });
+        (halves.next().unwrap(), halves.next().unwrap())
+    }
+
+    fn abort_inflight(&mut self, db: &Database) {
+        for (_, req) in self.requests.drain() {
+            let mut context = req.into_context();
+            context.clear();
+            db.respond_error(&mut context, CommandError::Unavailable);
+        }
+        let state = &mut self.state;
+        for (_, mut sync) in self.syncs.drain() {
+            sync.on_cancel(db, state);
+            sync.on_remove(db, state);
+        }
+    }
+
     pub fn save(&mut self, db: &Database, shutdown: bool) {
         self.state.save(db, shutdown);
     }
 
+    pub fn save_into(&self, batch: &mut StorageBatch) {
+        self.state.save_into(batch, false);
+    }
+
+    /// Number of split sources with entries not moved yet
+    pub fn split_sources(&self) -> usize {
+        self.state.split_sources.len()
+    }
+
+    /// Moves the entries of the oldest split sources until at most `max` remain
+    pub fn finish_split(&mut self, db: &Database, max: usize) {
+        while self.state.split_sources.len() > max {
+            self.split_tick(db);
+        }
+    }
+
     pub fn status(&self) -> VNodeStatus {
         self.state.status
     }
@@ -181,6 +385,27 @@ impl VNode {
             })
     }
 
+    /// Estimated number of keys and bytes stored, None if the vnode isn't held by this node
+    pub fn storage_stats(&self) -> Option<(u64, u64)> {
+        if self.status() == VNodeStatus::Absent {
+            return None;
+        }
+        Some(self.state.storage.approximate_stats())
+    }
+
+    /// What's needed to count the keys of a slot, so the vnode isn't held while scanning
+    pub fn slot_counter(&self) -> SlotCounter {
+        SlotCounter {
+            storage: self.state.storage.clone(),
+            clocks: self.state.clocks.clone(),
+            pruned: self.state.pruned.clone(),
+        }
+    }
+
+    pub fn syncs_progress(&self) -> Vec<SyncProgress> {
+        self.syncs.values().map(|s| s.progress()).collect()
+    }
+
     fn gen_cookie(&self) -> Cookie {
         let mut rng = thread_rng();
         Cookie::new(rng.gen(), rng.gen())
@@ -219,6 +444,7 @@ impl VNode {
                 let new_status = if status == VNodeStatus::Bootstrap {
                     VNodeStatus::Absent
                 } else {
+                    self.handoff = Default::default();
                     VNodeStatus::Zombie
                 };
 
@@ -260,23 +486,60 @@ impl VNode {
                 .collect::<Vec<_>>()
         };
         for (cookie, result) in terminated_syncs {
-            self.syncs
-                .remove(&cookie)
-                .unwrap()
-                .on_remove(db, &mut self.state);
+            let sync = self.syncs.remove(&cookie).unwrap();
+            let peer = sync.peer();
+            sync.on_remove(db, &mut self.state);
             if self.status() == VNodeStatus::Bootstrap {
-                self.handle_bootstrap_result(db, result);
+                self.handle_bootstrap_result(db, result, peer);
             }
         }
 
+        // single replica reads also ask the next replica if there's no reply after half a
+        // worker tick, as this is only checked on ticks it happens within 0.5-1.5 ticks
+        let hedge_delay = Duration::from_millis(db.config().worker_timer as u64 / 2);
+        let slow_gets: Vec<Cookie> = self.requests
+            .iter()
+            .filter(|&(_, req)| {
+                req.fallback.is_some() && req.total == 1 && req.replies == 0
+                    && req.sent.elapsed() > hedge_delay
+            })
+            .map(|(&cookie, _)| cookie)
+            .collect();
+        for cookie in slow_gets {
+            self.hedge_get(db, cookie);
+        }
+
         let now = Instant::now();
-        while let Some((cookie, mut req)) = self.requests.pop_expired(now) {
+        while let Some((cookie, req)) = self.requests.pop_expired(now) {
             debug!(
                 "Request cookie:{:?} token:{} timed out",
                 cookie, req.context.token
             );
-            req.context.clear();
-            db.respond_error(&mut req.context, CommandError::Timeout);
+            for &node in &req.pending {
+                db.health.record_timeout(node);
+            }
+            let mut context = req.into_context();
+            context.clear();
+            db.respond_error(&mut context, CommandError::Timeout);
+        }
+
+        if self.status() == VNodeStatus::Ready
+            && self.last_log_check.elapsed() > Duration::from_millis(LOG_WATERMARK_INTERVAL_MS)
+        {
+            self.last_log_check = Instant::now();
+            self.update_log_watermark(db);
+        }
+
+        if !self.state.split_sources.is_empty() {
+            self.split_tick(db);
+        }
+
+        // while splitting a missing key can still be read from the split sources
+        if self.status() == VNodeStatus::Ready && !self.state.is_splitting()
+            && (self.sweep_cursor.is_some()
+                || self.last_sweep.elapsed() > Duration::from_millis(SWEEP_INTERVAL_MS))
+        {
+            self.sweep_tick();
         }
 
         if self.state.pending_bootstrap {
@@ -284,10 +547,89 @@ impl VNode {
             self.start_bootstrap(db);
         } else if self.status() == VNodeStatus::Zombie && self.requests.is_empty()
             && self.syncs.is_empty()
-            && self.state.last_status_change.elapsed() > Duration::from_millis(ZOMBIE_TIMEOUT_MS)
         {
-            // go absent when zombie timeout
-            self.state.set_status(db, VNodeStatus::Absent);
+            self.handoff_tick(db);
+        }
+    }
+
+    // deletes the keys subsumed by the clocks (eg. tombstones written before all the dots
+    // they cover were seen) a batch per tick, the data isn't needed as missing keys
+    // resolve to the clocks anyway
+    fn sweep_tick(&mut self) {
+        let start = self.sweep_cursor.take().unwrap_or_else(Bytes::new);
+        let (_, next) = self.state.sweep_subsumed(&start, SWEEP_BATCH_SIZE);
+        if next.is_none() {
+            debug!("VNode {} sweep done", self.state.num);
+            self.last_sweep = Instant::now();
+        }
+        self.sweep_cursor = next;
+    }
+
+    // moves a batch of the entries of the oldest split source per tick
+    fn split_tick(&mut self, db: &Database) {
+        let cursor = self.split_cursor
+            .take()
+            .unwrap_or_else(|| SplitCursor::Entries(Bytes::new()));
+        self.split_cursor = self.state.split_step(db, cursor, SPLIT_BATCH_SIZE);
+    }
+
+    // owners that didn't confirm having all the zombie data yet
+    fn handoff_pending(&self, db: &Database) -> Vec<NodeId> {
+        let mut owners = db.dht.nodes_for_vnode(self.state.num, false, false);
+        owners.retain(|n| *n != db.dht.node() && !self.handoff.done.contains(n));
+        owners
+    }
+
+    // zombies push their data to the owners (which pull it with a regular sync)
+    // and go absent once all of them have it and the timeout passed,
+    // the owners are checked on every tick so ring changes are taken into account
+    fn handoff_tick(&mut self, db: &Database) {
+        let mut pending = self.handoff_pending(db);
+        let timeout = Duration::from_millis(ZOMBIE_TIMEOUT_MS);
+        let elapsed = self.state.last_status_change.elapsed();
+        if !pending.is_empty() && elapsed > Duration::from_millis(ZOMBIE_HANDOFF_MAX_MS) {
+            let handed_off = db.dht
+                .nodes_for_vnode(self.state.num, false, false)
+                .iter()
+                .any(|n| self.handoff.done.contains(n));
+            if handed_off {
+                // the owners that are down get the data from the others once back
+                pending.retain(|&n| db.is_node_healthy(n));
+            }
+        }
+        if pending.is_empty() {
+            if elapsed > timeout {
+                // go absent when zombie timeout
+                self.state.set_status(db, VNodeStatus::Absent);
+            }
+            return;
+        }
+
+        let push_interval = Duration::from_millis(ZOMBIE_PUSH_INTERVAL_MS);
+        if self.handoff.last_push.map_or(false, |t| t.elapsed() < push_interval) {
+            return;
+        }
+        self.handoff.last_push = Some(Instant::now());
+        if elapsed > timeout {
+            metrics::HANDOFF_STUCK.mark(1);
+            if !self.handoff.stuck {
+                self.handoff.stuck = true;
+                warn!(
+                    "Zombie vn:{} still handing off to {:?} after {:?}",
+                    self.state.num, pending, elapsed
+                );
+            }
+        }
+        for node in pending {
+            let tree = self.handoff.tree.contains(&node);
+            debug!("Zombie vn:{} pushing to {} tree:{}", self.state.num, node, tree);
+            let _ = db.fabric.send_msg(
+                node,
+                &MsgSyncPush {
+                    vnode: self.state.num,
+                    tree: tree,
+                },
+            );
         }
     }
 
@@ -306,14 +648,16 @@ impl VNode {
             context.token,
             consistency
         );
-        let nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
+        let mut nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         if nodes.is_empty() {
             debug!("vnode:{:?} no nodes", self.state.num());
             return Err(CommandError::Unavailable);
         }
         let participate = nodes.contains(&db.dht.node());
+        // a single reply is enough, so only the preferred remote replica is asked
+        let single = consistency == ConsistencyLevel::One && !participate;
         let cookie = self.gen_cookie();
-        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
+        let expire = Instant::now() + Duration::from_millis(db.config().request_timeout as _);
 
         let mut response_fn = Some(response_fn);
         for key in keys {
@@ -328,7 +672,11 @@ impl VNode {
             });
         }
 
-        let req = ReqState::new(replace_default(context), nodes.len(), consistency);
+        context.consistency = Some(consistency);
+        context.vnode = Some(self.state.num);
+        // in single mode the replicas are counted as they're asked
+        let total = if single { 0 } else { nodes.len() };
+        let req = ReqState::new(replace_default(context), total, consistency);
         self.requests.insert(cookie, req, expire);
 
         if participate {
@@ -343,16 +691,53 @@ impl VNode {
             vnode: self.state.num,
             keys: keys.iter().map(|&x| x.clone()).collect(),
         };
+        db.sort_by_health(&mut nodes);
+        if single {
+            if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
+                o.get_mut().fallback = Some((msg, nodes));
+            }
+            if !self.hedge_get(db, cookie) {
+                // none of the replicas could be reached
+                if let Some(req) = self.requests.remove(&cookie) {
+                    let mut context = req.into_context();
+                    context.clear();
+                    db.respond_error(&mut context, CommandError::Unavailable);
+                }
+            }
+            return Ok(());
+        }
         for node in nodes {
-            if node != db.dht.node() {
-                if let Err(err) = db.fabric.send_msg(node, &msg) {
-                    if self.process_get::<Option<_>>(db, cookie, Err(err)) {
-                        return Ok(());
+            if node == db.dht.node() {
+                continue;
+            }
+            match Self::send_to_replica(db, node, &msg) {
+                Ok(()) => self.add_pending(cookie, node),
+                Err(err) => if self.process_get::<Option<_>>(db, cookie, Err(err)) {
+                    return Ok(());
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // asks the next reachable replica of a single replica read, when the previous one
+    // failed or is slow, returns whether one was asked
+    fn hedge_get(&mut self, db: &Database, cookie: Cookie) -> bool {
+        if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
+            let req = o.get_mut();
+            if let Some((ref msg, ref mut nodes)) = req.fallback {
+                while !nodes.is_empty() {
+                    let node = nodes.remove(0);
+                    if Self::send_to_replica(db, node, msg).is_ok() {
+                        debug!("Asking replica {} for get {:?}", node, cookie);
+                        req.total += 1;
+                        req.pending.push(node);
+                        return true;
                     }
                 }
             }
         }
-        Ok(())
+        false
     }
 
     fn respond_cant_coordinate(
@@ -363,8 +748,11 @@ impl VNode {
     ) {
         let hash_slot = hash_slot(&context.writes[0].key);
         let mut nodes = db.dht.nodes_for_vnode_ex(self.state.num(), true, false);
+        // spread the redirects among the healthy nodes
         thread_rng().shuffle(&mut nodes);
-        for (node, (_, addr)) in nodes {
+        nodes.sort_by_key(|&(node, _)| !db.is_node_healthy(node));
+        for (node, (_, meta)) in nodes {
+            let addr = meta.addr;
             if node != db.dht.node() {
                 match status {
                     VNodeStatus::Absent | VNodeStatus::Zombie => {
@@ -424,7 +812,7 @@ impl VNode {
         }
 
         let cookie = self.gen_cookie();
-        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
+        let expire = Instant::now() + Duration::from_millis(db.config().request_timeout as _);
         let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
 
         match self.state.storage_set_local(
@@ -453,16 +841,22 @@ impl VNode {
         };
 
         // 2. create reqstate, note that writes have have nil cubes at this point
+        context.consistency = Some(consistency);
+        context.vnode = Some(self.state.num);
         let req = ReqState::new(replace_default(context), nodes.len(), consistency);
         self.requests.insert(cookie, req, expire);
 
         // 3. send the msgs
         for &node in &nodes {
             if node != db.dht.node() {
-                if let Err(err) = db.fabric.send_msg(node, &msg) {
-                    if self.process_set::<Option<_>>(db, cookie, Err(err)) {
+                match Self::send_to_replica(db, node, &msg) {
+                    // without replies there's nothing to wait for
+                    Ok(()) => if msg.reply {
+                        self.add_pending(cookie, node);
+                    },
+                    Err(err) => if self.process_set::<Option<_>>(db, cookie, Err(err)) {
                         return Ok(());
-                    }
+                    },
                 }
             }
         }
@@ -474,6 +868,36 @@ impl VNode {
     }
 
     // OTHER
+    // replicas known to be down are skipped and count as failed replies right away
+    fn send_to_replica<'a, T: Into<FabricMsgRef<'a>>>(
+        db: &'a Database,
+        node: NodeId,
+        msg: T,
+    ) -> Result<(), FabricError> {
+        if db.is_node_healthy(node) {
+            db.fabric.send_msg(node, msg)
+        } else {
+            Err(FabricError::NoRoute)
+        }
+    }
+
+    fn add_pending(&mut self, cookie: Cookie, node: NodeId) {
+        if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
+            o.get_mut().pending.push(node);
+        }
+    }
+
+    // records the reply latency if the request is still waiting for the node
+    fn record_reply(&mut self, db: &Database, cookie: Cookie, from: NodeId) {
+        if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
+            let req = o.get_mut();
+            if let Some(i) = req.pending.iter().position(|&n| n == from) {
+                req.pending.swap_remove(i);
+                db.health.record_reply(from, req.sent.elapsed());
+            }
+        }
+    }
+
     fn process_get<I: IntoIterator<Item = Cube>>(
         &mut self,
         db: &Database,
@@ -495,19 +919,24 @@ impl VNode {
                 state.done()
             };
             if done {
-                let mut state = o.remove();
-                if !state.satisfied() {
+                let state = o.remove();
+                let satisfied = state.satisfied();
+                let mut context = state.into_context();
+                if !satisfied {
                     debug!("get {:?} done but not satisfied", cookie);
-                    state.context.clear();
-                    db.respond_error(&mut state.context, CommandError::Unavailable);
+                    context.clear();
+                    db.respond_error(&mut context, CommandError::Unavailable);
                 } else {
-                    let ReqState { mut context, .. } = state;
+                    // responses don't carry the pruned ids, contexts would grow forever
+                    let pruned = &self.state.pruned;
                     let mut render_fn = None;
                     context.response.extend(context.reads.drain(..).map(|r| {
                         if render_fn.is_none() {
                             render_fn = r.response;
                         }
-                        render_fn.as_mut().expect("No ResponseFn")(r.cube)
+                        let mut cube = r.cube;
+                        cube.prune(pruned);
+                        render_fn.as_mut().expect("No ResponseFn")(cube)
                     }));
                     db.respond(&mut context);
                 }
@@ -542,21 +971,26 @@ impl VNode {
                 state.done()
             };
             if done {
-                let mut state = o.remove();
-                if !state.satisfied() {
+                let state = o.remove();
+                let satisfied = state.satisfied();
+                let mut context = state.into_context();
+                if !satisfied {
                     debug!("set {:?} done but not satisfied", cookie);
-                    state.context.clear();
-                    db.respond_error(&mut state.context, CommandError::Unavailable);
+                    context.clear();
+                    db.respond_error(&mut context, CommandError::Unavailable);
                 } else {
-                    let ReqState { mut context, .. } = state;
+                    let pruned = &self.state.pruned;
                     context.response.extend(context.writes.drain(..).map(|w| {
                         let ContextWrite {
                             response,
                             response_fn,
-                            cube,
+                            mut cube,
                             ..
                         } = w;
-                        response.unwrap_or_else(|| response_fn.expect("No ResponseFn")(cube))
+                        response.unwrap_or_else(|| {
+                            cube.prune(pruned);
+                            response_fn.expect("No ResponseFn")(cube)
+                        })
                     }));
                     db.respond(&mut context);
                 }
@@ -569,10 +1003,20 @@ impl VNode {
     }
 
     // CRUD HANDLERS
-    pub fn handler_get_remote_ack(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteGetAck) {
+    pub fn handler_get_remote_ack(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGetAck) {
+        self.record_reply(db, msg.cookie, from);
+        if msg.result.is_err() {
+            // single replica reads try the next replica instead
+            self.hedge_get(db, msg.cookie);
+        }
         self.process_get(db, msg.cookie, msg.result);
     }
 
+    // the sender may use a different partitioning if the ring was just split
+    fn owns_keys<'a, I: Iterator<Item = &'a Bytes>>(&self, db: &Database, mut keys: I) -> bool {
+        keys.all(|k| db.dht.key_vnode(k) == self.state.num)
+    }
+
     pub fn handler_get_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGet) {
         // accept zombie to reduce chance of timeouts due to races on cluster change
         check_status!(
@@ -584,6 +1028,10 @@ impl VNode {
             MsgRemoteGetAck,
             inflight_get
         );
+        if !self.owns_keys(db, msg.keys.iter()) {
+            let _ = fabric_send_error!(db, from, msg, MsgRemoteGetAck, FabricError::WrongPartition);
+            return;
+        }
         let mut result = Vec::with_capacity(msg.keys.len());
         for key in &msg.keys {
             let value = self.state
@@ -612,6 +1060,10 @@ impl VNode {
             MsgRemoteSetAck,
             inflight_set
         );
+        if !self.owns_keys(db, msg.writes.iter().map(|w| &w.0)) {
+            let _ = fabric_send_error!(db, from, msg, MsgRemoteSetAck, FabricError::WrongPartition);
+            return;
+        }
         let MsgRemoteSet {
             writes,
             vnode,
@@ -648,19 +1100,23 @@ impl VNode {
         }
     }
 
-    pub fn handler_set_remote_ack(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteSetAck) {
+    pub fn handler_set_remote_ack(&mut self, db: &Database, from: NodeId, msg: MsgRemoteSetAck) {
+        self.record_reply(db, msg.cookie, from);
         self.process_set(db, msg.cookie, msg.result);
     }
 
     // SYNC
     pub fn handler_sync_start(&mut self, db: &Database, from: NodeId, msg: MsgSyncStart) {
-        if !(self.state.status == VNodeStatus::Ready
-            || (self.state.status == VNodeStatus::Zombie
-                && self.state.last_status_change.elapsed()
-                    < Duration::from_millis(ZOMBIE_TIMEOUT_MS)))
-        {
+        if !(self.state.status == VNodeStatus::Ready || self.state.status == VNodeStatus::Zombie) {
             debug!("Can't start sync when {:?}", self.state.status);
             let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::BadVNodeStatus);
+        } else if msg.partitions as usize != db.dht.partitions() {
+            debug!("Can't start sync with {} partitions", msg.partitions);
+            let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::WrongPartition);
+        } else if self.state.is_splitting() {
+            // the storage doesn't have all the entries yet
+            debug!("Can't start sync while splitting");
+            let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::NotReady);
         } else if !self.syncs.contains_key(&msg.cookie) {
             if !db.signal_sync_start(SyncDirection::Outgoing) {
                 debug!("Refusing remote sync request, limit exceeded");
@@ -668,6 +1124,10 @@ impl VNode {
                 return;
             }
 
+            if msg.target.is_some() {
+                self.update_pruned(db, from, &msg);
+            }
+
             let cookie = msg.cookie;
             let sync = match msg.target {
                 None => {
@@ -676,8 +1136,13 @@ impl VNode {
                 }
                 Some(target) => {
                     assert_eq!(target, db.dht.node());
-                    info!("Starting sync sender {:?} peer:{}", cookie, from);
-                    Synchronization::new_sync_sender(db, &mut self.state, from, msg)
+                    if msg.tree.is_some() {
+                        info!("Starting hash tree sync sender {:?} peer:{}", cookie, from);
+                        Synchronization::new_tree_sync_sender(db, &mut self.state, from, msg)
+                    } else {
+                        info!("Starting sync sender {:?} peer:{}", cookie, from);
+                        Synchronization::new_sync_sender(db, &mut self.state, from, msg)
+                    }
                 }
             };
             match self.syncs.entry(cookie) {
@@ -689,6 +1154,24 @@ impl VNode {
         }
     }
 
+    // adopts the ids pruned by the peer and, when ready, prunes the retired ids
+    // of this node once all the replicas have seen them
+    fn update_pruned(&mut self, db: &Database, from: NodeId, msg: &MsgSyncStart) {
+        let node = db.dht.node();
+        let physical_id = split_u64(node).0;
+        let mut changed = self.state.adopt_pruned(&msg.pruned);
+        if self.state.status == VNodeStatus::Ready {
+            self.state
+                .observe_retired(physical_id, from, &msg.clocks_in_peer);
+            let mut replicas = db.dht.nodes_for_vnode(self.state.num, true, true);
+            replicas.retain(|&n| n != node);
+            changed |= self.state.prune_retired(physical_id, &replicas);
+        }
+        if changed {
+            self.state.save(db, false);
+        }
+    }
+
     pub fn handler_sync_send(&mut self, db: &Database, from: NodeId, msg: MsgSyncSend) {
         forward!(
             self,
@@ -726,7 +1209,20 @@ impl VNode {
             syncs
         );
         let cookie = msg.cookie;
-        let result = if let HMEntry::Occupied(mut o) = self.syncs.entry(cookie) {
+        let (result, tree_fallback) = if let HMEntry::Occupied(mut o) = self.syncs.entry(cookie) {
+            let tree_fallback = o.get().tree_fallback(&msg);
+            if self.state.status == VNodeStatus::Zombie {
+                match o.get().pushed_to(&self.state, &msg) {
+                    Some((peer, true)) => {
+                        info!("Zombie vn:{} handed off to {}", self.state.num, peer);
+                        self.handoff.done.insert(peer);
+                    }
+                    Some((peer, false)) => {
+                        self.handoff.tree.insert(peer);
+                    }
+                    None => (),
+                }
+            }
             let result = o.get_mut().on_msg_fin(db, &mut self.state, msg);
             match result {
                 SyncResult::Done | SyncResult::Error => {
@@ -736,7 +1232,7 @@ impl VNode {
                 SyncResult::Continue => (),
             }
             trace!("handler_sync_fin {:?}: {:?}", cookie, result);
-            result
+            (result, tree_fallback)
         } else {
             trace!("Can't find cookie {:?} for msg sync fin", cookie);
             // only send error if Ok, otherwise the message will be sent back and forth forever
@@ -747,78 +1243,159 @@ impl VNode {
         };
 
         if self.status() == VNodeStatus::Bootstrap {
-            self.handle_bootstrap_result(db, result);
+            self.handle_bootstrap_result(db, result, from);
+        } else if let Some(peer) = tree_fallback {
+            // the peer log doesn't cover the sync, so compare hash trees instead
+            // (with a new cookie, as the old sender may still be around)
+            if self.status() == VNodeStatus::Ready {
+                self.start_sync_receiver(db, peer, true);
+            }
+        }
+    }
+
+    pub fn handler_sync_push(&mut self, db: &Database, from: NodeId, msg: MsgSyncPush) {
+        // a zombie handing off its data, pull from it unless that's already going on
+        if self.status() != VNodeStatus::Ready || self.state.sync_nodes.contains(&from) {
+            debug!("Ignoring sync push from {} when {:?}", from, self.state.status);
+            return;
         }
+        info!("Zombie in {} pushing vn:{}", from, self.state.num);
+        self.start_sync_receiver(db, from, msg.tree);
     }
 
-    fn handle_bootstrap_result(&mut self, db: &Database, result: SyncResult) {
+    // called when a bootstrap receiver is removed, `peer` is the node it received from
+    fn handle_bootstrap_result(&mut self, db: &Database, result: SyncResult, peer: NodeId) {
         match result {
             SyncResult::Error => {
-                info!("Retrying bootstrap");
-                self.start_bootstrap(db);
+                info!("Retrying bootstrap share from peer:{}", peer);
+                // persist the checkpoint so even a restart can resume
+                self.state.save(db, false);
+                self.start_bootstrap_avoiding(db, Some(peer));
             }
-            SyncResult::Done => {
-                match db.dht.promote_pending_node(db.dht.node(), self.state.num()) {
-                    Ok(_) => {
-                        // now we're ready!
-                        self.state.set_status(db, VNodeStatus::Ready);
-                    }
-                    Err(e) => {
-                        // it's not clear what happened
-                        // go absent and wait for a dht callback to fix it
-                        self.state.set_status(db, VNodeStatus::Absent);
-                        warn!(
-                            "Can't promote node {} vnode {}: {}",
-                            db.dht.node(),
-                            self.state.num(),
-                            e
-                        );
-                    }
-                }
+            SyncResult::Done if self.state.bootstrap_checkpoint.is_done() => {
+                self.finish_bootstrap(db);
+            }
+            // other shares are still going
+            SyncResult::Done | SyncResult::Continue => (),
+        }
+    }
+
+    fn finish_bootstrap(&mut self, db: &Database) {
+        if let Some(clocks) = self.state.bootstrap_checkpoint.clocks.take() {
+            self.state.clocks.merge(&clocks);
+        }
+        self.state.bootstrap_checkpoint = Default::default();
+        info!(
+            "VNode {} bootstrap finished, {} keys received",
+            self.state.num(),
+            self.state.bootstrap_received
+        );
+        match db.dht.promote_pending_node(db.dht.node(), self.state.num()) {
+            Ok(_) => {
+                // now we're ready!
+                self.state.set_status(db, VNodeStatus::Ready);
+            }
+            Err(e) => {
+                // it's not clear what happened
+                // go absent and wait for a dht callback to fix it
+                self.state.set_status(db, VNodeStatus::Absent);
+                warn!(
+                    "Can't promote node {} vnode {}: {}",
+                    db.dht.node(),
+                    self.state.num(),
+                    e
+                );
             }
-            SyncResult::Continue => (),
         }
     }
 
     fn start_bootstrap(&mut self, db: &Database) {
+        self.start_bootstrap_avoiding(db, None)
+    }
+
+    // starts receivers for the shares that aren't done or running,
+    // preferring the least busy owners and not the one in `avoid` (which just failed)
+    fn start_bootstrap_avoiding(&mut self, db: &Database, avoid: Option<NodeId>) {
         debug!(
             "start_bootstrap vn:{} p:{:?}",
             self.state.num, self.state.pending_bootstrap
         );
         assert_eq!(self.state.status, VNodeStatus::Bootstrap);
-        assert_eq!(self.syncs.len(), 0);
         self.state.pending_bootstrap = false;
-        let cookie = self.gen_cookie();
+        let local_node = db.dht.node();
         let mut nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         let connected_nodes = db.fabric.connections();
-        nodes.retain(|x| connected_nodes.contains(x));
-        if nodes.is_empty() || nodes == &[db.dht.node()] {
-            // nothing to boostrap from
-            self.handle_bootstrap_result(db, SyncResult::Done);
+        nodes.retain(|x| *x != local_node && connected_nodes.contains(x));
+
+        let mut running = Vec::new();
+        let mut load: IdHashMap<NodeId, usize> = nodes.iter().map(|&n| (n, 0)).collect();
+        for sync in self.syncs.values() {
+            if let Synchronization::BootstrapReceiver { peer, share, .. } = *sync {
+                running.push(share);
+                if let Some(l) = load.get_mut(&peer) {
+                    *l += 1;
+                }
+            }
+        }
+
+        if nodes.is_empty() {
+            let nothing_missing = self.state.bootstrap_checkpoint.shares.is_empty()
+                || self.state.bootstrap_checkpoint.is_done();
+            if running.is_empty() && nothing_missing {
+                // nothing to boostrap from
+                self.finish_bootstrap(db);
+            } else {
+                // wait for the running shares, or for an owner of the missing ones,
+                // finishing now would merge clocks that don't cover all keys
+                self.state.pending_bootstrap = true;
+            }
             return;
         }
 
+        if self.state.bootstrap_checkpoint.shares.is_empty() {
+            // split the work between all owners
+            self.state.bootstrap_checkpoint = BootstrapCheckpoint::new(nodes.len() as u16);
+        }
+        let missing: Vec<u16> = (0..self.state.bootstrap_checkpoint.shares.len() as u16)
+            .filter(|share| {
+                !self.state.bootstrap_checkpoint.shares[*share as usize].done
+                    && !running.contains(share)
+            })
+            .collect();
+
         thread_rng().shuffle(&mut nodes);
-        for node in nodes {
-            if node == db.dht.node() {
-                continue;
-            }
+        for share in missing {
+            let node = *nodes
+                .iter()
+                .min_by_key(|n| (Some(**n) == avoid, load[*n]))
+                .unwrap();
             if !db.signal_sync_start(SyncDirection::Incomming) {
                 debug!("Bootstrap not allowed to start, go pending");
                 self.state.pending_bootstrap = true;
                 return;
             }
-            info!("starting bootstrap receiver {:?} peer:{}", cookie, node);
-            let sync = Synchronization::new_bootstrap_receiver(db, &mut self.state, node, cookie);
+            *load.get_mut(&node).unwrap() += 1;
+            let cookie = self.gen_cookie();
+            if let Some(ref key) = self.state.bootstrap_checkpoint.shares[share as usize].key {
+                info!(
+                    "resuming bootstrap receiver {:?} peer:{} share:{} after:{:?}",
+                    cookie, node, share, key
+                );
+            } else {
+                info!(
+                    "starting bootstrap receiver {:?} peer:{} share:{}",
+                    cookie, node, share
+                );
+            }
+            let sync =
+                Synchronization::new_bootstrap_receiver(db, &mut self.state, node, cookie, share);
             match self.syncs.entry(cookie) {
                 HMEntry::Vacant(v) => {
                     v.insert(sync).on_start(db, &mut self.state);
                 }
                 HMEntry::Occupied(_) => unreachable!(),
             }
-            return;
         }
-        unreachable!();
     }
 
     pub fn start_sync_if_ready(&mut self, db: &Database) -> bool {
@@ -828,6 +1405,20 @@ impl VNode {
         }
     }
 
+    #[cfg(test)]
+    pub fn _sweep(&mut self) -> usize {
+        self.state.sweep_subsumed(b"", usize::max_value()).0
+    }
+
+    #[cfg(test)]
+    pub fn _clear_log(&mut self) {
+        let mut batch = self.state.storage.batch_new(0);
+        for (log_key, _) in self.state.storage.log_iterator_all().iter() {
+            batch.log_del(log_key);
+        }
+        self.state.storage.batch_write(batch).unwrap();
+    }
+
     #[cfg(test)]
     pub fn _start_sync(&mut self, db: &Database) -> bool {
         assert_any!(self.state.status, VNodeStatus::Ready);
@@ -841,27 +1432,70 @@ impl VNode {
         nodes.retain(|x| connected_nodes.contains(x));
         thread_rng().shuffle(&mut nodes);
         for node in nodes {
-            if node == db.dht.node() {
-                continue;
-            }
-            if !db.signal_sync_start(SyncDirection::Incomming) {
-                debug!("Refusing start sync, limit exceeded");
-                continue;
+            if node != db.dht.node() && self.start_sync_receiver(db, node, false) {
+                return true;
             }
+        }
+        false
+    }
 
-            let cookie = self.gen_cookie();
-            self.state.sync_nodes.insert(node);
+    fn start_sync_receiver(&mut self, db: &Database, node: NodeId, tree: bool) -> bool {
+        if tree && self.state.is_splitting() {
+            // the hash tree would miss the entries not moved yet
+            debug!("Can't start hash tree sync while splitting");
+            return false;
+        }
+        if !db.signal_sync_start(SyncDirection::Incomming) {
+            debug!("Refusing start sync, limit exceeded");
+            return false;
+        }
+
+        let cookie = self.gen_cookie();
+        self.state.sync_nodes.insert(node);
+        let sync = if tree {
+            info!("Starting hash tree sync receiver {:?} peer:{}", cookie, node);
+            Synchronization::new_tree_sync_receiver(db, &mut self.state, node, cookie)
+        } else {
             info!("Starting sync receiver {:?} peer:{}", cookie, node);
-            let sync = Synchronization::new_sync_receiver(db, &mut self.state, node, cookie);
-            match self.syncs.entry(cookie) {
-                HMEntry::Vacant(v) => {
-                    v.insert(sync).on_start(db, &mut self.state);
-                }
-                HMEntry::Occupied(_) => unreachable!(),
+            Synchronization::new_sync_receiver(db, &mut self.state, node, cookie)
+        };
+        match self.syncs.entry(cookie) {
+            HMEntry::Vacant(v) => {
+                v.insert(sync).on_start(db, &mut self.state);
             }
-            return true;
+            HMEntry::Occupied(_) => unreachable!(),
         }
-        false
+        true
+    }
+
+    fn update_log_watermark(&mut self, db: &Database) -> bool {
+        let physical_id = split_u64(db.dht.node()).0;
+        if self.state.update_log_watermark(physical_id) {
+            self.state.save(db, false);
+            true
+        } else {
+            false
+        }
+    }
+
+    #[cfg(test)]
+    pub fn _update_log_watermark(&mut self, db: &Database) -> bool {
+        self.update_log_watermark(db)
+    }
+
+    #[cfg(test)]
+    pub fn _handed_off(&self, db: &Database) -> bool {
+        self.status() == VNodeStatus::Zombie && self.handoff_pending(db).is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn _has_bootstrap_checkpoint(&self) -> bool {
+        self.state.bootstrap_checkpoint.is_started()
+    }
+
+    #[cfg(test)]
+    pub fn _bootstrap_received(&self) -> u64 {
+        self.state.bootstrap_received
     }
 }
 
@@ -885,7 +1519,19 @@ impl VNodeState {
 
     pub fn clear(&mut self) {
         self.clocks.clear();
+        self.log_watermark.clear();
+        self.pruned.clear();
+        self.retired_seen.clear();
+        self.bootstrap_checkpoint = Default::default();
         self.storage.clear();
+        for source in &mut self.split_sources {
+            source.keep = false;
+        }
+    }
+
+    // whether some entries are still in the split sources
+    fn is_splitting(&self) -> bool {
+        self.split_sources.iter().any(|s| s.keep)
     }
 
     fn generate_id(base: NodeId) -> NodeId {
@@ -925,9 +1571,14 @@ impl VNodeState {
         db.meta_storage
             .del(num.to_string().as_bytes())
             .expect("Can't del vnode state");
-        let storage = db.storage_manager.open(num).expect("Can't open storage");
+        let storage = db.storage_manager
+            .open(db.storage_prefix(num))
+            .expect("Can't open storage");
         storage.clear();
+        Self::empty(num, storage, db, status)
+    }
 
+    fn empty(num: u16, storage: Storage, db: &Database, status: VNodeStatus) -> Self {
         VNodeState {
             id: Self::generate_id(db.dht.node()),
             num: num,
@@ -935,8 +1586,15 @@ impl VNodeState {
             last_status_change: Instant::now(),
             clocks: BitmappedVersionVector::new(),
             storage: storage,
+            log_watermark: Default::default(),
+            pruned: Default::default(),
+            retired_seen: Default::default(),
+            bootstrap_checkpoint: Default::default(),
+            bootstrap_received: 0,
             pending_bootstrap: false,
             sync_nodes: Default::default(),
+            split_sources: Default::default(),
+            split_partitions: 0,
         }
     }
 
@@ -947,20 +1605,61 @@ impl VNodeState {
                 bincode::deserialize(bytes).expect("Can't deserialize vnode state")
             })
             .expect("Can't read saved vnode state");
+        let saved_split: SavedSplit = db.meta_storage
+            .get(split_key(num).as_bytes(), |bytes| {
+                bincode::deserialize(bytes).expect("Can't deserialize vnode split")
+            })
+            .expect("Can't read saved vnode split")
+            .unwrap_or_default();
+
+        let restored = Self::restore(num, db, status, saved_state_opt);
+        // the entries left in the split sources are only valid for a restored state
+        let keep = restored.is_some();
+        let mut state = restored.unwrap_or_else(|| Self::new_empty(num, db, status));
+        state.split_sources = saved_split
+            .sources
+            .into_iter()
+            .map(|(prefix, k)| SplitSource {
+                storage: db.storage_manager.open(prefix).expect("Can't open storage"),
+                keep: keep && k,
+            })
+            .collect();
+        state.split_partitions = saved_split.partitions;
+        state
+    }
 
+    fn restore(
+        num: u16,
+        db: &Database,
+        status: VNodeStatus,
+        saved_state_opt: Option<SavedVNodeState>,
+    ) -> Option<Self> {
         if status == VNodeStatus::Absent || saved_state_opt.is_none() {
             info!("No saved state");
-            return Self::new_empty(num, db, status);
+            return None;
         };
 
-        assert_eq!(status, VNodeStatus::Ready);
         let SavedVNodeState {
             mut id,
             clocks,
+            log_watermark,
+            pruned,
+            bootstrap_checkpoint,
             clean_shutdown,
         } = saved_state_opt.unwrap();
 
-        let storage = db.storage_manager.open(num).expect("Can't open storage");
+        match status {
+            VNodeStatus::Ready => (),
+            VNodeStatus::Bootstrap if bootstrap_checkpoint.is_started() => {
+                info!("Resuming bootstrap from the saved checkpoint");
+            }
+            VNodeStatus::Bootstrap => return None,
+            _ => unreachable!(),
+        }
+
+        let storage = db.storage_manager
+            .open(db.storage_prefix(num))
+            .expect("Can't open storage");
 
         if !clean_shutdown {
             id = Self::generate_id(id);
@@ -973,15 +1672,22 @@ impl VNodeState {
             last_status_change: Instant::now(),
             clocks: clocks,
             storage: storage,
+            log_watermark: log_watermark,
+            pruned: pruned,
+            retired_seen: Default::default(),
+            bootstrap_checkpoint: bootstrap_checkpoint,
+            bootstrap_received: 0,
             sync_nodes: Default::default(),
             pending_bootstrap: false,
+            split_sources: Default::default(),
+            split_partitions: 0,
         };
 
         if !clean_shutdown {
             info!("Unclean shutdown, recovering from the storage");
             state.recover_dots();
         }
-        state
+        Some(state)
     }
 
     fn recover_dots(&mut self) {
@@ -993,28 +1699,307 @@ impl VNodeState {
         }
     }
 
+    /// Raises the log watermarks to the first version still in the log
+    /// for the ids coordinated by this physical node, returns whether any changed.
+    pub fn update_log_watermark(&mut self, physical_id: u32) -> bool {
+        // the log isn't fully moved from the split sources yet
+        if self.is_splitting() {
+            return false;
+        }
+        let mut changed = false;
+        for (&id, bv) in self.clocks.iter() {
+            if split_u64(id).0 != physical_id {
+                continue;
+            }
+            let first = self.storage
+                .log_iterator(id, 0)
+                .iter()
+                .next()
+                .map(|((_, v), _)| v);
+            // an empty log means everything was dropped
+            let watermark = first.unwrap_or_else(|| bv.max() + 1);
+            let current = self.log_watermark.get(&id).cloned().unwrap_or(1);
+            if watermark > current {
+                info!(
+                    "VNode {} log for {} truncated below {}",
+                    self.num, id, watermark
+                );
+                self.log_watermark.insert(id, watermark);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Whether a sync from the log would miss dots the peer needs,
+    /// as they're below the watermark.
+    pub fn log_truncated_for(&self, clocks_in_peer: &BitmappedVersionVector) -> bool {
+        self.log_watermark
+            .iter()
+            .any(|(&id, &watermark)| !clocks_in_peer.contains_all(id, watermark - 1))
+    }
+
+    /// Records the retired ids of this physical node the peer has seen all versions of.
+    pub fn observe_retired(
+        &mut self,
+        physical_id: u32,
+        peer: NodeId,
+        clocks_in_peer: &BitmappedVersionVector,
+    ) {
+        for (&id, bv) in self.clocks.iter() {
+            if Self::is_retired(physical_id, self.id, id, bv)
+                && clocks_in_peer.contains_all(id, bv.base())
+            {
+                self.retired_seen
+                    .entry(id)
+                    .or_insert_with(Default::default)
+                    .insert(peer);
+            }
+        }
+    }
+
+    /// Prunes the retired ids of this physical node that all the replicas have seen,
+    /// returns whether any was pruned.
+    pub fn prune_retired(&mut self, physical_id: u32, replicas: &[NodeId]) -> bool {
+        let mut retired = Vec::new();
+        for (&id, bv) in self.clocks.iter() {
+            if !Self::is_retired(physical_id, self.id, id, bv) {
+                continue;
+            }
+            if let Some(seen) = self.retired_seen.get(&id) {
+                if replicas.iter().all(|n| seen.contains(n)) {
+                    retired.push((id, bv.base()));
+                }
+            }
+        }
+        for &(id, version) in &retired {
+            info!("VNode {} pruning retired id {} at {}", self.num, id, version);
+            self.retired_seen.remove(&id);
+            self.pruned.insert(id, version);
+        }
+        self.prune_clocks();
+        !retired.is_empty()
+    }
+
+    /// Adopts the ids pruned by a peer, as long as the local clocks show all their versions
+    /// up to the pruned one, returns whether any was adopted.
+    pub fn adopt_pruned(&mut self, peer_pruned: &IdHashMap<Id, Version>) -> bool {
+        let mut changed = false;
+        for (&id, &version) in peer_pruned {
+            if self.pruned.contains_key(&id) {
+                continue;
+            }
+            // an id missing from the clocks wasn't seen at all, its writes may still come
+            if self.clocks.get(id).map_or(false, |bv| bv.base() >= version) {
+                info!("VNode {} pruning id {} at {}", self.num, id, version);
+                self.pruned.insert(id, version);
+                changed = true;
+            }
+        }
+        self.prune_clocks();
+        changed
+    }
+
+    /// Removes the pruned ids from the clocks, eg. after merging the clocks of a peer.
+    pub fn prune_clocks(&mut self) {
+        for (&id, &version) in &self.pruned {
+            if self.clocks.get(id).map_or(false, |bv| bv.max() <= version) {
+                self.clocks.remove(id);
+                self.log_watermark.remove(&id);
+            }
+        }
+    }
+
+    // ids coordinated by this physical node before a dirty restart, without gaps
+    fn is_retired(physical_id: u32, current: NodeId, id: Id, bv: &BitmappedVersion) -> bool {
+        split_u64(id).0 == physical_id && id != current && bv.base() != 0
+            && bv.max() == bv.base()
+    }
+
     pub fn save(&self, db: &Database, shutdown: bool) {
+        let mut batch = db.meta_storage.batch_new(0);
+        self.save_into(&mut batch, shutdown);
+        db.meta_storage
+            .batch_write(batch)
+            .expect("Can't save vnode state");
+    }
+
+    /// Adds the state to a batch of the meta storage
+    pub fn save_into(&self, batch: &mut StorageBatch, shutdown: bool) {
         let saved_state = SavedVNodeState {
             id: self.id,
             clocks: self.clocks.clone(),
+            log_watermark: self.log_watermark.clone(),
+            pruned: self.pruned.clone(),
+            bootstrap_checkpoint: self.bootstrap_checkpoint.clone(),
             clean_shutdown: shutdown,
         };
         debug!("Saving state for vnode {:?} {:?}", self.num, saved_state);
         let serialized_saved_state =
             bincode::serialize(&saved_state).expect("Can't serialize vnode state");
-        db.meta_storage
-            .set(self.num.to_string().as_bytes(), &serialized_saved_state)
-            .expect("Can't save vnode state");
+        batch.set(self.num.to_string().as_bytes(), &serialized_saved_state);
+
+        if self.split_sources.is_empty() {
+            batch.del(split_key(self.num).as_bytes());
+        } else {
+            let saved_split = SavedSplit {
+                sources: self.split_sources
+                    .iter()
+                    .map(|s| (s.storage.num(), s.keep))
+                    .collect(),
+                partitions: self.split_partitions,
+            };
+            let serialized_saved_split =
+                bincode::serialize(&saved_split).expect("Can't serialize vnode split");
+            batch.set(split_key(self.num).as_bytes(), &serialized_saved_split);
+        }
+    }
+
+    // moves (or drops) up to `limit` entries of the oldest split source that belong to this
+    // vnode, returns where to continue from, None once the source is done (or on errors)
+    fn split_step(
+        &mut self,
+        db: &Database,
+        cursor: SplitCursor,
+        limit: usize,
+    ) -> Option<SplitCursor> {
+        let (num, partitions) = (self.num, self.split_partitions);
+        let owned = |k: &[u8]| slot_vnode(hash_slot(k), partitions) == num;
+        let result = {
+            let source = self.split_sources.last().unwrap();
+            // writes delete the keys from the split sources,
+            // so the entries still there are the latest ones
+            let mut batch = self.storage.batch_new(0);
+            let mut source_batch = source.storage.batch_new(0);
+            let next = match cursor {
+                SplitCursor::Entries(start) => {
+                    let mut next = Some(SplitCursor::Log((0, 0)));
+                    let mut iterator = source.storage.iterator_from(&start);
+                    for (i, (k, v)) in iterator.iter().enumerate() {
+                        if i == limit {
+                            next = Some(SplitCursor::Entries(Bytes::from(k)));
+                            break;
+                        }
+                        if owned(k) {
+                            if source.keep {
+                                batch.set(k, v);
+                            }
+                            source_batch.del(k);
+                        }
+                    }
+                    next
+                }
+                SplitCursor::Log(start) => {
+                    let mut next = None;
+                    let mut iterator = source.storage.log_iterator_from(start);
+                    for (i, (log_key, k)) in iterator.iter().enumerate() {
+                        if i == limit {
+                            next = Some(SplitCursor::Log(log_key));
+                            break;
+                        }
+                        if owned(k) {
+                            if source.keep {
+                                batch.log_set(log_key, k);
+                            }
+                            source_batch.log_del(log_key);
+                        }
+                    }
+                    next
+                }
+            };
+            // write before deleting from the source, so a crash can't lose entries
+            self.storage
+                .batch_write(batch)
+                .and_then(|_| source.storage.batch_write(source_batch))
+                .map(|_| next)
+        };
+        match result {
+            Ok(None) => {
+                self.split_sources.pop();
+                if self.split_sources.is_empty() {
+                    info!("VNode {} split done", self.num);
+                }
+                self.save(db, false);
+                None
+            }
+            Ok(next) => next,
+            Err(e) => {
+                warn!("VNode {} split step failed: {:?}", self.num, e);
+                None
+            }
+        }
     }
 
     // STORAGE
     pub fn storage_get(&self, key: &[u8]) -> Result<Cube, ()> {
-        let result = self.storage.get(key, |v| bincode::deserialize::<Cube>(v));
-        match result {
-            Ok(Some(Ok(cube))) => Ok(cube),
-            Ok(Some(Err(_de))) => Err(()),
-            Ok(None) => Ok(Cube::new(&self.clocks)),
-            Err(_se) => Err(()),
+        let mut result = self.storage.get(key, |v| bincode::deserialize::<Cube>(v));
+        // fall back to the entries not moved yet by a split
+        for source in self.split_sources.iter().take_while(|s| s.keep) {
+            if let Ok(None) = result {
+                result = source.storage.get(key, |v| bincode::deserialize::<Cube>(v));
+            }
+        }
+        let mut cube = match result {
+            Ok(Some(Ok(cube))) => cube,
+            Ok(Some(Err(_de))) => return Err(()),
+            Ok(None) => Cube::new(&self.clocks),
+            Err(_se) => return Err(()),
+        };
+        cube.restore(&self.pruned);
+        Ok(cube)
+    }
+
+    // the cube as stored, without the pruned ids
+    fn storage_cube<'a>(&self, cube: &'a Cube) -> Cow<'a, Cube> {
+        if self.pruned.is_empty() {
+            Cow::Borrowed(cube)
+        } else {
+            let mut cube = cube.clone();
+            cube.prune(&self.pruned);
+            Cow::Owned(cube)
+        }
+    }
+
+    /// Deletes the keys subsumed by the clocks, checking up to `limit` keys from `start`,
+    /// returns the number of deleted keys and where to continue from (None if done).
+    pub fn sweep_subsumed(&self, start: &[u8], limit: usize) -> (usize, Option<Bytes>) {
+        let mut batch = self.storage.batch_new(0);
+        let mut next = None;
+        let (mut keys, mut bytes) = (0, 0);
+        {
+            let mut iterator = self.storage.iterator_from(start);
+            for (i, (k, v)) in iterator.iter().enumerate() {
+                if i == limit {
+                    next = Some(Bytes::from(k));
+                    break;
+                }
+                let cube = match bincode::deserialize::<Cube>(v) {
+                    Ok(cube) => cube,
+                    Err(_) => continue,
+                };
+                if self.storage_cube(&cube).is_subsumed(&self.clocks) {
+                    batch.del(k);
+                    keys += 1;
+                    bytes += k.len() + v.len();
+                }
+            }
+        }
+        if keys != 0 {
+            if let Err(e) = self.storage.batch_write(batch) {
+                warn!("VNode {} sweep failed: {:?}", self.num, e);
+                return (0, None);
+            }
+            debug!("VNode {} swept {} keys ({} bytes)", self.num, keys, bytes);
+            metrics::SWEEP_KEYS.mark(keys as i64);
+            metrics::SWEEP_BYTES.mark(bytes as i64);
+        }
+        (keys, next)
+    }
+
+    // so the stale entries of a split aren't read once the key is deleted from the storage
+    fn del_from_split_sources(&self, batch: &mut StorageBatch, key: &[u8]) {
+        for source in self.split_sources.iter().take_while(|s| s.keep) {
+            batch.del_from(&source.storage, key);
         }
     }
 
@@ -1025,13 +2010,15 @@ impl VNodeState {
     ) -> Result<(), CommandError> {
         let mut batch = self.storage.batch_new(0);
         for (version, key, cube) in writes {
+            let cube = self.storage_cube(cube);
             // TODO: integrate is_subsumed logic into the result of merge and MutatorFn
             if cube.is_subsumed(&self.clocks) {
                 batch.del(key);
             } else {
-                let bytes = bincode::serialize(cube).expect("Can't serialize Cube");
+                let bytes = bincode::serialize(&*cube).expect("Can't serialize Cube");
                 batch.set(key, &bytes);
             }
+            self.del_from_split_sources(&mut batch, key);
 
             batch.log_set((self.id, version), key);
         }
@@ -1042,6 +2029,35 @@ impl VNodeState {
         Ok(())
     }
 
+    /// Merges the keys with the clocks of a peer that doesn't have them,
+    /// dropping the values the peer saw deleted
+    pub fn storage_set_void(
+        &self,
+        keys: &[Bytes],
+        clocks: &BitmappedVersionVector,
+    ) -> Result<(), ()> {
+        let mut batch = self.storage.batch_new(0);
+        for key in keys {
+            let new = match self.storage_get(key)? {
+                // nothing to drop if the key is gone already
+                Cube::Void(_) => continue,
+                cube => cube.merge(Cube::new(clocks)),
+            };
+            let stored = self.storage_cube(&new);
+            if stored.is_subsumed(&self.clocks) {
+                batch.del(key);
+            } else {
+                let serialized = bincode::serialize(&*stored).expect("Can't serialize Cube");
+                batch.set(key, &serialized);
+            }
+            self.del_from_split_sources(&mut batch, key);
+        }
+        if !keys.is_empty() {
+            debug!("VNode {} merged {} keys the peer lacks", self.num, keys.len());
+        }
+        self.storage.batch_write(batch).map_err(|_| ())
+    }
+
     pub fn storage_set_remote(
         &mut self,
         _db: &Database,
@@ -1057,7 +2073,12 @@ impl VNodeState {
             let mut empty = true;
             {
                 let clocks = &mut self.clocks;
+                let pruned = &self.pruned;
                 proposed.for_each_dot(|i, v| {
+                    // all versions of pruned ids were already seen
+                    if pruned.get(&i).map_or(false, |&p| v <= p) {
+                        return;
+                    }
                     if clocks.add(i, v) {
                         empty = false;
                         batch.log_set((i, v), &key);
@@ -1073,12 +2094,14 @@ impl VNodeState {
             let new = old.merge(proposed);
 
             if !empty {
-                if new.is_subsumed(&self.clocks) {
+                let stored = self.storage_cube(&new);
+                if stored.is_subsumed(&self.clocks) {
                     batch.del(&key);
                 } else {
-                    let serialized = bincode::serialize(&new).expect("Can't serialize Cube");
+                    let serialized = bincode::serialize(&*stored).expect("Can't serialize Cube");
                     batch.set(&key, &serialized);
                 }
+                self.del_from_split_sources(&mut batch, &key);
             }
 
             results.push(if reply_result { Some(new) } else { None });
@@ -4,13 +4,19 @@ use cubes::Cube;
 use database::*;
 use fabric::*;
 use inflightmap::InFlightMap;
+use merkle::{self, MerkleTree, MERKLE_LEAVES};
 use metrics::{self, Meter};
 use std::collections::{hash_set, HashSet};
 use std::time::{Duration, Instant};
-use utils::{split_u64, IdHasherBuilder};
+use utils::{split_u64, IdHashMap, IdHasherBuilder};
 use version_vector::*;
 use vnode::VNodeState;
 
+// local keys checked per tick after a hash tree sync, see `TreeCleanup`
+const TREE_CLEANUP_BATCH_SIZE: usize = 10_000;
+// keys hashed per tick for a hash tree sync, see `TreeHasher`
+const TREE_HASH_BATCH_SIZE: usize = 10_000;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[must_use]
 pub enum SyncResult {
@@ -46,13 +52,89 @@ pub enum SyncDirection {
     Outgoing,
 }
 
-type IteratorFn = Box<FnMut(&VNodeState) -> Result<Option<(Bytes, Cube)>, ()> + Send>;
+/// Progress of a bootstrap, so it can resume instead of starting over.
+/// The keys are split in shares, each one streamed from a different owner.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BootstrapCheckpoint {
+    // empty until the bootstrap starts
+    pub shares: Vec<BootstrapShare>,
+    // clocks covered by the received keys,
+    // the intersection of the clocks of all senders involved
+    pub clocks: Option<BitmappedVersionVector>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BootstrapShare {
+    // all keys of the share up to this one (in storage order) were received
+    pub key: Option<Bytes>,
+    pub done: bool,
+}
+
+impl BootstrapCheckpoint {
+    pub fn new(shares: u16) -> Self {
+        BootstrapCheckpoint {
+            shares: vec![Default::default(); shares as usize],
+            clocks: None,
+        }
+    }
+
+    /// Whether any keys were received
+    pub fn is_started(&self) -> bool {
+        self.shares.iter().any(|s| s.done || s.key.is_some())
+    }
+
+    /// Whether all shares were received
+    pub fn is_done(&self) -> bool {
+        !self.shares.is_empty() && self.shares.iter().all(|s| s.done)
+    }
+
+    fn intersect_clocks(&mut self, clocks: &BitmappedVersionVector) {
+        self.clocks = Some(match self.clocks.take() {
+            Some(previous) => previous.intersection(clocks),
+            None => clocks.clone(),
+        });
+    }
+}
+
+/// Bootstrap share of the key, shares are contiguous ranges of hash tree leaves
+pub fn bootstrap_share_of(key: &[u8], shares: u16) -> u16 {
+    (merkle::leaf_of(key) * shares as usize / MERKLE_LEAVES) as u16
+}
+
+/// Progress of an ongoing synchronization
+pub struct SyncProgress {
+    pub kind: &'static str,
+    pub peer: NodeId,
+    // keys sent or received so far
+    pub count: u64,
+}
+
+type IteratorFn = Box<FnMut(&VNodeState) -> Result<Option<(Bytes, Cube)>, FabricError> + Send>;
 
 type InFlightSyncMsgMap = InFlightMap<u64, MsgSyncSend, Instant, IdHasherBuilder>;
 
+// hash tree leaves of the vnode data, built a batch of keys per tick
+// as a full scan would block the vnode for long
+struct TreeHasher {
+    leaves: Vec<u64>,
+    // where hashing continues from, None once done
+    next: Option<Bytes>,
+}
+
+// after a hash tree sync the local keys in the differing leaves that weren't received
+// are merged with the sender clocks, as the sender doesn't have them (eg. deleted)
+struct TreeCleanup {
+    differing: Vec<bool>,
+    clocks: BitmappedVersionVector,
+    // where checking continues from, None once done
+    next: Option<Bytes>,
+}
+
 struct SyncKeysIterator {
     dots_delta: BitmappedVersionVectorDelta,
     keys: hash_set::IntoIter<Bytes>,
+    // dots of this physical node must be in the log
+    physical_id: u32,
 }
 
 // TODO: Refactor into trait objects
@@ -71,12 +153,22 @@ pub enum Synchronization {
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // hash tree leaves that differ from the peer, for hash tree syncs
+        leaves: Option<Vec<u16>>,
+        // for hash tree syncs, the local tree being hashed and the peer one,
+        // no keys are sent until it's done
+        hashing: Option<(TreeHasher, MerkleTree)>,
         last_recv: Instant,
         last_send: Instant,
     },
     SyncReceiver {
         // local bvv at the time of sync start
         clocks_in_peer: BitmappedVersionVector,
+        // local hash tree, to compare the hash trees instead of using the peer log
+        tree: Option<TreeHasher>,
+        // keys received by a hash tree sync
+        received: HashSet<Bytes>,
+        cleanup: Option<TreeCleanup>,
         cookie: Cookie,
         peer: NodeId,
         // aprox count of received keys (includes dups)
@@ -98,6 +190,11 @@ pub enum Synchronization {
     BootstrapReceiver {
         cookie: Cookie,
         peer: NodeId,
+        // index in the checkpoint shares
+        share: u16,
+        // next seq to advance the checkpoint and the keys received ahead of it
+        next_seq: u64,
+        received: IdHashMap<u64, Bytes>,
         // aprox count of received keys (includes dups)
         recv_count: u64,
         last_recv: Instant,
@@ -105,15 +202,81 @@ pub enum Synchronization {
     },
 }
 
+impl TreeHasher {
+    fn new() -> Self {
+        TreeHasher {
+            leaves: vec![0u64; MERKLE_LEAVES],
+            next: Some(Bytes::new()),
+        }
+    }
+
+    // hashes up to `limit` keys, returns the leaves once all keys were hashed
+    fn step(&mut self, state: &VNodeState, limit: usize) -> Option<&[u64]> {
+        if let Some(start) = self.next.take() {
+            let mut iterator = state.storage.iterator_from(&start);
+            for (i, (k, v)) in iterator.iter().enumerate() {
+                if i == limit {
+                    self.next = Some(Bytes::from(k));
+                    break;
+                }
+                let leaf = &mut self.leaves[merkle::leaf_of(k)];
+                *leaf = leaf.wrapping_add(entry_hash(k, v));
+            }
+        }
+        if self.next.is_none() {
+            Some(&self.leaves)
+        } else {
+            None
+        }
+    }
+}
+
+impl TreeCleanup {
+    // checks up to `limit` keys, Done once all were checked
+    fn step(
+        &mut self,
+        state: &mut VNodeState,
+        received: &HashSet<Bytes>,
+        limit: usize,
+    ) -> SyncResult {
+        let start = match self.next.take() {
+            Some(start) => start,
+            None => return SyncResult::Done,
+        };
+        let mut keys = Vec::new();
+        {
+            let mut iterator = state.storage.iterator_from(&start);
+            for (i, (k, _)) in iterator.iter().enumerate() {
+                if i == limit {
+                    self.next = Some(Bytes::from(k));
+                    break;
+                }
+                if self.differing[merkle::leaf_of(k)] && !received.contains(k) {
+                    keys.push(Bytes::from(k));
+                }
+            }
+        }
+        if state.storage_set_void(&keys, &self.clocks).is_err() {
+            return SyncResult::Error;
+        }
+        if self.next.is_some() {
+            SyncResult::Continue
+        } else {
+            SyncResult::Done
+        }
+    }
+}
+
 impl SyncKeysIterator {
-    fn new(dots_delta: BitmappedVersionVectorDelta) -> Self {
+    fn new(dots_delta: BitmappedVersionVectorDelta, physical_id: u32) -> Self {
         SyncKeysIterator {
             dots_delta: dots_delta,
             keys: HashSet::new().into_iter(),
+            physical_id: physical_id,
         }
     }
 
-    fn next(&mut self, state: &VNodeState) -> Result<Option<Bytes>, ()> {
+    fn next(&mut self, state: &VNodeState) -> Result<Option<Bytes>, FabricError> {
         loop {
             if let Some(key) = self.keys.next() {
                 return Ok(Some(key));
@@ -127,12 +290,16 @@ impl SyncKeysIterator {
                 let key = state
                     .storage
                     .log_get((n, v), |x| Bytes::from(x))
-                    .map_err(|_| ())?;
+                    .map_err(|_| FabricError::SyncInterrupted)?;
                 if let Some(key) = key {
                     keys.insert(key);
                     if keys.len() >= limit {
                         break;
                     }
+                } else if split_u64(n).0 == self.physical_id {
+                    // the entry was coordinated by this node, so the log was truncated
+                    warn!("Log truncated, can't find log key for ({}, {})", n, v);
+                    return Err(FabricError::LogTruncated);
                 } else {
                     warn!("Can't find log key for ({}, {})", n, v);
                 }
@@ -146,6 +313,44 @@ impl SyncKeysIterator {
     }
 }
 
+// iterates the storage key/values accepted by the filter, starting at `start` (inclusive)
+fn storage_iterator_fn<F>(state: &VNodeState, start: &[u8], accept: F) -> IteratorFn
+where
+    F: Fn(&[u8]) -> bool + Send + 'static,
+{
+    let mut storage_iterator = state.storage.iterator_from(start);
+    Box::new(move |state| {
+        let next = storage_iterator
+            .iter()
+            .filter(|&(k, _)| accept(k))
+            .map(|(k, v)| {
+                let mut cube =
+                    bincode::deserialize::<Cube>(v).map_err(|_| FabricError::SyncInterrupted)?;
+                // peers may not know about the pruned ids
+                cube.restore(&state.pruned);
+                Ok((Bytes::from(k), cube))
+            })
+            .next();
+
+        match next {
+            Some(Ok(r)) => Ok(Some(r)),
+            None => Ok(None),
+            Some(Err(e)) => Err(e),
+        }
+    })
+}
+
+// hash of a storage entry for the hash tree, using the canonical form of the cube
+fn entry_hash(key: &[u8], value: &[u8]) -> u64 {
+    match bincode::deserialize::<Cube>(value) {
+        Ok(cube) => {
+            let canonical = bincode::serialize(&cube.canonical()).unwrap();
+            merkle::entry_hash(key, &canonical)
+        }
+        Err(_) => merkle::entry_hash(key, value),
+    }
+}
+
 use self::Synchronization::*;
 
 impl Synchronization {
@@ -154,10 +359,14 @@ impl Synchronization {
         _state: &mut VNodeState,
         peer: NodeId,
         cookie: Cookie,
+        share: u16,
     ) -> Self {
         BootstrapReceiver {
             cookie: cookie,
             peer: peer,
+            share: share,
+            next_seq: 0,
+            received: Default::default(),
             recv_count: 0,
             last_recv: Instant::now(),
             last_send: Instant::now(),
@@ -170,26 +379,25 @@ impl Synchronization {
         peer: NodeId,
         msg: MsgSyncStart,
     ) -> Self {
-        let mut storage_iterator = state.storage.iterator();
-        let iterator_fn: IteratorFn = Box::new(move |_| {
-            let next = storage_iterator
-                .iter()
-                .map(|(k, v)| {
-                    let cube = bincode::deserialize::<Cube>(v).map_err(|_| ())?;
-                    Ok((Bytes::from(k), cube))
-                })
-                .next();
-
-            match next {
-                Some(Ok(r)) => Ok(Some(r)),
-                None => Ok(None),
-                Some(Err(e)) => Err(e),
-            }
-        });
+        let (share, shares) = msg.share.unwrap_or((0, 1));
+        let in_share = move |k: &[u8]| bootstrap_share_of(k, shares) == share;
+        let iterator_fn = if let Some(resume_after) = msg.resume_after {
+            debug!("Resuming bootstrap {:?} after {:?}", msg.cookie, resume_after);
+            let start = resume_after.clone();
+            storage_iterator_fn(state, &start, move |k| k != &resume_after[..] && in_share(k))
+        } else {
+            storage_iterator_fn(state, b"", in_share)
+        };
+        // the cubes are sent with the pruned ids restored, so are the clocks,
+        // otherwise the peer could never adopt the pruning
+        let mut clocks_snapshot = state.clocks.clone();
+        for (&id, &version) in &state.pruned {
+            clocks_snapshot.add_all(id, version);
+        }
 
         BootstrapSender {
             cookie: msg.cookie,
-            clocks_snapshot: state.clocks.clone(),
+            clocks_snapshot: clocks_snapshot,
             iterator: iterator_fn,
             inflight: InFlightMap::new(),
             peer: peer,
@@ -207,6 +415,31 @@ impl Synchronization {
     ) -> Self {
         SyncReceiver {
             clocks_in_peer: state.clocks.clone(),
+            tree: None,
+            received: Default::default(),
+            cleanup: None,
+            peer: peer,
+            cookie: cookie,
+            recv_count: 0,
+            last_recv: Instant::now(),
+            last_send: Instant::now(),
+        }
+    }
+
+    // like a SyncReceiver but the peer compares hash trees,
+    // used when the peer log doesn't cover the sync (eg. after a long outage)
+    pub fn new_tree_sync_receiver(
+        _db: &Database,
+        state: &mut VNodeState,
+        peer: NodeId,
+        cookie: Cookie,
+    ) -> Self {
+        metrics::SYNC_TREE.mark(1);
+        SyncReceiver {
+            clocks_in_peer: state.clocks.clone(),
+            tree: Some(TreeHasher::new()),
+            received: Default::default(),
+            cleanup: None,
             peer: peer,
             cookie: cookie,
             recv_count: 0,
@@ -237,20 +470,28 @@ impl Synchronization {
         let dots_delta = state.clocks.delta(&clocks_in_peer);
         debug!("Delta from {:?} to {:?}", state.clocks, clocks_in_peer);
 
-        let mut sync_keys = SyncKeysIterator::new(dots_delta);
-        let iterator_fn: IteratorFn = Box::new(move |state| {
-            if let Some(key) = sync_keys.next(state)? {
-                let cube = state.storage_get(&key)?;
-                Ok(Some((key, cube)))
-            } else {
-                Ok(None)
-            }
-        });
+        let physical_id = split_u64(db.dht.node()).0;
+        let iterator_fn: IteratorFn = if state.log_truncated_for(&clocks_in_peer) {
+            // fail right away instead of finding out halfway through the sync
+            info!("Log doesn't cover sync {:?}, watermark {:?}", cookie, state.log_watermark);
+            Box::new(|_| Err(FabricError::LogTruncated))
+        } else {
+            let mut sync_keys = SyncKeysIterator::new(dots_delta, physical_id);
+            Box::new(move |state| {
+                if let Some(key) = sync_keys.next(state)? {
+                    let cube = state
+                        .storage_get(&key)
+                        .map_err(|_| FabricError::SyncInterrupted)?;
+                    Ok(Some((key, cube)))
+                } else {
+                    Ok(None)
+                }
+            })
+        };
 
         // Only send the part of the bvv corresponding to things that this node coordinated.
         // Even if the dots are registered in the bvv,
         // there's no guarantee that the dot->key log had the dot.
-        let physical_id = split_u64(db.dht.node()).0;
         let clocks_snapshot = state.clocks.clone_if(|i| split_u64(i).0 == physical_id);
 
         SyncSender {
@@ -261,36 +502,106 @@ impl Synchronization {
             cookie: cookie,
             peer: peer,
             count: 0,
+            leaves: None,
+            hashing: None,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
     }
 
-    // send SyncStart message, only valid for Receivers
-    fn send_start(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
-        let (peer, cookie, target, clocks_in_peer) = match *self {
-            SyncReceiver {
-                cookie,
-                peer,
-                ref mut last_send,
-                ref clocks_in_peer,
-                ..
-            } => {
-                *last_send = Instant::now();
-                (peer, cookie, Some(peer), clocks_in_peer.clone())
-            }
-            BootstrapReceiver {
-                peer,
-                cookie,
-                ref mut last_send,
-                ..
-            } => {
-                *last_send = Instant::now();
-                (peer, cookie, None, BitmappedVersionVector::new())
+    // like a SyncSender but sends the keys in the hash tree leaves that differ from the peer,
+    // used when the log doesn't cover the sync
+    pub fn new_tree_sync_sender(
+        db: &Database,
+        state: &mut VNodeState,
+        peer: NodeId,
+        msg: MsgSyncStart,
+    ) -> Self {
+        let MsgSyncStart {
+            target,
+            cookie,
+            clocks_in_peer,
+            tree,
+            ..
+        } = msg;
+        assert_eq!(target, Some(db.dht.node()));
+
+        let peer_tree = tree
+            .ok_or_else(|| "Missing hash tree".into())
+            .and_then(MerkleTree::new);
+        // the iterator is set once the local tree is hashed, see `hash_tree_step`
+        let (iterator_fn, hashing): (IteratorFn, _) = match peer_tree {
+            Ok(peer_tree) => (Box::new(|_| Ok(None)), Some((TreeHasher::new(), peer_tree))),
+            Err(e) => {
+                warn!("Can't use hash tree from {}: {}", peer, e);
+                (Box::new(|_| Err(FabricError::SyncInterrupted)), None)
             }
-            _ => unreachable!(),
         };
 
+        // all keys are compared, so the whole bvv can be sent (like bootstrap),
+        // the keys in the differing leaves that aren't sent are dropped by the receiver
+        SyncSender {
+            clocks_in_peer: clocks_in_peer,
+            clocks_snapshot: state.clocks.clone(),
+            iterator: iterator_fn,
+            inflight: InFlightMap::new(),
+            cookie: cookie,
+            peer: peer,
+            count: 0,
+            leaves: None,
+            hashing: hashing,
+            last_recv: Instant::now(),
+            last_send: Instant::now(),
+        }
+    }
+
+    // send SyncStart message, only valid for Receivers
+    fn send_start(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
+        let (peer, cookie, target, clocks_in_peer, tree, resume_after, share, pruned) =
+            match *self {
+                SyncReceiver {
+                    cookie,
+                    peer,
+                    ref mut last_send,
+                    ref mut last_recv,
+                    ref clocks_in_peer,
+                    ref mut tree,
+                    ..
+                } => {
+                    let tree = match *tree {
+                        Some(ref mut hasher) => match hasher.step(state, TREE_HASH_BATCH_SIZE) {
+                            Some(leaves) => Some(leaves.to_vec()),
+                            None => {
+                                // nothing to wait for from the peer yet
+                                *last_recv = Instant::now();
+                                return SyncResult::Continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    *last_send = Instant::now();
+                    let clocks_in_peer = clocks_in_peer.clone();
+                    let pruned = state.pruned.clone();
+                    (peer, cookie, Some(peer), clocks_in_peer, tree, None, None, pruned)
+                }
+                BootstrapReceiver {
+                    peer,
+                    cookie,
+                    share,
+                    ref mut last_send,
+                    ..
+                } => {
+                    *last_send = Instant::now();
+                    let checkpoint = &state.bootstrap_checkpoint;
+                    let resume_after = checkpoint.shares[share as usize].key.clone();
+                    let share = Some((share, checkpoint.shares.len() as u16));
+                    let clocks_in_peer = BitmappedVersionVector::new();
+                    let pruned = Default::default();
+                    (peer, cookie, None, clocks_in_peer, None, resume_after, share, pruned)
+                }
+                _ => unreachable!(),
+            };
+
         info!("Sending start for {:?}", cookie);
         db.fabric
             .send_msg(
@@ -298,8 +609,13 @@ impl Synchronization {
                 &MsgSyncStart {
                     cookie: cookie,
                     vnode: state.num(),
+                    partitions: db.dht.partitions() as u16,
                     clocks_in_peer: clocks_in_peer,
                     target: target,
+                    tree: tree,
+                    resume_after: resume_after,
+                    share: share,
+                    pruned: pruned,
                 },
             )
             .into()
@@ -354,6 +670,10 @@ impl Synchronization {
     // Senders wait for the Receivers to reply => Continue
     // unless there's no route the peer => Error
     fn send_sender_success_fin(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
+        let leaves = match *self {
+            SyncSender { ref leaves, .. } => leaves.clone(),
+            _ => None,
+        };
         match *self {
             SyncSender {
                 peer,
@@ -376,7 +696,7 @@ impl Synchronization {
                         &MsgSyncFin {
                             cookie: cookie,
                             vnode: state.num(),
-                            result: Ok(clocks_snapshot.clone()),
+                            result: Ok((clocks_snapshot.clone(), leaves)),
                         },
                     )
                     .into()
@@ -388,9 +708,17 @@ impl Synchronization {
     // send (possibly multiple) SyncSend messages and eventual SyncFin
     // (also takes care of expired SyncSend)
     fn send_next(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
+        if let Some(result) = self.hash_tree_step(db, state) {
+            return result;
+        }
         let now = Instant::now();
-        let timeout = now + Duration::from_millis(db.config.sync_msg_timeout as _);
-        let (error, inflight_empty) = match *self {
+        let timeout = now + Duration::from_millis(db.config().sync_msg_timeout as _);
+        let rate_limit = db.config().sync_rate_limit;
+        let bootstrap = match *self {
+            BootstrapSender { .. } => true,
+            _ => false,
+        };
+        let (error, inflight_empty, throttled) = match *self {
             SyncSender {
                 peer,
                 cookie,
@@ -398,6 +726,8 @@ impl Synchronization {
                 ref mut count,
                 ref mut inflight,
                 ref mut last_send,
+                ref mut last_recv,
+                ref clocks_snapshot,
                 ..
             }
             | BootstrapSender {
@@ -407,15 +737,26 @@ impl Synchronization {
                 ref mut count,
                 ref mut inflight,
                 ref mut last_send,
+                ref mut last_recv,
+                ref clocks_snapshot,
                 ..
             } => {
                 while let Some((seq, msg)) = inflight.touch_expired(now, timeout) {
                     debug!("resending seq {} for sync/bootstrap {:?}", seq, cookie);
                     let _ = stry!(db.fabric.send_msg(peer, msg,));
+                    db.sync_throttle
+                        .consume(rate_limit, bincode::serialized_size(msg).unwrap());
                     metrics::SYNC_RESEND.mark(1);
                 }
-                let mut error = false;
-                while inflight.len() < db.config.sync_msg_inflight as usize {
+                let mut error = None;
+                let mut throttled = false;
+                while inflight.len() < db.config().sync_msg_inflight as usize {
+                    if !db.sync_throttle.is_ready(rate_limit) {
+                        // ticks and acks will resume sending
+                        throttled = true;
+                        metrics::SYNC_THROTTLED.mark(1);
+                        break;
+                    }
                     match iterator(state) {
                         Ok(Some((k, v))) => {
                             let msg = MsgSyncSend {
@@ -424,8 +765,16 @@ impl Synchronization {
                                 seq: *count,
                                 key: k.clone(),
                                 value: v.clone(),
+                                // so an interrupted bootstrap knows what the keys cover
+                                clocks: if bootstrap && *count == 0 {
+                                    Some(clocks_snapshot.clone())
+                                } else {
+                                    None
+                                },
                             };
                             let _ = stry!(db.fabric.send_msg(peer, &msg,));
+                            db.sync_throttle
+                                .consume(rate_limit, bincode::serialized_size(&msg).unwrap());
                             inflight.insert(*count, msg, timeout);
                             *count += 1;
                             *last_send = now;
@@ -435,20 +784,24 @@ impl Synchronization {
                         Ok(None) => {
                             break;
                         }
-                        Err(_) => {
-                            error = true;
+                        Err(e) => {
+                            error = Some(e);
                             break;
                         }
                     }
                 }
-                (error, inflight.is_empty())
+                if throttled && inflight.is_empty() {
+                    // the throttle debt may take longer than the sync timeout to pay
+                    Self::keep_alive(db, state, peer, cookie, last_recv, last_send);
+                }
+                (error, inflight.is_empty(), throttled)
             }
             _ => unreachable!(),
         };
 
-        if error {
-            self.send_error_fin(db, state, FabricError::SyncInterrupted)
-        } else if inflight_empty {
+        if let Some(error) = error {
+            self.send_error_fin(db, state, error)
+        } else if inflight_empty && !throttled {
             // do not trottle success fin as we don't know if last_send
             // was set by MsgSend or MsgFin
             self.send_sender_success_fin(db, state)
@@ -457,15 +810,75 @@ impl Synchronization {
         }
     }
 
+    // for senders not sending anything for a while, there are no acks to wait for
+    // so the sender timeout is refreshed, and the peer is told it's alive every now and then
+    fn keep_alive(
+        db: &Database,
+        state: &VNodeState,
+        peer: NodeId,
+        cookie: Cookie,
+        last_recv: &mut Instant,
+        last_send: &mut Instant,
+    ) {
+        *last_recv = Instant::now();
+        if last_send.elapsed() > Duration::from_millis(db.config().sync_msg_timeout as _) {
+            *last_send = Instant::now();
+            let _ = db.fabric.send_msg(
+                peer,
+                &MsgSyncFin {
+                    cookie: cookie,
+                    vnode: state.num(),
+                    result: Err(FabricError::SenderWaiting),
+                },
+            );
+        }
+    }
+
+    // hashes a batch of the local tree for a hash tree sync sender, Some while not done,
+    // then sets up sending the keys in the leaves that differ from the peer
+    fn hash_tree_step(&mut self, db: &Database, state: &mut VNodeState) -> Option<SyncResult> {
+        let leaves = match *self {
+            SyncSender {
+                hashing: Some((ref mut hasher, ref peer_tree)),
+                peer,
+                cookie,
+                ref mut last_recv,
+                ref mut last_send,
+                ..
+            } => match hasher.step(state, TREE_HASH_BATCH_SIZE) {
+                Some(leaves) => MerkleTree::new(leaves.to_vec()).unwrap().diff(peer_tree),
+                None => {
+                    Self::keep_alive(db, state, peer, cookie, last_recv, last_send);
+                    return Some(SyncResult::Continue);
+                }
+            },
+            _ => return None,
+        };
+
+        let mut differing = vec![false; MERKLE_LEAVES];
+        for &leaf in &leaves {
+            differing[leaf] = true;
+        }
+        if let SyncSender {
+            cookie,
+            ref mut hashing,
+            ref mut iterator,
+            leaves: ref mut sent_leaves,
+            ..
+        } = *self
+        {
+            debug!("Hash tree sync {:?} has {} differing leaves", cookie, leaves.len());
+            *hashing = None;
+            *iterator = storage_iterator_fn(state, b"", move |k| differing[merkle::leaf_of(k)]);
+            *sent_leaves = Some(leaves.into_iter().map(|l| l as u16).collect());
+        }
+        None
+    }
+
     // called by vnode when node is transition to an incompatible state
     // only valid for Receivers right now
     pub fn on_cancel(&mut self, db: &Database, state: &mut VNodeState) {
-        match *self {
-            BootstrapReceiver { .. } | SyncReceiver { .. } => {
-                let _ = self.send_error_fin(db, state, FabricError::BadVNodeStatus);
-            }
-            _ => unreachable!(),
-        }
+        let _ = self.send_error_fin(db, state, FabricError::BadVNodeStatus);
     }
 
     // called by vnode as soon as the sync is unregistered
@@ -481,13 +894,21 @@ impl Synchronization {
     }
 
     pub fn on_tick(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
+        if let SyncReceiver {
+            cleanup: Some(ref mut cleanup),
+            ref received,
+            ..
+        } = *self
+        {
+            return cleanup.step(state, received, TREE_CLEANUP_BATCH_SIZE);
+        }
         match *self {
             SyncSender {
                 last_recv, cookie, ..
             }
             | BootstrapSender {
                 last_recv, cookie, ..
-            } => if last_recv.elapsed() > Duration::from_millis(db.config.sync_timeout as _) {
+            } => if last_recv.elapsed() > Duration::from_millis(db.config().sync_timeout as _) {
                 warn!("sync/boostrap sender timed out {:?}", cookie);
                 SyncResult::Error
             } else {
@@ -506,11 +927,13 @@ impl Synchronization {
                 last_send,
                 cookie,
                 ..
-            } => if last_recv.elapsed() > Duration::from_millis(db.config.sync_timeout as _) {
+            } => if last_recv.elapsed() > Duration::from_millis(db.config().sync_timeout as _) {
                 warn!("sync/boostrap receiver timed out {:?}", cookie);
                 SyncResult::Error
             } else if recv_count == 0
-                && last_send.elapsed() > Duration::from_millis(db.config.sync_msg_timeout as _)
+                && (self.is_hashing()
+                    || last_send.elapsed()
+                        > Duration::from_millis(db.config().sync_msg_timeout as _))
             {
                 self.send_start(db, state)
             } else {
@@ -519,6 +942,17 @@ impl Synchronization {
         }
     }
 
+    // whether a hash tree sync receiver is still hashing the local data
+    fn is_hashing(&self) -> bool {
+        match *self {
+            SyncReceiver {
+                tree: Some(ref hasher),
+                ..
+            } => hasher.next.is_some(),
+            _ => false,
+        }
+    }
+
     // called by vnode as soon as the sync is registered (after creation)
     pub fn on_start(&mut self, db: &Database, state: &mut VNodeState) {
         let _ = match *self {
@@ -535,12 +969,56 @@ impl Synchronization {
     ) -> SyncResult {
         match *self {
             SyncReceiver { peer, .. } | BootstrapReceiver { peer, .. } => {
-                if msg.result.is_ok() {
-                    state.clocks.merge(msg.result.as_ref().unwrap());
+                if let Ok((ref clocks, ref leaves)) = msg.result {
+                    match *self {
+                        BootstrapReceiver { share, .. } => {
+                            // the clocks only cover the keys of the share,
+                            // the vnode merges them once all shares are done
+                            let checkpoint = &mut state.bootstrap_checkpoint;
+                            checkpoint.intersect_clocks(clocks);
+                            checkpoint.shares[share as usize].done = true;
+                        }
+                        SyncReceiver {
+                            ref tree,
+                            ref mut cleanup,
+                            ..
+                        } => if cleanup.is_none() {
+                            state.clocks.merge(clocks);
+                            state.prune_clocks();
+                            if let (&Some(_), &Some(ref leaves)) = (tree, leaves) {
+                                let mut differing = vec![false; MERKLE_LEAVES];
+                                for &leaf in leaves {
+                                    differing[leaf as usize % MERKLE_LEAVES] = true;
+                                }
+                                *cleanup = Some(TreeCleanup {
+                                    differing: differing,
+                                    clocks: clocks.clone(),
+                                    next: Some(Bytes::new()),
+                                });
+                            }
+                        },
+                        _ => unreachable!(),
+                    }
                     state.save(db, false);
                     // send it back as a form of ack-ack
                     let _ = db.fabric.send_msg(peer, &msg);
-                    SyncResult::Done
+                    match *self {
+                        SyncReceiver {
+                            cleanup: Some(_), ..
+                        } => SyncResult::Continue,
+                        _ => SyncResult::Done,
+                    }
+                } else if msg.result.as_ref().err() == Some(&FabricError::SenderWaiting) {
+                    match *self {
+                        SyncReceiver {
+                            ref mut last_recv, ..
+                        }
+                        | BootstrapReceiver {
+                            ref mut last_recv, ..
+                        } => *last_recv = Instant::now(),
+                        _ => unreachable!(),
+                    }
+                    SyncResult::Continue
                 } else if msg.result.err() == Some(FabricError::NotReady) {
                     SyncResult::Continue
                 } else {
@@ -554,6 +1032,36 @@ impl Synchronization {
         }
     }
 
+    // peer to start a hash tree sync with, if the fin says the peer log doesn't cover the sync
+    pub fn tree_fallback(&self, msg: &MsgSyncFin) -> Option<NodeId> {
+        match *self {
+            SyncReceiver {
+                peer, tree: None, ..
+            } if msg.result.as_ref().err() == Some(&FabricError::LogTruncated) => Some(peer),
+            _ => None,
+        }
+    }
+
+    // peer of a sender getting its fin back (the ack-ack),
+    // and whether the peer clocks now cover the local ones
+    pub fn pushed_to(&self, state: &VNodeState, msg: &MsgSyncFin) -> Option<(NodeId, bool)> {
+        match (self, &msg.result) {
+            (
+                &SyncSender {
+                    peer,
+                    ref clocks_in_peer,
+                    ..
+                },
+                &Ok((ref clocks, _)),
+            ) => {
+                let mut peer_clocks = clocks_in_peer.clone();
+                peer_clocks.merge(clocks);
+                Some((peer, peer_clocks.covers(&state.clocks)))
+            }
+            _ => None,
+        }
+    }
+
     pub fn on_msg_send(&mut self, db: &Database, state: &mut VNodeState, msg: MsgSyncSend) {
         match *self {
             SyncReceiver {
@@ -572,7 +1080,7 @@ impl Synchronization {
             } => {
                 // TODO: what to do with errors here?
                 state
-                    .storage_set_remote(db, vec![(msg.key, msg.value, false)])
+                    .storage_set_remote(db, vec![(msg.key.clone(), msg.value, false)])
                     .unwrap();
 
                 let _ = db.fabric.send_msg(
@@ -592,6 +1100,39 @@ impl Synchronization {
             }
             _ => unreachable!(),
         }
+
+        if let SyncReceiver {
+            tree: Some(_),
+            ref mut received,
+            ..
+        } = *self
+        {
+            received.insert(msg.key.clone());
+        }
+
+        // advance the bootstrap checkpoint, resends were already accounted for
+        if let BootstrapReceiver {
+            share,
+            ref mut next_seq,
+            ref mut received,
+            ..
+        } = *self
+        {
+            state.bootstrap_received += 1;
+            if msg.seq >= *next_seq && !received.contains_key(&msg.seq) {
+                let checkpoint = &mut state.bootstrap_checkpoint;
+                if let Some(ref clocks) = msg.clocks {
+                    // keys from different senders only cover what all of them had
+                    checkpoint.intersect_clocks(clocks);
+                }
+                received.insert(msg.seq, msg.key);
+                let progress = &mut checkpoint.shares[share as usize];
+                while let Some(key) = received.remove(&*next_seq) {
+                    progress.key = Some(key);
+                    *next_seq += 1;
+                }
+            }
+        }
     }
 
     pub fn on_msg_ack(&mut self, db: &Database, state: &mut VNodeState, msg: MsgSyncAck) {
@@ -614,6 +1155,33 @@ impl Synchronization {
         let _ = self.send_next(db, state);
     }
 
+    pub fn progress(&self) -> SyncProgress {
+        let (kind, peer, count) = match *self {
+            SyncSender { peer, count, .. } => ("sync_sender", peer, count),
+            SyncReceiver {
+                peer, recv_count, ..
+            } => ("sync_receiver", peer, recv_count),
+            BootstrapSender { peer, count, .. } => ("bootstrap_sender", peer, count),
+            BootstrapReceiver {
+                peer, recv_count, ..
+            } => ("bootstrap_receiver", peer, recv_count),
+        };
+        SyncProgress {
+            kind: kind,
+            peer: peer,
+            count: count,
+        }
+    }
+
+    pub fn peer(&self) -> NodeId {
+        match *self {
+            SyncSender { peer, .. }
+            | SyncReceiver { peer, .. }
+            | BootstrapSender { peer, .. }
+            | BootstrapReceiver { peer, .. } => peer,
+        }
+    }
+
     pub fn direction(&self) -> SyncDirection {
         match *self {
             BootstrapReceiver { .. } | SyncReceiver { .. } => SyncDirection::Incomming,